@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::Result;
 use log::{info, warn};
@@ -6,9 +6,13 @@ use structopt::StructOpt;
 use walkdir::DirEntry;
 
 use super::crawler::{CrawlHelper, Crawler};
-use super::direntryutil::is_hidden;
+use super::crawlerrors::CrawlErrors;
+use super::crawloptions::CrawlOptions;
+use super::direntryutil::{has_extension, is_hidden};
 use super::filer::Filer;
+use super::gc::prune_missing;
 use super::image_type::ImageType;
+use super::progress::Progress;
 
 /// Add extensions to image files with no extensions.
 #[derive(StructOpt, Debug)]
@@ -17,19 +21,19 @@ pub struct AddExt {
     #[structopt(short = "n", long)]
     dry_run: bool,
 
-    /// The directories to search
-    // TODO: figure out what this does if the filename is not UTF-8.
-    #[structopt(min_values(1))]
-    directories: Vec<String>,
-}
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
 
-fn has_extension(path: &Path) -> bool {
-    path.extension().map(|e| !e.is_empty()).unwrap_or(false)
+    /// The directories to search
+    #[structopt(min_values(1), parse(from_os_str))]
+    directories: Vec<PathBuf>,
 }
 
 struct Helper<'a> {
     dry_run: bool,
     filer: &'a Filer,
+    progress: &'a Progress,
+    errors: &'a CrawlErrors,
 }
 
 #[derive(Default)]
@@ -89,25 +93,52 @@ impl<'a> CrawlHelper for Helper<'a> {
             let mut new_name = path.to_path_buf();
             if new_name.set_extension(ext) {
                 // TODO: verbose option?
-                std::fs::rename(path, new_name)?;
+                std::fs::rename(path, &new_name)?;
+                self.filer.move_file(path, new_name);
             } else {
                 warn!("Failed to add extension, {}, to {}", ext, path.display());
             }
         }
         Ok(())
     }
+
+    fn progress(&self) -> Option<&Progress> {
+        Some(self.progress)
+    }
+
+    fn errors(&self) -> Option<&CrawlErrors> {
+        Some(self.errors)
+    }
 }
 
 pub fn process_addext(ae: &AddExt, filer: &Filer) -> Result<()> {
+    let progress = Progress::new();
+    let errors = CrawlErrors::new(ae.crawl_options.fail_fast);
     for dir in &ae.directories {
         let crawler = Crawler::new(
             dir,
+            &ae.crawl_options,
             Helper {
                 dry_run: ae.dry_run,
                 filer,
+                progress: &progress,
+                errors: &errors,
             },
         );
         crawler.crawl()?;
+        if errors.should_stop() {
+            break;
+        }
+    }
+    progress.finish();
+
+    if !errors.should_stop() {
+        let removed = prune_missing(filer, &ae.directories, ae.dry_run);
+        if ae.dry_run {
+            eprintln!("{} catalog entries refer to missing files.", removed);
+        } else {
+            eprintln!("Removed {} missing files from the catalog.", removed);
+        }
     }
-    Ok(())
+    errors.finish()
 }
@@ -0,0 +1,265 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use flate2::read::GzDecoder;
+
+use crate::imt::filer::Filer;
+
+// Members of archives are cataloged with paths like "photos.zip!/2019/beach.jpg".
+const MEMBER_SEPARATOR: &str = "!";
+
+/// A regular file inside of an archive. Its contents are read from `data`, so that big
+/// members never have to be held in memory.
+pub struct Member<'a> {
+    pub path: PathBuf,
+    pub mtime: Option<u64>,
+    // The size from the archive's header.
+    pub size: u64,
+    pub data: &'a mut dyn Read,
+}
+
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    archive_kind(path).is_some()
+}
+
+/// The catalog path for the member at `inner` in the archive at `archive`.
+pub fn member_path(archive: &Path, inner: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_os_string();
+    name.push(MEMBER_SEPARATOR);
+    PathBuf::from(name).join(inner)
+}
+
+/// If `path` is the catalog path of an archive member, returns the path of the archive. Only
+/// names of archives count, so a directory like "Wow!" isn't mistaken for one.
+pub fn archive_of(path: &Path) -> Option<PathBuf> {
+    let mut archive = PathBuf::new();
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            if let Some(stripped) = strip_separator(name) {
+                let candidate = archive.join(stripped);
+                if is_archive(&candidate) {
+                    return Some(candidate);
+                }
+            }
+        }
+        archive.push(component);
+    }
+    None
+}
+
+#[cfg(unix)]
+fn strip_separator(name: &OsStr) -> Option<OsString> {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    name.as_bytes()
+        .strip_suffix(MEMBER_SEPARATOR.as_bytes())
+        .map(|b| OsString::from_vec(b.to_vec()))
+}
+
+#[cfg(not(unix))]
+fn strip_separator(name: &OsStr) -> Option<OsString> {
+    name.to_str()?
+        .strip_suffix(MEMBER_SEPARATOR)
+        .map(OsString::from)
+}
+
+/// The archives that already have members in the catalog.
+pub fn cataloged_archives(filer: &Filer) -> HashSet<PathBuf> {
+    let mut archives = HashSet::new();
+    filer.with_files(|p| {
+        if let Some(archive) = archive_of(p) {
+            archives.insert(archive);
+        }
+    });
+    archives
+}
+
+/// Drop all of the catalog entries for members of `archive`.
+pub fn remove_members(filer: &Filer, archive: &Path) {
+    let mut members: Vec<PathBuf> = Vec::default();
+    filer.with_files(|p| {
+        if archive_of(p).as_deref() == Some(archive) {
+            members.push(p.clone());
+        }
+    });
+    for member in members {
+        filer.remove_file(member);
+    }
+}
+
+/// Calls `f` with every regular file in the archive at `path`.
+pub fn for_each_member<F>(path: &Path, f: F) -> Result<()>
+where
+    F: FnMut(Member<'_>) -> Result<()>,
+{
+    match archive_kind(path) {
+        Some(ArchiveKind::Zip) => for_each_zip_member(File::open(path)?, f),
+        Some(ArchiveKind::Tar) => for_each_tar_member(File::open(path)?, f),
+        Some(ArchiveKind::TarGz) => for_each_tar_member(GzDecoder::new(File::open(path)?), f),
+        None => Ok(()),
+    }
+}
+
+fn for_each_zip_member<F>(file: File, mut f: F) -> Result<()>
+where
+    F: FnMut(Member<'_>) -> Result<()>,
+{
+    let mut zip = zip::ZipArchive::new(file)?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        // enclosed_name() drops names that would escape the archive, like "../x".
+        let path = match entry.enclosed_name() {
+            Some(p) if entry.is_file() => p.to_path_buf(),
+            _ => continue,
+        };
+        let dt = entry.last_modified();
+        let mtime = NaiveDate::from_ymd_opt(dt.year().into(), dt.month().into(), dt.day().into())
+            .and_then(|d| d.and_hms_opt(dt.hour().into(), dt.minute().into(), dt.second().into()))
+            .map(|t| t.and_utc().timestamp().max(0) as u64);
+        let size = entry.size();
+        f(Member {
+            path,
+            mtime,
+            size,
+            data: &mut entry,
+        })?;
+    }
+    Ok(())
+}
+
+fn for_each_tar_member<R, F>(reader: R, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(Member<'_>) -> Result<()>,
+{
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let mtime = entry.header().mtime().ok();
+        let size = entry.size();
+        f(Member {
+            path,
+            mtime,
+            size,
+            data: &mut entry,
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("photos.zip", "2019/beach.jpg" ; "zip")]
+    #[test_case("old/photos.tar.gz", "beach.jpg" ; "tar gz")]
+    #[test_case("/abs/Wow!/photos.TGZ", "a/b/c.jpg" ; "bang in a parent")]
+    fn member_paths_split_back(archive: &str, inner: &str) {
+        let path = member_path(Path::new(archive), Path::new(inner));
+        assert_eq!(archive_of(&path), Some(PathBuf::from(archive)));
+    }
+
+    #[test_case("Wow!/pic.jpg" ; "directory")]
+    #[test_case("photos.zip/pic.jpg" ; "no separator")]
+    #[test_case("notes.txt!/pic.jpg" ; "not an archive")]
+    #[test_case("photos.zip!" ; "archive itself")]
+    fn real_files_are_not_members(path: &str) {
+        let expected = path.strip_suffix("!").map(PathBuf::from);
+        assert_eq!(archive_of(Path::new(path)), expected);
+    }
+
+    // The path, mtime, size, and contents of every member of the archive at `path`.
+    fn members(path: &Path) -> Vec<(PathBuf, Option<u64>, u64, Vec<u8>)> {
+        let mut members = Vec::default();
+        for_each_member(path, |m| {
+            let mut data = Vec::default();
+            m.data.read_to_end(&mut data)?;
+            members.push((m.path, m.mtime, m.size, data));
+            Ok(())
+        })
+        .unwrap();
+        members
+    }
+
+    #[test]
+    fn tar_members_are_read() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("photos.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mtime(1_500_000_000);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "2019/a.jpg", &b"abc"[..])
+            .unwrap();
+        builder.into_inner().unwrap().flush().unwrap();
+
+        assert_eq!(
+            members(&path),
+            vec![(
+                PathBuf::from("2019/a.jpg"),
+                Some(1_500_000_000),
+                3,
+                b"abc".to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn zip_members_are_read() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("photos.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let time = zip::DateTime::from_date_and_time(2017, 7, 14, 2, 40, 0).unwrap();
+        let options = zip::write::FileOptions::default().last_modified_time(time);
+        zip.add_directory("2019/", options).unwrap();
+        zip.start_file("2019/a.jpg", options).unwrap();
+        zip.write_all(b"abcd").unwrap();
+        // Names that would escape the archive are skipped.
+        zip.start_file("../evil.jpg", options).unwrap();
+        zip.write_all(b"evil").unwrap();
+        zip.finish().unwrap();
+
+        assert_eq!(
+            members(&path),
+            vec![(
+                PathBuf::from("2019/a.jpg"),
+                Some(1_500_000_000),
+                4,
+                b"abcd".to_vec()
+            )]
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use structopt::StructOpt;
+use walkdir::DirEntry;
+
+use crate::imt::crawler::{CrawlHelper, Crawler};
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::CrawlOptions;
+use crate::imt::direntryutil::{is_hidden, replace_contents};
+use crate::imt::exif::{orientation, set_orientation, EXIF_IDENTIFIER, ORIENTATION_NORMAL};
+use crate::imt::filer::{FileStat, Filer};
+use crate::imt::image_type::{jpeg_segments, ImageType};
+use crate::imt::jpegcoefs::{Block, Coefficients};
+use crate::imt::progress::Progress;
+
+/// Turn JPEGs the right way up, according to their EXIF Orientation tag, without re-encoding
+/// the pixels. The tag is reset afterwards, so viewers that honor it still agree.
+#[derive(StructOpt, Debug)]
+pub struct AutoRotate {
+    /// Print the files that would change. No files are changed.
+    #[structopt(short = "n", long)]
+    dry_run: bool,
+
+    /// When the image isn't a whole number of MCUs wide or high, drop the partial MCUs at the
+    /// edge instead of skipping the file. This loses at most 15 pixels from the edge.
+    #[structopt(long)]
+    trim: bool,
+
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
+
+    /// The directories to search
+    #[structopt(min_values(1), parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transform {
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Transform {
+    // The transform that undoes an orientation, so that it becomes 1.
+    fn for_orientation(orientation: u16) -> Option<Transform> {
+        match orientation {
+            2 => Some(Transform::FlipHorizontal),
+            3 => Some(Transform::Rotate180),
+            4 => Some(Transform::FlipVertical),
+            5 => Some(Transform::Transpose),
+            6 => Some(Transform::Rotate90),
+            7 => Some(Transform::Transverse),
+            8 => Some(Transform::Rotate270),
+            _ => None,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Transform::FlipHorizontal => "flip horizontally",
+            Transform::Rotate180 => "rotate 180°",
+            Transform::FlipVertical => "flip vertically",
+            Transform::Transpose => "transpose",
+            Transform::Rotate90 => "rotate 90° clockwise",
+            Transform::Transverse => "transverse",
+            Transform::Rotate270 => "rotate 90° counterclockwise",
+        }
+    }
+
+    fn transposes(self) -> bool {
+        matches!(
+            self,
+            Transform::Transpose
+                | Transform::Rotate90
+                | Transform::Transverse
+                | Transform::Rotate270
+        )
+    }
+
+    // Whether the columns, or the rows, of the source come out in reverse order. Partial
+    // MCUs on that edge would end up on the wrong side of the image.
+    fn mirrors_columns(self) -> bool {
+        matches!(
+            self,
+            Transform::FlipHorizontal
+                | Transform::Rotate180
+                | Transform::Rotate270
+                | Transform::Transverse
+        )
+    }
+
+    fn mirrors_rows(self) -> bool {
+        matches!(
+            self,
+            Transform::FlipVertical
+                | Transform::Rotate180
+                | Transform::Rotate90
+                | Transform::Transverse
+        )
+    }
+
+    // The source block for the destination block at (x, y), where the source is
+    // `wide` x `high` blocks.
+    fn source_block(self, x: usize, y: usize, wide: usize, high: usize) -> (usize, usize) {
+        match self {
+            Transform::FlipHorizontal => (wide - 1 - x, y),
+            Transform::Rotate180 => (wide - 1 - x, high - 1 - y),
+            Transform::FlipVertical => (x, high - 1 - y),
+            Transform::Transpose => (y, x),
+            Transform::Rotate90 => (y, high - 1 - x),
+            Transform::Transverse => (wide - 1 - y, high - 1 - x),
+            Transform::Rotate270 => (wide - 1 - y, x),
+        }
+    }
+
+    // Mirroring a block flips the sign of its odd frequencies in that direction, and
+    // transposing it transposes the coefficients.
+    fn transform_block(self, src: &Block) -> Block {
+        let (negate_u, negate_v) = match self {
+            Transform::FlipHorizontal | Transform::Rotate90 => (true, false),
+            Transform::FlipVertical | Transform::Rotate270 => (false, true),
+            Transform::Rotate180 | Transform::Transverse => (true, true),
+            Transform::Transpose => (false, false),
+        };
+        let mut dst = [0; 64];
+        for v in 0..8 {
+            for u in 0..8 {
+                let value = if self.transposes() {
+                    src[u * 8 + v]
+                } else {
+                    src[v * 8 + u]
+                };
+                let negate = (negate_u && u % 2 == 1) != (negate_v && v % 2 == 1);
+                dst[v * 8 + u] = if negate { -value } else { value };
+            }
+        }
+        dst
+    }
+
+    // Returns the number of pixels that would have to be trimmed from the right and bottom to
+    // do this transform perfectly.
+    fn trim_needed(self, coefficients: &Coefficients) -> (usize, usize) {
+        let (mcu_width, mcu_height) = coefficients.mcu_size();
+        let right = if self.mirrors_columns() {
+            coefficients.width % mcu_width
+        } else {
+            0
+        };
+        let bottom = if self.mirrors_rows() {
+            coefficients.height % mcu_height
+        } else {
+            0
+        };
+        (right, bottom)
+    }
+
+    fn apply(self, coefficients: &mut Coefficients) -> Result<()> {
+        let (right, bottom) = self.trim_needed(coefficients);
+        coefficients.width -= right;
+        coefficients.height -= bottom;
+        if coefficients.width == 0 || coefficients.height == 0 {
+            return Err(anyhow!("image is smaller than one MCU"));
+        }
+        // The source, trimmed, in blocks, and the blocks themselves.
+        let (mcu_width, mcu_height) = coefficients.mcu_size();
+        let mcus_wide = coefficients.width.div_ceil(mcu_width);
+        let mcus_high = coefficients.height.div_ceil(mcu_height);
+        let sources: Vec<(usize, usize, usize, Vec<Block>)> = coefficients
+            .components
+            .iter_mut()
+            .map(|c| {
+                let blocks = std::mem::take(&mut c.blocks);
+                (c.padded_wide, c.h * mcus_wide, c.v * mcus_high, blocks)
+            })
+            .collect();
+
+        if self.transposes() {
+            std::mem::swap(&mut coefficients.width, &mut coefficients.height);
+            for c in &mut coefficients.components {
+                std::mem::swap(&mut c.h, &mut c.v);
+            }
+            for table in coefficients.quant_tables.iter_mut().flatten() {
+                let old = table.values;
+                for v in 0..8 {
+                    for u in 0..8 {
+                        table.values[v * 8 + u] = old[u * 8 + v];
+                    }
+                }
+            }
+        }
+        coefficients.layout();
+
+        for (c, (stride, wide, high, blocks)) in coefficients.components.iter_mut().zip(sources) {
+            for y in 0..c.padded_high {
+                for x in 0..c.padded_wide {
+                    let (sx, sy) = self.source_block(x, y, wide, high);
+                    c.blocks[y * c.padded_wide + x] =
+                        self.transform_block(&blocks[sy * stride + sx]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The EXIF orientation of a JPEG, if it has one that isn't normal.
+fn jpeg_orientation(bytes: &[u8]) -> Result<Option<u16>> {
+    let found = jpeg_segments(bytes)?
+        .iter()
+        .filter(|s| s.id == 0xe1)
+        .find_map(|s| orientation(bytes.get(s.range.start + 4..s.range.end)?));
+    Ok(found.filter(|o| *o != ORIENTATION_NORMAL))
+}
+
+struct Helper<'a> {
+    auto_rotate: &'a AutoRotate,
+    filer: &'a Filer,
+    progress: &'a Progress,
+    errors: &'a CrawlErrors,
+}
+
+impl<'a> CrawlHelper for Helper<'a> {
+    type InfoType = ();
+
+    fn should_descend(&self, e: &DirEntry) -> Result<bool> {
+        // Basically just trimming off hidden directories.
+        Ok(!is_hidden(e))
+    }
+
+    fn should_process_file(&self, e: &DirEntry, _it: &mut Self::InfoType) -> Result<bool> {
+        Ok(ImageType::type_of_file_at(e.path())? == ImageType::JPEG)
+    }
+
+    fn process_file(&self, e: &DirEntry, _it: &mut Self::InfoType) -> Result<()> {
+        let path = e.path();
+        let bytes = fs::read(path)?;
+        self.progress.add_bytes(bytes.len() as u64);
+        let orientation = match jpeg_orientation(&bytes)? {
+            Some(orientation) => orientation,
+            None => return Ok(()),
+        };
+        let transform = match Transform::for_orientation(orientation) {
+            Some(transform) => transform,
+            None => {
+                info!("{}: unknown orientation {}", path.display(), orientation);
+                return Ok(());
+            }
+        };
+
+        let mut coefficients = Coefficients::read(&bytes)?;
+        let (right, bottom) = transform.trim_needed(&coefficients);
+        let trimmed = right > 0 || bottom > 0;
+        if trimmed && !self.auto_rotate.trim {
+            println!(
+                "{}: skipped, can't {} without trimming {}x{} pixels (see --trim)",
+                path.display(),
+                transform.describe(),
+                right,
+                bottom
+            );
+            return Ok(());
+        }
+        if self.auto_rotate.dry_run {
+            println!("{}: would {}", path.display(), transform.describe());
+            return Ok(());
+        }
+
+        transform.apply(&mut coefficients)?;
+        for (marker, data) in &mut coefficients.extra_segments {
+            if *marker == 0xe1 && data.starts_with(EXIF_IDENTIFIER) {
+                set_orientation(data, ORIENTATION_NORMAL);
+            }
+        }
+        replace_contents(path, &coefficients.write()?)?;
+        // Drops the cached hashes and dimensions, since the contents changed.
+        if self.filer.contains_file(path) {
+            self.filer.set_stat(path, FileStat::from_path(path)?);
+        }
+        println!("{}: {}", path.display(), transform.describe());
+        Ok(())
+    }
+
+    fn progress(&self) -> Option<&Progress> {
+        Some(self.progress)
+    }
+
+    fn errors(&self) -> Option<&CrawlErrors> {
+        Some(self.errors)
+    }
+}
+
+pub fn process_autorotate(ar: &AutoRotate, filer: &Filer) -> Result<()> {
+    let progress = Progress::new();
+    let errors = CrawlErrors::new(ar.crawl_options.fail_fast);
+    for dir in &ar.directories {
+        let crawler = Crawler::new(
+            dir,
+            &ar.crawl_options,
+            Helper {
+                auto_rotate: ar,
+                filer,
+                progress: &progress,
+                errors: &errors,
+            },
+        );
+        crawler.crawl()?;
+        if errors.should_stop() {
+            break;
+        }
+    }
+    progress.finish();
+    errors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView};
+    use test_case::test_case;
+
+    use super::*;
+
+    const ALL: [Transform; 7] = [
+        Transform::FlipHorizontal,
+        Transform::Rotate180,
+        Transform::FlipVertical,
+        Transform::Transpose,
+        Transform::Rotate90,
+        Transform::Transverse,
+        Transform::Rotate270,
+    ];
+
+    // The same transform, done on decoded pixels.
+    fn transform_pixels(transform: Transform, image: &DynamicImage) -> DynamicImage {
+        match transform {
+            Transform::FlipHorizontal => image.fliph(),
+            Transform::Rotate180 => image.rotate180(),
+            Transform::FlipVertical => image.flipv(),
+            Transform::Transpose => image.rotate90().fliph(),
+            Transform::Rotate90 => image.rotate90(),
+            Transform::Transverse => image.rotate270().fliph(),
+            Transform::Rotate270 => image.rotate270(),
+        }
+    }
+
+    fn numbered_block() -> Block {
+        let mut block = [0; 64];
+        for (i, value) in block.iter_mut().enumerate() {
+            *value = i as i16 + 1;
+        }
+        block
+    }
+
+    #[test]
+    fn block_transforms_undo_themselves_or_their_inverse() {
+        let block = numbered_block();
+        for transform in ALL {
+            let inverse = match transform {
+                Transform::Rotate90 => Transform::Rotate270,
+                Transform::Rotate270 => Transform::Rotate90,
+                other => other,
+            };
+            let back = inverse.transform_block(&transform.transform_block(&block));
+            assert_eq!(back, block, "{:?}", transform);
+        }
+    }
+
+    #[test]
+    fn transpose_swaps_frequencies() {
+        let block = numbered_block();
+        let transposed = Transform::Transpose.transform_block(&block);
+        assert_eq!(transposed[1], block[8]);
+        assert_eq!(transposed[8], block[1]);
+    }
+
+    #[test_case(1, None ; "normal")]
+    #[test_case(6, Some(Transform::Rotate90) ; "rotated")]
+    #[test_case(9, None ; "out of range")]
+    fn orientations(orientation: u16, expected: Option<Transform>) {
+        assert_eq!(Transform::for_orientation(orientation), expected);
+    }
+
+    // The test image is 585x1080, so transforms that mirror columns have to trim.
+    #[test_case(Transform::FlipHorizontal ; "flip horizontal")]
+    #[test_case(Transform::Rotate180 ; "rotate 180")]
+    #[test_case(Transform::FlipVertical ; "flip vertical")]
+    #[test_case(Transform::Transpose ; "transpose")]
+    #[test_case(Transform::Rotate90 ; "rotate 90")]
+    #[test_case(Transform::Transverse ; "transverse")]
+    #[test_case(Transform::Rotate270 ; "rotate 270")]
+    fn lossless_transform_matches_pixels(transform: Transform) {
+        let bytes = fs::read("test_images/test.jpg").unwrap();
+        let mut coefficients = Coefficients::read(&bytes).unwrap();
+        let (right, bottom) = transform.trim_needed(&coefficients);
+        let (width, height) = (coefficients.width, coefficients.height);
+        transform.apply(&mut coefficients).unwrap();
+        let actual = image::load_from_memory(&coefficients.write().unwrap()).unwrap();
+
+        let original = image::load_from_memory(&bytes).unwrap();
+        let cropped = original.crop_imm(0, 0, (width - right) as u32, (height - bottom) as u32);
+        let expected = transform_pixels(transform, &cropped);
+        assert_eq!(actual.dimensions(), expected.dimensions());
+
+        // The IDCT rounds a little differently once the blocks move around.
+        let (actual, expected) = (actual.to_rgb8(), expected.to_rgb8());
+        let diffs: Vec<u8> = actual
+            .as_raw()
+            .iter()
+            .zip(expected.as_raw())
+            .map(|(a, b)| a.abs_diff(*b))
+            .collect();
+        let mean = diffs.iter().map(|d| *d as f64).sum::<f64>() / diffs.len() as f64;
+        assert!(mean < 0.5, "mean difference {}", mean);
+        assert!(diffs.iter().all(|d| *d <= 8));
+    }
+
+    // progressive.jpg is 32x23 with 8x8 MCUs. Turning it a quarter turn counterclockwise
+    // needs no trim (the columns mirror, and 32 is whole MCUs), and neither does turning it
+    // back.
+    #[test]
+    fn progressive_round_trip_keeps_coefficients() {
+        let bytes = fs::read("test_images/progressive.jpg").unwrap();
+        let original = Coefficients::read(&bytes).unwrap();
+        let mut coefficients = Coefficients::read(&bytes).unwrap();
+        assert_eq!((8, 8), coefficients.mcu_size());
+        Transform::Rotate270.apply(&mut coefficients).unwrap();
+        assert_eq!((23, 32), (coefficients.width, coefficients.height));
+        let rotated = Coefficients::read(&coefficients.write().unwrap()).unwrap();
+
+        let mut coefficients = rotated;
+        Transform::Rotate90.apply(&mut coefficients).unwrap();
+        assert_eq!((32, 23), (coefficients.width, coefficients.height));
+        for (a, b) in original.components.iter().zip(&coefficients.components) {
+            assert_eq!((a.h, a.v, a.quant_table), (b.h, b.v, b.quant_table));
+            assert_eq!(a.blocks, b.blocks);
+        }
+        for (a, b) in original.quant_tables.iter().zip(&coefficients.quant_tables) {
+            assert_eq!(a.as_ref().map(|t| t.values), b.as_ref().map(|t| t.values));
+        }
+    }
+}
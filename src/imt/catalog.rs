@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use structopt::StructOpt;
+
+use crate::imt::filer::{encode_path, Filer, Files, CATALOG_PATH};
+
+/// Work with catalog files, without looking at the files that they describe.
+#[derive(StructOpt, Debug)]
+pub enum Catalog {
+    /// Combine catalogs into one. Where several have the same file, the entry with the newest
+    /// modification time wins. If the newest entries have the same modification time but
+    /// differ, like in size, the one from the catalog listed first wins.
+    Merge {
+        /// Where to write the combined catalog.
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+
+        /// The catalogs to combine
+        #[structopt(min_values(1), required(true), parse(from_os_str))]
+        catalogs: Vec<PathBuf>,
+    },
+
+    /// List the files that are only in one of two catalogs, and the files that are in both
+    /// with different hashes.
+    Diff {
+        #[structopt(parse(from_os_str))]
+        a: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        b: PathBuf,
+    },
+
+    /// Write a catalog of only the files at or below a directory.
+    Split {
+        /// The directory to keep.
+        #[structopt(long, parse(from_os_str))]
+        prefix: PathBuf,
+
+        /// Make the paths relative to the prefix, so the new catalog can be used from there.
+        #[structopt(long)]
+        strip_prefix: bool,
+
+        /// Where to write the new catalog.
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+
+        /// The catalog to split
+        #[structopt(parse(from_os_str))]
+        catalog: PathBuf,
+    },
+}
+
+impl Catalog {
+    // Only writing to the main catalog changes it; the others are written as they go.
+    pub fn changes_catalog(&self) -> bool {
+        match self {
+            Catalog::Merge { output, .. } | Catalog::Split { output, .. } => {
+                is_main_catalog(output)
+            }
+            Catalog::Diff { .. } => false,
+        }
+    }
+}
+
+fn is_main_catalog(path: &Path) -> bool {
+    match (fs::canonicalize(path), fs::canonicalize(CATALOG_PATH)) {
+        (Ok(path), Ok(main)) => path == main,
+        _ => path == Path::new(CATALOG_PATH),
+    }
+}
+
+// The main catalog is written when the command finishes, so writing over it here would be
+// undone. It is replaced in memory instead.
+fn write_catalog(files: Files, path: &Path, filer: &Filer) -> Result<()> {
+    if is_main_catalog(path) {
+        filer.replace(files);
+    } else {
+        files.write_to_path(path)?;
+    }
+    eprintln!("Wrote {}.", path.display());
+    Ok(())
+}
+
+fn merge(catalogs: &[PathBuf], output: &Path, filer: &Filer) -> Result<()> {
+    let mut merged = Files::default();
+    let mut conflicts = 0;
+    for path in catalogs {
+        let these = merged.merge(Files::read_from_path(path)?);
+        conflicts += these.count;
+        for tie in these.ties {
+            eprintln!(
+                "{}: {} has a different version with the same modification time; keeping the one \
+                 from an earlier catalog",
+                tie.display(),
+                path.display()
+            );
+        }
+    }
+    eprintln!(
+        "Merged {} catalogs, {} files, {} different versions of the same file.",
+        catalogs.len(),
+        merged.file_iter().count(),
+        conflicts
+    );
+    write_catalog(merged, output, filer)
+}
+
+fn diff(a: &Path, b: &Path) -> Result<()> {
+    let diff = Files::read_from_path(a)?.diff(&Files::read_from_path(b)?);
+    // Encoded like the catalog keys, so every line can be pasted back in.
+    let print = |mark: &str, paths: &[PathBuf]| {
+        paths
+            .iter()
+            .for_each(|p| println!("{} {}", mark, encode_path(p)))
+    };
+    print("<", &diff.only_in_self);
+    print(">", &diff.only_in_other);
+    print("!", &diff.different_hashes);
+    eprintln!(
+        "{} only in {}, {} only in {}, {} with different hashes.",
+        diff.only_in_self.len(),
+        a.display(),
+        diff.only_in_other.len(),
+        b.display(),
+        diff.different_hashes.len()
+    );
+    Ok(())
+}
+
+fn split(
+    catalog: &Path,
+    prefix: &Path,
+    strip_prefix: bool,
+    output: &Path,
+    filer: &Filer,
+) -> Result<()> {
+    let subtree = Files::read_from_path(catalog)?.subtree(prefix, strip_prefix);
+    eprintln!(
+        "{} files under {}.",
+        subtree.file_iter().count(),
+        prefix.display()
+    );
+    write_catalog(subtree, output, filer)
+}
+
+pub fn process_catalog(catalog: &Catalog, filer: &Filer) -> Result<()> {
+    match catalog {
+        Catalog::Merge { output, catalogs } => merge(catalogs, output, filer),
+        Catalog::Diff { a, b } => diff(a, b),
+        Catalog::Split {
+            prefix,
+            strip_prefix,
+            output,
+            catalog,
+        } => split(catalog, prefix, *strip_prefix, output, filer),
+    }
+}
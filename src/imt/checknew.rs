@@ -0,0 +1,282 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use structopt::StructOpt;
+use walkdir::DirEntry;
+
+use crate::imt::crawler::{CrawlHelper, Crawler};
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::CrawlOptions;
+use crate::imt::direntryutil::{crawl_root, is_hidden};
+use crate::imt::filer::Filer;
+use crate::imt::finddups::HASH_NAME;
+use crate::imt::hashers::{hash_algorithm, hash_reader, HashAlgorithm};
+use crate::imt::image_type::ImageType;
+use crate::imt::progress::Progress;
+
+/// Report which files in the directories are already in the catalog, by looking up their
+/// hashes. Only the new directories are read; the catalog must already have the hashes of the
+/// library, from find-dups with the same --hash. Nothing is added to the catalog, and it is
+/// taken at its word: a file deleted from the library since it was cataloged still counts as
+/// already there.
+#[derive(StructOpt, Debug)]
+pub struct CheckNew {
+    /// The catalog to look the files up in, instead of the one in the current directory.
+    #[structopt(long, parse(from_os_str))]
+    against: Option<PathBuf>,
+
+    /// The hash to look the files up by. The catalog must have been built with the same one.
+    #[structopt(long, default_value = HASH_NAME, parse(try_from_str = hash_algorithm))]
+    hash: &'static HashAlgorithm,
+
+    /// Copy the files that aren't in the catalog into this directory, keeping their paths
+    /// below the directory they were found in. Existing files are never overwritten.
+    #[structopt(long, parse(from_os_str))]
+    copy_new_to: Option<PathBuf>,
+
+    /// With --copy-new-to, print what would be copied. No files are copied.
+    #[structopt(short = "n", long, requires = "copy-new-to")]
+    dry_run: bool,
+
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
+
+    /// The directories with the new files
+    #[structopt(min_values(1), required(true), parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct Tally {
+    new: usize,
+    present: usize,
+    // New files that are copies of other new files.
+    repeated: usize,
+    // Files that the hash can't be taken of, like non-images with PIXELS.
+    skipped: usize,
+}
+
+struct Helper<'a> {
+    check: &'a CheckNew,
+    root: &'a Path,
+    // Hash to a cataloged path with that hash.
+    index: &'a HashMap<String, PathBuf>,
+    // Hash to the first new file with that hash, so copies on the card are only copied once.
+    seen: &'a RefCell<HashMap<String, PathBuf>>,
+    tally: &'a RefCell<Tally>,
+    progress: &'a Progress,
+    errors: &'a CrawlErrors,
+}
+
+impl<'a> Helper<'a> {
+    fn copy_new(&self, path: &Path, hash: &str, dir: &Path) -> Result<()> {
+        let dest = dir.join(path.strip_prefix(self.root)?);
+        if dest.exists() {
+            // From an earlier run, before the library was cataloged again.
+            let dest_hash = hash_reader(&mut File::open(&dest)?, self.check.hash, self.progress)?;
+            if dest_hash == hash {
+                println!("{}: already copied to {}", path.display(), dest.display());
+                return Ok(());
+            }
+            bail!("{} already exists", dest.display());
+        }
+        println!("{}: copying to {}", path.display(), dest.display());
+        if self.check.dry_run {
+            return Ok(());
+        }
+        let parent = dest
+            .parent()
+            .ok_or_else(|| anyhow!("no directory for {}", dest.display()))?;
+        fs::create_dir_all(parent)?;
+        fs::copy(path, &dest)?;
+        // Keep the time the photo was taken, as far as the file can tell.
+        let mtime = fs::metadata(path)?.modified()?;
+        File::options()
+            .write(true)
+            .open(&dest)?
+            .set_modified(mtime)?;
+        Ok(())
+    }
+}
+
+impl<'a> CrawlHelper for Helper<'a> {
+    type InfoType = ();
+
+    fn should_descend(&self, e: &DirEntry) -> Result<bool> {
+        // Basically just trimming off hidden directories.
+        Ok(!is_hidden(e))
+    }
+
+    fn process_file(&self, e: &DirEntry, _it: &mut Self::InfoType) -> Result<()> {
+        let path = e.path();
+        if !self
+            .check
+            .hash
+            .applies_to(ImageType::type_of_file_at(path)?)
+        {
+            println!(
+                "{}: skipped, no {} hash",
+                path.display(),
+                self.check.hash.name
+            );
+            self.tally.borrow_mut().skipped += 1;
+            return Ok(());
+        }
+
+        let hash = hash_reader(&mut File::open(path)?, self.check.hash, self.progress)?;
+        if let Some(have) = self.index.get(&hash) {
+            println!("{}: already have {}", path.display(), have.display());
+            self.tally.borrow_mut().present += 1;
+            return Ok(());
+        }
+        if let Some(first) = self.seen.borrow().get(&hash) {
+            println!("{}: new, same as {}", path.display(), first.display());
+            self.tally.borrow_mut().repeated += 1;
+            return Ok(());
+        }
+        self.seen
+            .borrow_mut()
+            .insert(hash.clone(), path.to_path_buf());
+        self.tally.borrow_mut().new += 1;
+        match &self.check.copy_new_to {
+            Some(dir) => self.copy_new(path, &hash, dir),
+            None => {
+                println!("{}: new", path.display());
+                Ok(())
+            }
+        }
+    }
+
+    fn progress(&self) -> Option<&Progress> {
+        Some(self.progress)
+    }
+
+    fn errors(&self) -> Option<&CrawlErrors> {
+        Some(self.errors)
+    }
+}
+
+// Each hash in the catalog, with one of the files that has it.
+fn hash_index(filer: &Filer, hash_name: &str) -> HashMap<String, PathBuf> {
+    let mut index = HashMap::default();
+    filer.with_files(|p| {
+        if let Some(hash) = filer.hash_value(p, hash_name) {
+            index.entry(hash).or_insert_with(|| p.clone());
+        }
+    });
+    index
+}
+
+pub fn process_checknew(check: &CheckNew, filer: &Filer) -> Result<()> {
+    let against = match &check.against {
+        Some(path) => Filer::read_from_path(path)?,
+        None => filer.clone(),
+    };
+    let index = hash_index(&against, check.hash.name);
+    if index.is_empty() {
+        bail!(
+            "the catalog has no {} hashes; run find-dups --hash {} on the library first",
+            check.hash.name,
+            check.hash.name
+        );
+    }
+
+    let seen = RefCell::default();
+    let tally = RefCell::new(Tally::default());
+    let progress = Progress::new();
+    let errors = CrawlErrors::new(check.crawl_options.fail_fast);
+    for dir in &check.directories {
+        // Copies of a file named on its own go right into --copy-new-to.
+        let root = crawl_root(dir);
+        let crawler = Crawler::new(
+            dir,
+            &check.crawl_options,
+            Helper {
+                check,
+                root,
+                index: &index,
+                seen: &seen,
+                tally: &tally,
+                progress: &progress,
+                errors: &errors,
+            },
+        );
+        crawler.crawl()?;
+        if errors.should_stop() {
+            break;
+        }
+    }
+    progress.finish();
+
+    let tally = tally.borrow();
+    eprintln!(
+        "{} new, {} already in the catalog, {} copies of other new files, {} skipped.",
+        tally.new, tally.present, tally.repeated, tally.skipped
+    );
+    errors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::imt::filer::FileStat;
+    use crate::imt::hashers::default_hash_algorithm;
+
+    // A library with one cataloged file, and a card with a copy of it and one new file.
+    fn library_and_card() -> (TempDir, Filer) {
+        let dir = TempDir::new().unwrap();
+        for sub in ["library", "card/DCIM"] {
+            fs::create_dir_all(dir.path().join(sub)).unwrap();
+        }
+        let old = dir.path().join("library/old.jpg");
+        fs::copy("test_images/test.jpg", &old).unwrap();
+        fs::copy("test_images/test.jpg", dir.path().join("card/DCIM/old.jpg")).unwrap();
+        fs::write(dir.path().join("card/DCIM/new.txt"), "new").unwrap();
+
+        let filer = Filer::new().unwrap();
+        filer.set_stat(&old, FileStat::from_path(&old).unwrap());
+        let hash = hash_reader(
+            &mut File::open(&old).unwrap(),
+            default_hash_algorithm(),
+            &Progress::new(),
+        )
+        .unwrap();
+        filer.add_hash(&old, HASH_NAME, hash).unwrap();
+        (dir, filer)
+    }
+
+    fn check_new(filer: &Filer, out: &Path, source: &Path) {
+        let args = [OsStr::new("check-new"), OsStr::new("--copy-new-to")];
+        let check = CheckNew::from_iter_safe(
+            args.iter()
+                .copied()
+                .chain([out.as_os_str(), source.as_os_str()]),
+        )
+        .unwrap();
+        process_checknew(&check, filer).unwrap();
+    }
+
+    #[test]
+    fn only_new_files_are_copied() {
+        let (dir, filer) = library_and_card();
+        let out = dir.path().join("out");
+        check_new(&filer, &out, &dir.path().join("card"));
+        assert!(out.join("DCIM/new.txt").exists());
+        assert!(!out.join("DCIM/old.jpg").exists());
+    }
+
+    #[test]
+    fn single_file_is_copied_into_the_directory() {
+        let (dir, filer) = library_and_card();
+        let out = dir.path().join("out");
+        check_new(&filer, &out, &dir.path().join("card/DCIM/new.txt"));
+        assert_eq!(fs::read(out.join("new.txt")).unwrap(), b"new");
+    }
+}
@@ -2,22 +2,79 @@ use anyhow::Result;
 use structopt::StructOpt;
 
 use crate::imt::addext::{process_addext, AddExt};
+use crate::imt::autorotate::{process_autorotate, AutoRotate};
+use crate::imt::catalog::{process_catalog, Catalog};
+use crate::imt::checknew::{process_checknew, CheckNew};
+use crate::imt::convert::{process_convert, Convert};
 use crate::imt::filer::Filer;
 use crate::imt::finddups::{process_finddups, FindDups};
 use crate::imt::findneardups::{process_findneardups, FindNearDups};
+use crate::imt::formats::{process_formats, Formats};
+use crate::imt::gc::{process_gc, Gc};
+use crate::imt::identify::{process_identify, Identify};
+use crate::imt::inventory::{process_inventory, Inventory};
+use crate::imt::query::{process_query, Query};
+use crate::imt::serve::{process_serve, Serve};
+use crate::imt::stripmeta::{process_stripmeta, StripMeta};
+use crate::imt::thumbs::{process_thumbs, Thumbs};
+use crate::imt::watch::{process_watch, Watch};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "imt2", about = "image tools")]
 pub enum Command {
     AddExt(AddExt),
+    AutoRotate(AutoRotate),
+    Catalog(Catalog),
+    CheckNew(CheckNew),
+    Convert(Convert),
     FindDups(FindDups),
     FindNearDups(FindNearDups),
+    Formats(Formats),
+    Gc(Gc),
+    Identify(Identify),
+    Inventory(Inventory),
+    Query(Query),
+    Serve(Serve),
+    StripMeta(StripMeta),
+    Thumbs(Thumbs),
+    Watch(Watch),
+}
+
+impl Command {
+    /// Whether the command may change the catalog, so that it has to be written afterwards.
+    pub fn changes_catalog(&self) -> bool {
+        if let Command::Catalog(catalog) = self {
+            return catalog.changes_catalog();
+        }
+        !matches!(
+            self,
+            Command::CheckNew(_)
+                | Command::FindNearDups(_)
+                | Command::Formats(_)
+                | Command::Identify(_)
+                | Command::Inventory(_)
+                | Command::Query(_)
+        )
+    }
 }
 
 pub fn process_command(command: Command, filer: &Filer) -> Result<()> {
     match command {
         Command::AddExt(ae) => process_addext(&ae, filer),
+        Command::AutoRotate(ar) => process_autorotate(&ar, filer),
+        Command::Catalog(c) => process_catalog(&c, filer),
+        Command::CheckNew(cn) => process_checknew(&cn, filer),
+        Command::Convert(c) => process_convert(&c, filer),
         Command::FindDups(fd) => process_finddups(&fd, filer),
         Command::FindNearDups(fnd) => process_findneardups(&fnd),
+        Command::Formats(f) => process_formats(&f),
+        Command::Gc(gc) => process_gc(&gc, filer),
+        Command::Identify(id) => process_identify(&id),
+        Command::Inventory(inv) => process_inventory(&inv, filer),
+        Command::Query(q) => process_query(&q, filer),
+        Command::Serve(s) => process_serve(&s, filer),
+        Command::StripMeta(sm) => process_stripmeta(&sm, filer),
+        Command::Thumbs(t) => process_thumbs(&t, filer),
+        Command::Watch(w) => process_watch(&w, filer),
     }
 }
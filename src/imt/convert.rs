@@ -0,0 +1,542 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageEncoder};
+use log::info;
+use structopt::StructOpt;
+use walkdir::DirEntry;
+
+use crate::imt::crawler::{CrawlHelper, Crawler};
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::CrawlOptions;
+use crate::imt::direntryutil::{
+    crawl_root, is_hidden, is_inside, prepare_output_dir, replace_contents,
+};
+use crate::imt::exif::{orientation, EXIF_IDENTIFIER, ORIENTATION_NORMAL};
+use crate::imt::filer::{FileStat, Filer};
+use crate::imt::finddups::{record_image_type, HASH_NAME};
+use crate::imt::hashers::{default_hash_algorithm, hash_reader};
+use crate::imt::image_metadata::{apply_orientation, read_metadata, Metadata, ICC_IDENTIFIER};
+use crate::imt::image_type::{jpeg_segments, png_chunks, ImageType};
+use crate::imt::progress::Progress;
+
+const DEFAULT_QUALITY: u8 = 85;
+
+/// Re-encode images as JPEG, PNG, or WebP, optionally shrinking them. The new files are added
+/// to the catalog, with a link back to the file they were made from.
+#[derive(StructOpt, Debug)]
+pub struct Convert {
+    /// The format to convert to (jpeg, png, or webp). WebP is always written losslessly.
+    #[structopt(long)]
+    to: ImageType,
+
+    /// Only convert images of this type. Defaults to every image that isn't animated.
+    #[structopt(long)]
+    from: Option<ImageType>,
+
+    /// The JPEG quality, from 1 to 100. Defaults to 85.
+    #[structopt(long)]
+    quality: Option<u8>,
+
+    /// Shrink images so that neither side is larger than this. Smaller images are left alone.
+    #[structopt(long)]
+    max_dimension: Option<u32>,
+
+    /// Copy the EXIF data and ICC color profile to the new file. Otherwise they are dropped,
+    /// after turning the image the right way up.
+    #[structopt(long)]
+    keep_metadata: bool,
+
+    /// Write the new files under this directory. Files that are already there are left alone.
+    #[structopt(long, parse(from_os_str), required_unless = "in-place")]
+    output_dir: Option<PathBuf>,
+
+    /// Replace each image with the converted one, deleting the original.
+    #[structopt(long, conflicts_with = "output-dir")]
+    in_place: bool,
+
+    /// Print what would be converted. No files are changed.
+    #[structopt(short = "n", long)]
+    dry_run: bool,
+
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
+
+    /// The directories to search
+    #[structopt(min_values(1), parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+// The most ICC data that fits in one APP2 segment, after the identifier and sequence numbers.
+const ICC_CHUNK_LEN: usize = 65519;
+const MAX_JPEG_SEGMENT_LEN: usize = 65533;
+
+fn jpeg_segment(marker: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let len: usize = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+    let mut segment = vec![0xff, marker];
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    parts.iter().for_each(|p| segment.extend_from_slice(p));
+    segment
+}
+
+fn add_jpeg_metadata(jpeg: Vec<u8>, metadata: &Metadata) -> Result<Vec<u8>> {
+    let mut segments: Vec<u8> = Vec::default();
+    if let Some(exif) = &metadata.exif {
+        if exif.len() > MAX_JPEG_SEGMENT_LEN {
+            return Err(anyhow!("the EXIF data is too big for a JPEG"));
+        }
+        segments.extend(jpeg_segment(0xe1, &[exif]));
+    }
+    if let Some(icc) = &metadata.icc {
+        let chunks: Vec<&[u8]> = icc.chunks(ICC_CHUNK_LEN).collect();
+        // The sequence numbers are single bytes.
+        if chunks.len() > u8::MAX as usize {
+            return Err(anyhow!("the ICC profile is too big for a JPEG"));
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let sequence = [i as u8 + 1, chunks.len() as u8];
+            segments.extend(jpeg_segment(0xe2, &[ICC_IDENTIFIER, &sequence, chunk]));
+        }
+    }
+    // After the SOI, and the JFIF segment that has to come first.
+    let mut insert_at = 2;
+    if let Some(first) = jpeg_segments(&jpeg)?.first() {
+        if first.id == 0xe0 {
+            insert_at = first.range.end;
+        }
+    }
+    Ok([&jpeg[..insert_at], &segments, &jpeg[insert_at..]].concat())
+}
+
+fn png_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(id);
+    chunk.extend_from_slice(data);
+    let mut crc = Crc::new();
+    crc.update(id);
+    crc.update(data);
+    chunk.extend_from_slice(&crc.sum().to_be_bytes());
+    chunk
+}
+
+fn add_png_metadata(png: Vec<u8>, metadata: &Metadata) -> Result<Vec<u8>> {
+    let mut chunks: Vec<u8> = Vec::default();
+    if let Some(icc) = &metadata.icc {
+        let mut encoder = ZlibEncoder::new(b"ICC Profile\0\0".to_vec(), Compression::default());
+        encoder.write_all(icc)?;
+        chunks.extend(png_chunk(b"iCCP", &encoder.finish()?));
+    }
+    if let Some(exif) = &metadata.exif {
+        chunks.extend(png_chunk(b"eXIf", &exif[EXIF_IDENTIFIER.len()..]));
+    }
+    // Right after IHDR, since iCCP has to come before the image data.
+    let ihdr_end = png_chunks(&png)?
+        .first()
+        .map(|ihdr| ihdr.range.end)
+        .ok_or_else(|| anyhow!("the PNG encoder wrote no chunks"))?;
+    Ok([&png[..ihdr_end], &chunks, &png[ihdr_end..]].concat())
+}
+
+fn webp_chunk(fourcc: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = fourcc.to_vec();
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+// Metadata in a WebP needs the extended format: a VP8X chunk with flags, then ICCP, the
+// image, and EXIF, in that order.
+fn add_webp_metadata(webp: Vec<u8>, metadata: &Metadata, image: &DynamicImage) -> Vec<u8> {
+    let mut flags = 0u8;
+    if metadata.icc.is_some() {
+        flags |= 0x20;
+    }
+    if image.color().has_alpha() {
+        flags |= 0x10;
+    }
+    if metadata.exif.is_some() {
+        flags |= 0x08;
+    }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(image.width() - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(image.height() - 1).to_le_bytes()[..3]);
+
+    let mut body = b"WEBP".to_vec();
+    body.extend(webp_chunk(b"VP8X", &vp8x));
+    if let Some(icc) = &metadata.icc {
+        body.extend(webp_chunk(b"ICCP", icc));
+    }
+    body.extend_from_slice(&webp[12..]);
+    if let Some(exif) = &metadata.exif {
+        body.extend(webp_chunk(b"EXIF", &exif[EXIF_IDENTIFIER.len()..]));
+    }
+    let mut riff = b"RIFF".to_vec();
+    riff.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    riff.extend(body);
+    riff
+}
+
+fn encode(
+    image: &DynamicImage,
+    to: ImageType,
+    quality: u8,
+    metadata: &Metadata,
+) -> Result<Vec<u8>> {
+    let mut bytes = Vec::default();
+    match to {
+        ImageType::JPEG => {
+            // JPEG has no alpha channel.
+            let image = if image.color().has_color() {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            } else {
+                DynamicImage::ImageLuma8(image.to_luma8())
+            };
+            JpegEncoder::new_with_quality(&mut bytes, quality).write_image(
+                image.as_bytes(),
+                image.width(),
+                image.height(),
+                image.color(),
+            )?;
+            add_jpeg_metadata(bytes, metadata)
+        }
+        ImageType::PNG => {
+            PngEncoder::new(&mut bytes).write_image(
+                image.as_bytes(),
+                image.width(),
+                image.height(),
+                image.color(),
+            )?;
+            add_png_metadata(bytes, metadata)
+        }
+        ImageType::WEBP => {
+            // The pure Rust encoder only takes 8-bit RGB(A).
+            let rgb = if image.color().has_alpha() {
+                DynamicImage::ImageRgba8(image.to_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            };
+            WebPEncoder::new_lossless(&mut bytes).write_image(
+                rgb.as_bytes(),
+                rgb.width(),
+                rgb.height(),
+                rgb.color(),
+            )?;
+            if metadata.exif.is_none() && metadata.icc.is_none() {
+                Ok(bytes)
+            } else {
+                Ok(add_webp_metadata(bytes, metadata, &rgb))
+            }
+        }
+        _ => Err(anyhow!("can't convert to {}", to.name())),
+    }
+}
+
+struct Helper<'a> {
+    convert: &'a Convert,
+    root: &'a Path,
+    // The canonical --output-dir, which isn't crawled, if it is inside of a directory that is.
+    output_dir: Option<&'a Path>,
+    filer: &'a Filer,
+    progress: &'a Progress,
+    errors: &'a CrawlErrors,
+}
+
+#[derive(Default)]
+struct Info {
+    image_type: Option<ImageType>,
+}
+
+impl<'a> Helper<'a> {
+    fn destination(&self, path: &Path) -> Result<PathBuf> {
+        let extension = self.convert.to.preferred_extension();
+        Ok(match &self.convert.output_dir {
+            Some(dir) => dir
+                .join(path.strip_prefix(self.root)?)
+                .with_extension(extension),
+            None => path.with_extension(extension),
+        })
+    }
+
+    fn convert_bytes(&self, bytes: &[u8], image_type: ImageType) -> Result<Vec<u8>> {
+        let mut image = image::load_from_memory(bytes)?;
+        let mut metadata = read_metadata(bytes, image_type)?;
+        if !self.convert.keep_metadata {
+            let orientation = metadata.exif.as_deref().and_then(orientation);
+            image = apply_orientation(image, orientation.unwrap_or(ORIENTATION_NORMAL));
+            metadata = Metadata::default();
+        }
+        if let Some(max) = self.convert.max_dimension {
+            if image.width() > max || image.height() > max {
+                image = image.resize(max, max, FilterType::Lanczos3);
+            }
+        }
+        let quality = self.convert.quality.unwrap_or(DEFAULT_QUALITY);
+        encode(&image, self.convert.to, quality, &metadata)
+    }
+
+    fn record(&self, dest: &Path) -> Result<()> {
+        // Drops anything we knew about an older file at `dest`.
+        self.filer.set_stat(dest, FileStat::from_path(dest)?);
+        record_image_type(self.filer, dest, &mut File::open(dest)?);
+        if !self.filer.contains_hash(dest, HASH_NAME) {
+            let hash = hash_reader(
+                &mut File::open(dest)?,
+                default_hash_algorithm(),
+                self.progress,
+            )?;
+            self.filer.add_hash(dest, HASH_NAME, hash)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> CrawlHelper for Helper<'a> {
+    type InfoType = Info;
+
+    fn should_descend(&self, e: &DirEntry) -> Result<bool> {
+        // Basically just trimming off hidden directories.
+        Ok(!is_hidden(e) && !self.output_dir.is_some_and(|dir| is_inside(e, dir)))
+    }
+
+    fn should_process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<bool> {
+        let image_type = ImageType::type_of_file_at(e.path())?;
+        it.image_type = Some(image_type);
+        if image_type == ImageType::UNKNOWN
+            || self.convert.from.is_some_and(|from| from != image_type)
+        {
+            return Ok(false);
+        }
+        // Re-encoding in the same format only makes sense to shrink the file.
+        if image_type == self.convert.to
+            && self.convert.quality.is_none()
+            && self.convert.max_dimension.is_none()
+        {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<()> {
+        let path = e.path();
+        let image_type = it.image_type.unwrap_or(ImageType::UNKNOWN);
+        let mut file = File::open(path)?;
+        if image_type
+            .animation(&mut file)?
+            .is_some_and(|a| a.is_animated())
+        {
+            println!("{}: skipped, animations can't be converted", path.display());
+            return Ok(());
+        }
+        let dest = self.destination(path)?;
+        // In place, that would be some other image that happens to share the name. Under the
+        // output directory, it is most likely from an earlier run, but may be the only copy of
+        // something.
+        if dest != path && dest.exists() {
+            info!(
+                "{}: skipped, {} already exists",
+                path.display(),
+                dest.display()
+            );
+            return Ok(());
+        }
+        if self.convert.dry_run {
+            println!("{}: would convert to {}", path.display(), dest.display());
+            return Ok(());
+        }
+
+        let bytes = fs::read(path)?;
+        self.progress.add_bytes(bytes.len() as u64);
+        let converted = self.convert_bytes(&bytes, image_type)?;
+
+        if dest == path {
+            replace_contents(&dest, &converted)?;
+        } else {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&dest, &converted)?;
+        }
+        self.record(&dest)?;
+        if dest != path {
+            self.filer.set_converted_from(&dest, path);
+            if self.convert.in_place {
+                fs::remove_file(path)?;
+                self.filer.remove_file(path);
+            }
+        }
+        println!(
+            "{}: {} ({} bytes -> {} bytes)",
+            path.display(),
+            dest.display(),
+            bytes.len(),
+            converted.len()
+        );
+        Ok(())
+    }
+
+    fn progress(&self) -> Option<&Progress> {
+        Some(self.progress)
+    }
+
+    fn errors(&self) -> Option<&CrawlErrors> {
+        Some(self.errors)
+    }
+}
+
+pub fn process_convert(convert: &Convert, filer: &Filer) -> Result<()> {
+    if !matches!(
+        convert.to,
+        ImageType::JPEG | ImageType::PNG | ImageType::WEBP
+    ) {
+        return Err(anyhow!(
+            "can't convert to {}; use jpeg, png, or webp",
+            convert.to.name()
+        ));
+    }
+    if convert.quality.is_some_and(|q| !(1..=100).contains(&q)) {
+        return Err(anyhow!("the quality must be from 1 to 100"));
+    }
+
+    let output_dir = match &convert.output_dir {
+        // A dry run doesn't create it, but still mustn't crawl it if it's already there.
+        Some(dir) if convert.dry_run => fs::canonicalize(dir).ok(),
+        Some(dir) => Some(prepare_output_dir(dir)?),
+        None => None,
+    };
+    let progress = Progress::new();
+    let errors = CrawlErrors::new(convert.crawl_options.fail_fast);
+    for dir in &convert.directories {
+        let crawler = Crawler::new(
+            dir,
+            &convert.crawl_options,
+            Helper {
+                convert,
+                // Files named on their own go right into --output-dir.
+                root: crawl_root(dir),
+                output_dir: output_dir.as_deref(),
+                filer,
+                progress: &progress,
+                errors: &errors,
+            },
+        );
+        crawler.crawl()?;
+        if errors.should_stop() {
+            break;
+        }
+    }
+    progress.finish();
+    errors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::imt::image_metadata::jpeg_metadata;
+
+    fn jpeg_with(metadata: &Metadata) -> Result<Vec<u8>> {
+        let image = DynamicImage::new_rgb8(16, 16);
+        encode(&image, ImageType::JPEG, DEFAULT_QUALITY, metadata)
+    }
+
+    #[test]
+    fn icc_profile_is_split_and_joined() {
+        let icc: Vec<u8> = (0..ICC_CHUNK_LEN * 2 + 10).map(|i| i as u8).collect();
+        let metadata = Metadata {
+            exif: None,
+            icc: Some(icc.clone()),
+        };
+        let jpeg = jpeg_with(&metadata).unwrap();
+        assert_eq!(jpeg_metadata(&jpeg).unwrap().icc, Some(icc));
+    }
+
+    #[test]
+    fn icc_profile_needing_too_many_segments_is_an_error() {
+        let metadata = Metadata {
+            exif: None,
+            icc: Some(vec![0; ICC_CHUNK_LEN * 255 + 1]),
+        };
+        assert!(jpeg_with(&metadata).is_err());
+    }
+
+    fn convert_to_png(args: &[&std::ffi::OsStr]) -> Result<()> {
+        let convert = Convert::from_iter_safe(
+            ["convert", "--to", "png"]
+                .iter()
+                .map(std::ffi::OsStr::new)
+                .chain(args.iter().copied()),
+        )?;
+        process_convert(&convert, &Filer::new()?)
+    }
+
+    #[test]
+    fn output_dir_files_are_not_replaced() {
+        // Not the default ".tmp" name, since hidden directories aren't crawled.
+        let dir = tempfile::Builder::new().prefix("imt").tempdir().unwrap();
+        fs::copy("test_images/test.jpg", dir.path().join("test.jpg")).unwrap();
+        let out = TempDir::new().unwrap();
+        let dest = out.path().join("test.png");
+        fs::write(&dest, "something else").unwrap();
+
+        let args = [
+            "--output-dir".as_ref(),
+            out.path().as_os_str(),
+            dir.path().as_os_str(),
+        ];
+        // Skipped, so that a rerun carries on where the last one stopped.
+        convert_to_png(&args).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"something else");
+
+        fs::remove_file(&dest).unwrap();
+        convert_to_png(&args).unwrap();
+        assert_eq!(ImageType::type_of_file_at(&dest).unwrap(), ImageType::PNG);
+    }
+
+    #[test]
+    fn single_file_goes_into_output_dir() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a.jpg");
+        fs::copy("test_images/test.jpg", &file).unwrap();
+        let out = dir.path().join("out");
+
+        convert_to_png(&["--output-dir".as_ref(), out.as_os_str(), file.as_os_str()]).unwrap();
+        assert_eq!(
+            ImageType::type_of_file_at(out.join("a.png")).unwrap(),
+            ImageType::PNG
+        );
+        assert!(!dir.path().join("out.png").exists());
+    }
+
+    #[test]
+    fn output_dir_is_not_crawled() {
+        let dir = tempfile::Builder::new().prefix("imt").tempdir().unwrap();
+        fs::copy("test_images/test.jpg", dir.path().join("a.jpg")).unwrap();
+        let out = dir.path().join("converted");
+        // With --quality, the PNGs that were written would be converted again if they were
+        // found.
+        let args = [
+            "--quality".as_ref(),
+            "90".as_ref(),
+            "--output-dir".as_ref(),
+            out.as_os_str(),
+            dir.path().as_os_str(),
+        ];
+
+        convert_to_png(&args).unwrap();
+        convert_to_png(&args).unwrap();
+        assert!(out.join("a.png").exists());
+        assert!(!out.join("converted").exists());
+    }
+}
@@ -1,15 +1,20 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use log::{debug, error, info};
-use std::error::Error;
 use walkdir::{DirEntry, WalkDir};
 
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::{CrawlOptions, IgnoreRules};
+use crate::imt::image_type::ImageType;
+use crate::imt::progress::Progress;
+
 pub struct Crawler<H>
 where
     H: CrawlHelper,
 {
     path: PathBuf,
+    options: CrawlOptions,
     helper: H,
 }
 
@@ -17,8 +22,8 @@ pub trait CrawlHelper {
     type InfoType: Default;
 
     fn handle_error(&self, err: &anyhow::Error) {
-        log::error!("{}", err.description());
-        eprintln!("Error: {}", err.description());
+        log::error!("{:#}", err);
+        eprintln!("Error: {:#}", err);
     }
     fn should_descend(&self, _e: &DirEntry) -> Result<bool> {
         Ok(true)
@@ -30,6 +35,18 @@ pub trait CrawlHelper {
         Ok(true)
     }
     fn process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<()>;
+
+    // If the helper returns a Progress, the Crawler counts files in it. The helper can add
+    // the number of bytes that it reads.
+    fn progress(&self) -> Option<&Progress> {
+        None
+    }
+
+    // If the helper returns a CrawlErrors, the Crawler records every error in it, and stops
+    // early if it is in fail-fast mode.
+    fn errors(&self) -> Option<&CrawlErrors> {
+        None
+    }
 }
 
 struct EntryInfo<T>
@@ -44,7 +61,7 @@ impl<T> EntryInfo<T>
 where
     T: Default,
 {
-    fn path_to_display(&self) -> std::path::Display {
+    fn path_to_display(&self) -> std::path::Display<'_> {
         self.entry.path().display()
     }
 }
@@ -53,12 +70,13 @@ impl<H> Crawler<H>
 where
     H: CrawlHelper,
 {
-    pub fn new<P>(p: P, h: H) -> Crawler<H>
+    pub fn new<P>(p: P, options: &CrawlOptions, h: H) -> Crawler<H>
     where
         PathBuf: From<P>,
     {
         Crawler {
             path: PathBuf::from(p),
+            options: options.clone(),
             helper: h,
         }
     }
@@ -68,19 +86,58 @@ where
         // and write it ourselves so that we can call it.skip_current_dir().
         // We cannot use filter_entry() directly since we want to create
         // EntryInfos to pass to all of the helpers.
-        // TODO: return Result from all of the helpers and DTRT with errors.
         debug!("Walking into {}", self.path.display());
-        let mut it = WalkDir::new(&self.path).into_iter();
+        let mut rules = IgnoreRules::new(&self.options)?;
+        let mut walker = WalkDir::new(&self.path)
+            .follow_links(self.options.follow_links)
+            .same_file_system(self.options.one_file_system);
+        if let Some(max_depth) = self.options.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        let mut it = walker.into_iter();
         loop {
             let entry = match it.next() {
                 None => break,
                 Some(Err(err)) => {
-                    error!("Error getting next DirEntry: {}", err.description());
-                    self.helper.handle_error(&(err.into()));
+                    error!("Error getting next DirEntry: {}", err);
+                    if self.report_error(err.into()) {
+                        break;
+                    }
                     continue;
                 }
                 Some(Ok(e)) => e,
             };
+            match rules.is_ignored(&entry) {
+                Err(err) => {
+                    error!(
+                        "Error checking ignore rules for {}: {}",
+                        entry.path().display(),
+                        err
+                    );
+                    let path = entry.path().display().to_string();
+                    if self.report_error(err.context(path)) {
+                        break;
+                    }
+                }
+                Ok(true) => {
+                    info!("Ignoring {}", entry.path().display());
+                    if entry.file_type().is_dir() {
+                        it.skip_current_dir();
+                    }
+                    continue;
+                }
+                Ok(false) => {}
+            }
+            // We don't use WalkDir's min_depth, since the ignore rules and should_descend()
+            // still need to see the directories above it.
+            let too_shallow = self
+                .options
+                .min_depth
+                .is_some_and(|min| entry.depth() < min);
+            if too_shallow && !entry.file_type().is_dir() {
+                debug!("Skipping {}: above minimum depth", entry.path().display());
+                continue;
+            }
             let mut ei = EntryInfo {
                 entry,
                 info: H::InfoType::default(),
@@ -90,19 +147,23 @@ where
                     error!(
                         "Error processing filter for file {}: {}",
                         ei.path_to_display(),
-                        err.description()
+                        err
                     );
-                    self.helper.handle_error(&err);
+                    let path = ei.path_to_display().to_string();
+                    if self.report_error(err.context(path)) {
+                        break;
+                    }
                 }
                 Ok((b, is_dir)) => {
                     if b {
-                        if let Err(err) = self.process_entry(&mut ei) {
-                            error!(
-                                "Error processing entry for {}: {}",
-                                ei.path_to_display(),
-                                err.description()
-                            );
-                            self.helper.handle_error(&err);
+                        let result = self
+                            .process_entry(&mut ei)
+                            .with_context(|| ei.path_to_display().to_string());
+                        if let Err(err) = result {
+                            error!("Error processing entry: {:#}", err);
+                            if self.report_error(err) {
+                                break;
+                            }
                         }
                     } else {
                         debug!("Skipping {}", ei.path_to_display());
@@ -117,13 +178,33 @@ where
         Ok(())
     }
 
+    // Returns true if the crawl should stop.
+    fn report_error(&self, err: anyhow::Error) -> bool {
+        self.helper.handle_error(&err);
+        match self.helper.errors() {
+            Some(errors) => {
+                errors.record(&err);
+                if errors.should_stop() {
+                    info!("Stopping at the first error.");
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+
     // Returns => Ok((filter, is_dir))
     fn filter(&self, ei: &mut EntryInfo<H::InfoType>) -> Result<(bool, bool)> {
         let path = ei.entry.path();
         Ok(if path.is_dir() {
             (self.filter_dir(ei)?, true)
         } else if path.is_file() && path.exists() {
-            (self.filter_file(ei)?, false)
+            if let Some(progress) = self.helper.progress() {
+                progress.file_seen();
+            }
+            (self.filter_animation(path)? && self.filter_file(ei)?, false)
         } else {
             (false, false)
         })
@@ -133,6 +214,17 @@ where
         self.helper.should_descend(&ei.entry)
     }
 
+    // Files that aren't images are neither animations nor stills.
+    fn filter_animation(&self, path: &Path) -> Result<bool> {
+        match self.options.animation_filter() {
+            None => Ok(true),
+            Some(animated) => {
+                Ok(ImageType::animation_of_file_at(path)?
+                    .is_some_and(|a| a.is_animated() == animated))
+            }
+        }
+    }
+
     fn filter_file(&self, ei: &mut EntryInfo<H::InfoType>) -> Result<bool> {
         self.helper.should_process_file(&ei.entry, &mut ei.info)
     }
@@ -150,6 +242,95 @@ where
     }
 
     fn process_file(&self, ei: &mut EntryInfo<H::InfoType>) -> Result<()> {
-        self.helper.process_file(&ei.entry, &mut ei.info)
+        self.helper.process_file(&ei.entry, &mut ei.info)?;
+        if let Some(progress) = self.helper.progress() {
+            progress.file_processed();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs;
+
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // Records the files it is given, relative to `root`.
+    struct Collect<'a> {
+        root: &'a Path,
+        seen: RefCell<Vec<String>>,
+    }
+
+    impl<'a> CrawlHelper for Collect<'a> {
+        type InfoType = ();
+
+        fn process_file(&self, e: &DirEntry, _it: &mut Self::InfoType) -> Result<()> {
+            let rest = e.path().strip_prefix(self.root)?;
+            self.seen
+                .borrow_mut()
+                .push(rest.to_string_lossy().replace('\\', "/"));
+            Ok(())
+        }
+    }
+
+    fn crawl(root: &Path, args: &[&str]) -> Vec<String> {
+        let options = CrawlOptions::from_iter_safe(["crawl"].iter().chain(args)).unwrap();
+        let helper = Collect {
+            root,
+            seen: RefCell::default(),
+        };
+        let crawler = Crawler::new(root, &options, helper);
+        crawler.crawl().unwrap();
+        let mut seen = crawler.helper.seen.take();
+        seen.sort();
+        seen
+    }
+
+    // One file at each depth from 1 to 3.
+    fn tree() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        for file in ["one", "a/two", "a/b/three"] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn depth_limits() {
+        let dir = tree();
+        let root = dir.path();
+        assert_eq!(crawl(root, &[]), ["a/b/three", "a/two", "one"]);
+        assert_eq!(crawl(root, &["--max-depth", "2"]), ["a/two", "one"]);
+        assert_eq!(crawl(root, &["--min-depth", "2"]), ["a/b/three", "a/two"]);
+        assert_eq!(
+            crawl(root, &["--min-depth", "2", "--max-depth", "2"]),
+            ["a/two"]
+        );
+    }
+
+    #[test]
+    fn min_depth_still_applies_ignore_rules_above_it() {
+        let dir = tree();
+        assert_eq!(
+            crawl(dir.path(), &["--min-depth", "2", "--exclude", "b"]),
+            ["a/two"]
+        );
+    }
+
+    #[test]
+    fn animation_filters() {
+        let dir = TempDir::new().unwrap();
+        for name in ["test.gif", "test.jpg", "not_an_image"] {
+            fs::copy(Path::new("test_images").join(name), dir.path().join(name)).unwrap();
+        }
+        assert_eq!(crawl(dir.path(), &["--animated-only"]), ["test.gif"]);
+        // Files that aren't images are neither.
+        assert_eq!(crawl(dir.path(), &["--still-only"]), ["test.jpg"]);
     }
 }
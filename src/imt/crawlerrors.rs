@@ -0,0 +1,207 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::ErrorKind;
+
+/// Returned by a command when some of the files could not be processed. The work done on the
+/// other files is still good, so the catalog should be saved anyway.
+#[derive(Debug)]
+pub struct CrawlFailed {
+    pub count: usize,
+}
+
+impl fmt::Display for CrawlFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} files could not be processed", self.count)
+    }
+}
+
+impl std::error::Error for CrawlFailed {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorCategory {
+    Permission,
+    Io,
+    Parse,
+    Unsupported,
+    Other,
+}
+
+impl ErrorCategory {
+    fn of_io_error(kind: ErrorKind) -> ErrorCategory {
+        match kind {
+            ErrorKind::PermissionDenied => ErrorCategory::Permission,
+            ErrorKind::Unsupported => ErrorCategory::Unsupported,
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => ErrorCategory::Parse,
+            _ => ErrorCategory::Io,
+        }
+    }
+
+    pub fn of(err: &anyhow::Error) -> ErrorCategory {
+        for cause in err.chain() {
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                return ErrorCategory::of_io_error(e.kind());
+            }
+            if let Some(e) = cause.downcast_ref::<walkdir::Error>() {
+                // No io_error() means a filesystem loop.
+                return e.io_error().map_or(ErrorCategory::Io, |io| {
+                    ErrorCategory::of_io_error(io.kind())
+                });
+            }
+            if let Some(e) = cause.downcast_ref::<image::ImageError>() {
+                return match e {
+                    image::ImageError::IoError(io) => ErrorCategory::of_io_error(io.kind()),
+                    image::ImageError::Decoding(_) => ErrorCategory::Parse,
+                    image::ImageError::Unsupported(_) => ErrorCategory::Unsupported,
+                    _ => ErrorCategory::Other,
+                };
+            }
+            if cause.is::<toml::de::Error>()
+                || cause.is::<serde_json::Error>()
+                || cause.is::<globset::Error>()
+                || cause.is::<ignore::Error>()
+                || cause.is::<zip::result::ZipError>()
+            {
+                return ErrorCategory::Parse;
+            }
+        }
+        ErrorCategory::Other
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorCategory::Permission => "permission denied",
+            ErrorCategory::Io => "I/O",
+            ErrorCategory::Parse => "parse",
+            ErrorCategory::Unsupported => "unsupported",
+            ErrorCategory::Other => "other",
+        }
+    }
+}
+
+// How many messages to keep for each category, to show in the summary.
+const EXAMPLES_PER_CATEGORY: usize = 3;
+
+#[derive(Default)]
+struct Tally {
+    count: usize,
+    examples: Vec<String>,
+}
+
+/// Collects the errors from a crawl, so that we can summarize them at the end.
+#[derive(Default)]
+pub struct CrawlErrors {
+    fail_fast: bool,
+    tallies: RefCell<BTreeMap<ErrorCategory, Tally>>,
+}
+
+impl CrawlErrors {
+    pub fn new(fail_fast: bool) -> CrawlErrors {
+        CrawlErrors {
+            fail_fast,
+            tallies: RefCell::default(),
+        }
+    }
+
+    pub fn record(&self, err: &anyhow::Error) {
+        let mut tallies = self.tallies.borrow_mut();
+        let tally = tallies.entry(ErrorCategory::of(err)).or_default();
+        tally.count += 1;
+        if tally.examples.len() < EXAMPLES_PER_CATEGORY {
+            tally.examples.push(format!("{:#}", err));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.tallies.borrow().values().map(|t| t.count).sum()
+    }
+
+    /// True if we're in fail-fast mode and have already seen an error.
+    pub fn should_stop(&self) -> bool {
+        self.fail_fast && self.count() > 0
+    }
+
+    /// Print a summary of the errors, if there were any, and return CrawlFailed.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let count = self.count();
+        if count == 0 {
+            return Ok(());
+        }
+
+        eprintln!();
+        eprintln!("Errors:");
+        for (category, tally) in self.tallies.borrow().iter() {
+            eprintln!("  {}: {}", category.name(), tally.count);
+            for example in &tally.examples {
+                eprintln!("    {}", example);
+            }
+            if tally.count > tally.examples.len() {
+                eprintln!("    ...");
+            }
+        }
+        Err(CrawlFailed { count }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    fn category_of<E: std::error::Error + Send + Sync + 'static>(err: E) -> ErrorCategory {
+        ErrorCategory::of(&anyhow::Error::new(err))
+    }
+
+    #[test]
+    fn io_errors_by_kind() {
+        let of_kind = |kind| category_of(io::Error::from(kind));
+        assert_eq!(
+            of_kind(ErrorKind::PermissionDenied),
+            ErrorCategory::Permission
+        );
+        assert_eq!(of_kind(ErrorKind::UnexpectedEof), ErrorCategory::Parse);
+        assert_eq!(of_kind(ErrorKind::Unsupported), ErrorCategory::Unsupported);
+        assert_eq!(of_kind(ErrorKind::NotFound), ErrorCategory::Io);
+    }
+
+    #[test]
+    fn causes_are_found_under_context() {
+        let err = Err::<(), _>(io::Error::from(ErrorKind::PermissionDenied))
+            .context("photo.jpg")
+            .unwrap_err();
+        assert_eq!(ErrorCategory::of(&err), ErrorCategory::Permission);
+        assert_eq!(
+            ErrorCategory::of(&anyhow!("something")),
+            ErrorCategory::Other
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let toml = toml::from_str::<toml::Value>("= nope").unwrap_err();
+        assert_eq!(category_of(toml), ErrorCategory::Parse);
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        assert_eq!(category_of(json), ErrorCategory::Parse);
+    }
+
+    #[test]
+    fn image_errors() {
+        let broken = image::load_from_memory(b"\x89PNG\r\n\x1a\n broken").unwrap_err();
+        assert_eq!(category_of(broken), ErrorCategory::Parse);
+        let unknown = image::load_from_memory(b"not an image at all").unwrap_err();
+        assert_eq!(category_of(unknown), ErrorCategory::Unsupported);
+    }
+
+    #[test]
+    fn fail_fast_stops_at_the_first_error() {
+        let errors = CrawlErrors::new(true);
+        assert!(!errors.should_stop());
+        errors.record(&anyhow!("one"));
+        assert!(errors.should_stop());
+        assert!(errors.finish().unwrap_err().is::<CrawlFailed>());
+        assert!(CrawlErrors::new(false).finish().is_ok());
+    }
+}
@@ -0,0 +1,308 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use structopt::StructOpt;
+use walkdir::DirEntry;
+
+/// The name of the gitignore-style file that lists paths to skip in a directory.
+pub const IGNORE_FILE_NAME: &str = ".imtignore";
+
+// Options shared by every command that crawls directories.
+#[derive(StructOpt, Debug, Default, Clone)]
+pub struct CrawlOptions {
+    /// Skip files and directories whose path or name matches this glob. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Only process files whose path or name matches this glob. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Don't read .imtignore files.
+    #[structopt(long)]
+    pub no_ignore_files: bool,
+
+    /// Follow symbolic links. Links that lead back to a parent directory are reported and skipped.
+    #[structopt(long)]
+    pub follow_links: bool,
+
+    /// Don't descend more than this many directories below the starting point.
+    #[structopt(long)]
+    pub max_depth: Option<usize>,
+
+    /// Don't process files less than this many directories below the starting point.
+    #[structopt(long)]
+    pub min_depth: Option<usize>,
+
+    /// Don't cross into other filesystems.
+    #[structopt(long)]
+    pub one_file_system: bool,
+
+    /// Only process animated images.
+    #[structopt(long, conflicts_with = "still-only")]
+    pub animated_only: bool,
+
+    /// Only process images that are not animated.
+    #[structopt(long)]
+    pub still_only: bool,
+
+    /// Catalog the files inside of zip and tar archives, as "archive.zip!/path/in/archive".
+    /// Ignored by commands that change files.
+    #[structopt(long)]
+    pub archives: bool,
+
+    /// Stop at the first file that can't be processed.
+    #[structopt(long)]
+    pub fail_fast: bool,
+}
+
+impl CrawlOptions {
+    // Some(true) to keep only animations, Some(false) to keep only stills, or None to keep
+    // everything.
+    pub fn animation_filter(&self) -> Option<bool> {
+        if self.animated_only {
+            Some(true)
+        } else if self.still_only {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+fn build_glob_set(globs: &[String]) -> Result<Option<GlobSet>> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        // Like gitignore, '*' doesn't match across directories.
+        builder.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+fn matches_path_or_name(set: &GlobSet, path: &Path) -> bool {
+    set.is_match(path) || path.file_name().is_some_and(|n| set.is_match(n))
+}
+
+/// The compiled form of CrawlOptions' ignore rules. The Crawler applies these before calling
+/// into the CrawlHelper, so every command skips the same things.
+pub struct IgnoreRules {
+    exclude: Option<GlobSet>,
+    include: Option<GlobSet>,
+    read_ignore_files: bool,
+
+    // The .imtignore files for the directories that we're currently inside of,
+    // from outermost to innermost.
+    ignore_files: Vec<(PathBuf, Gitignore)>,
+}
+
+impl IgnoreRules {
+    pub fn new(options: &CrawlOptions) -> Result<IgnoreRules> {
+        Ok(IgnoreRules {
+            exclude: build_glob_set(&options.exclude)?,
+            include: build_glob_set(&options.include)?,
+            read_ignore_files: !options.no_ignore_files,
+            ignore_files: Vec::default(),
+        })
+    }
+
+    /// Returns true if `e` should be skipped. Since the walk is depth first, this also keeps
+    /// track of which .imtignore files are in effect, so it must see every entry in order.
+    pub fn is_ignored(&mut self, e: &DirEntry) -> Result<bool> {
+        let path = e.path();
+        while let Some((dir, _)) = self.ignore_files.last() {
+            if path.starts_with(dir) {
+                break;
+            }
+            self.ignore_files.pop();
+        }
+
+        // The roots were asked for explicitly, so we never skip them.
+        self.check(path, e.file_type().is_dir(), e.depth() == 0)
+    }
+
+    /// Like is_ignored(), but for a single path under `root` that we didn't get to by walking,
+    /// e.g., from a filesystem event. This checks every directory on the way down.
+    pub fn is_path_ignored(&mut self, root: &Path, path: &Path) -> Result<bool> {
+        self.ignore_files.clear();
+        let rest = match path.strip_prefix(root) {
+            Ok(rest) => rest,
+            Err(_) => return Ok(false),
+        };
+
+        let mut current = root.to_path_buf();
+        if self.check(&current, true, true)? {
+            return Ok(true);
+        }
+        let count = rest.components().count();
+        for (i, component) in rest.components().enumerate() {
+            current.push(component);
+            let is_dir = i + 1 < count || current.is_dir();
+            if self.check(&current, is_dir, false)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn check(&mut self, path: &Path, is_dir: bool, is_root: bool) -> Result<bool> {
+        if !is_root
+            && self
+                .exclude
+                .as_ref()
+                .is_some_and(|set| matches_path_or_name(set, path))
+        {
+            return Ok(true);
+        }
+        if !is_dir
+            && self
+                .include
+                .as_ref()
+                .is_some_and(|set| !matches_path_or_name(set, path))
+        {
+            return Ok(true);
+        }
+
+        // The innermost file with an opinion wins.
+        for (_, gitignore) in self.ignore_files.iter().rev() {
+            let m = gitignore.matched(path, is_dir);
+            if m.is_ignore() {
+                return Ok(true);
+            }
+            if m.is_whitelist() {
+                break;
+            }
+        }
+
+        if is_dir && self.read_ignore_files {
+            self.push_ignore_file(path)?;
+        }
+        Ok(false)
+    }
+
+    fn push_ignore_file(&mut self, dir: &Path) -> Result<()> {
+        let ignore_path = dir.join(IGNORE_FILE_NAME);
+        if !ignore_path.is_file() {
+            return Ok(());
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(err) = builder.add(&ignore_path) {
+            return Err(anyhow!("{}: {}", ignore_path.display(), err));
+        }
+        self.ignore_files
+            .push((dir.to_path_buf(), builder.build()?));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    use super::*;
+
+    // A tree with an .imtignore at the top that skips "*.tmp" and "raw/", and one in "keep"
+    // that brings "*.tmp" files back.
+    fn tree() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for sub in ["raw", "keep/raw", "other"] {
+            fs::create_dir_all(dir.path().join(sub)).unwrap();
+        }
+        for file in [
+            "a.jpg",
+            "a.tmp",
+            "raw/b.jpg",
+            "keep/c.jpg",
+            "keep/c.tmp",
+            "keep/raw/d.jpg",
+            "other/e.png",
+        ] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        fs::write(dir.path().join(IGNORE_FILE_NAME), "*.tmp\nraw/\n").unwrap();
+        fs::write(dir.path().join("keep").join(IGNORE_FILE_NAME), "!*.tmp\n").unwrap();
+        dir
+    }
+
+    // The files a crawl would see, relative to `root`, sorted.
+    fn walk(root: &Path, args: &[&str]) -> Vec<String> {
+        let options = CrawlOptions::from_iter_safe(["crawl"].iter().chain(args)).unwrap();
+        let mut rules = IgnoreRules::new(&options).unwrap();
+        let mut seen = Vec::default();
+        let mut it = WalkDir::new(root).sort_by_file_name().into_iter();
+        while let Some(entry) = it.next() {
+            let entry = entry.unwrap();
+            if rules.is_ignored(&entry).unwrap() {
+                if entry.file_type().is_dir() {
+                    it.skip_current_dir();
+                }
+            } else if entry.file_type().is_file() && entry.file_name() != IGNORE_FILE_NAME {
+                let rest = entry.path().strip_prefix(root).unwrap();
+                seen.push(rest.to_string_lossy().replace('\\', "/"));
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn innermost_ignore_file_wins() {
+        let dir = tree();
+        assert_eq!(
+            walk(dir.path(), &[]),
+            ["a.jpg", "keep/c.jpg", "keep/c.tmp", "other/e.png"]
+        );
+    }
+
+    #[test]
+    fn ignore_files_can_be_turned_off() {
+        let dir = tree();
+        assert_eq!(walk(dir.path(), &["--no-ignore-files"]).len(), 7);
+    }
+
+    #[test]
+    fn exclude_and_include_globs() {
+        let dir = tree();
+        assert_eq!(
+            walk(dir.path(), &["--exclude", "other", "--exclude", "c.*"]),
+            ["a.jpg"]
+        );
+        assert_eq!(
+            walk(dir.path(), &["--include", "*.png", "--no-ignore-files"]),
+            ["other/e.png"]
+        );
+        // '*' stays within one directory.
+        assert!(walk(dir.path(), &["--include", "*/e.png"]).is_empty());
+    }
+
+    #[test]
+    fn single_paths_are_checked_on_the_way_down() {
+        let dir = tree();
+        let options = CrawlOptions::default();
+        let mut rules = IgnoreRules::new(&options).unwrap();
+        let root = dir.path();
+        assert!(rules
+            .is_path_ignored(root, &root.join("raw/b.jpg"))
+            .unwrap());
+        assert!(rules.is_path_ignored(root, &root.join("a.tmp")).unwrap());
+        assert!(!rules
+            .is_path_ignored(root, &root.join("keep/c.tmp"))
+            .unwrap());
+        assert!(rules
+            .is_path_ignored(root, &root.join("keep/raw/d.jpg"))
+            .unwrap());
+        assert!(!rules
+            .is_path_ignored(root, &root.join("other/e.png"))
+            .unwrap());
+        // Outside of the root, nothing applies.
+        assert!(!rules
+            .is_path_ignored(root, Path::new("/elsewhere/a.tmp"))
+            .unwrap());
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use log::info;
+use serde::Deserialize;
+
+use crate::imt::archive::archive_of;
+use crate::imt::filer::{decode_path, FileStat, Filer};
+use crate::imt::hashers::HashAlgorithm;
+use crate::imt::journal::Journal;
+
+/// The decisions file exported from the duplicate report.
+#[derive(Debug, Deserialize)]
+struct Decisions {
+    // Catalog paths, encoded as they are in the catalog.
+    delete: Vec<String>,
+}
+
+// Whether the file at `path` is still what the catalog says it is.
+fn is_unchanged(filer: &Filer, path: &Path) -> bool {
+    match (filer.stat(path), FileStat::from_path(path)) {
+        (Some(cataloged), Ok(current)) => cataloged.same_size_and_mtime(&current),
+        _ => false,
+    }
+}
+
+// Whether `a` and `b` are the same file, under two names. Hard links count, since deleting
+// one of them wouldn't free anything anyway.
+fn same_file(a: &Path, b: &Path) -> bool {
+    if let (Ok(a), Ok(b)) = (FileStat::from_path(a), FileStat::from_path(b)) {
+        if a.same_inode(&b) {
+            return true;
+        }
+    }
+    matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+// Compares the contents of two files, a chunk at a time.
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let a_buf = a.fill_buf()?;
+        let b_buf = b.fill_buf()?;
+        let n = a_buf.len().min(b_buf.len());
+        if n == 0 {
+            // Only equal if both ended, in case a file changed under us.
+            return Ok(a_buf.is_empty() && b_buf.is_empty());
+        }
+        if a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+        a.consume(n);
+        b.consume(n);
+    }
+}
+
+/// What happened, or would happen, to one file that was marked for deletion.
+pub struct Deletion {
+    pub path: PathBuf,
+    // Why the file was kept, or None if it was (or would be) deleted.
+    pub refused: Option<String>,
+}
+
+/// Delete the files in `doomed`. A file is only deleted if it hasn't changed since it was
+/// cataloged, and a copy with the same `hash` that isn't being deleted still exists. Unless
+/// `trust_hashes` is set, every file is also compared byte for byte with the copy that is
+/// kept, and if any file with the same hash doesn't match, none of them are deleted. The
+/// checks and deletions are written to the journal. The results are sorted by path.
+pub fn delete_duplicates(
+    filer: &Filer,
+    doomed: &HashSet<PathBuf>,
+    hash: &HashAlgorithm,
+    dry_run: bool,
+    trust_hashes: bool,
+) -> Result<Vec<Deletion>> {
+    if trust_hashes && !hash.cryptographic {
+        bail!(
+            "{} is not a cryptographic hash, so it can't be trusted",
+            hash.name
+        );
+    }
+    let mut by_hash: HashMap<String, Vec<PathBuf>> = HashMap::default();
+    filer.with_files(|p| {
+        if let Some(hash) = filer.hash_value(p, hash.name) {
+            by_hash.entry(hash).or_default().push(p.clone());
+        }
+    });
+    by_hash.values_mut().for_each(|paths| paths.sort());
+    let mut journal = if dry_run {
+        None
+    } else {
+        Some(Journal::open()?)
+    };
+
+    // First decide, for each file, whether it could go, and which copy it would leave.
+    let mut sorted: Vec<&PathBuf> = doomed.iter().collect();
+    sorted.sort();
+    let mut plans: Vec<(PathBuf, Result<PathBuf, String>)> = Vec::default();
+    let mut failed_hashes: HashSet<String> = HashSet::default();
+    for victim in sorted {
+        let victim_hash = filer.hash_value(victim, hash.name);
+        let keeper = if archive_of(victim).is_some() {
+            Err("it is inside of an archive".to_string())
+        } else if !is_unchanged(filer, victim) {
+            Err("it has changed, or is missing, since it was cataloged".to_string())
+        } else {
+            victim_hash
+                .as_ref()
+                .and_then(|hash| {
+                    by_hash.get(hash)?.iter().find(|p| {
+                        !doomed.contains(*p)
+                            && archive_of(p).is_none()
+                            && is_unchanged(filer, p)
+                            && !same_file(victim, p)
+                    })
+                })
+                .cloned()
+                .ok_or_else(|| "no identical copy would be left".to_string())
+        };
+
+        if let (Ok(keeper), Some(victim_hash)) = (&keeper, &victim_hash) {
+            if trust_hashes {
+                if let Some(journal) = &mut journal {
+                    journal.record("verify", "trusted hash", &[victim, keeper])?;
+                }
+            } else {
+                let same = same_contents(victim, keeper)?;
+                if let Some(journal) = &mut journal {
+                    let outcome = if same { "identical" } else { "MISMATCH" };
+                    journal.record("verify", outcome, &[victim, keeper])?;
+                }
+                if !same {
+                    failed_hashes.insert(victim_hash.clone());
+                }
+            }
+        }
+        plans.push((victim.clone(), keeper));
+    }
+
+    let mut deletions = Vec::default();
+    for (victim, keeper) in plans {
+        let group_failed = filer
+            .hash_value(&victim, hash.name)
+            .is_some_and(|hash| failed_hashes.contains(&hash));
+        let refused = match keeper {
+            Err(reason) => Some(reason),
+            Ok(_) if group_failed => {
+                Some("a file with the same hash didn't match its copy byte for byte".to_string())
+            }
+            Ok(keeper) => {
+                info!("{} is a copy of {}", victim.display(), keeper.display());
+                if !dry_run {
+                    fs::remove_file(&victim)?;
+                    filer.remove_file(&victim);
+                    if let Some(journal) = &mut journal {
+                        journal.record("delete", "deleted", &[&victim, &keeper])?;
+                    }
+                }
+                None
+            }
+        };
+        deletions.push(Deletion {
+            path: victim,
+            refused,
+        });
+    }
+    Ok(deletions)
+}
+
+/// Delete the files listed in the decisions file at `path`, with `delete_duplicates`.
+pub fn apply_decisions(
+    filer: &Filer,
+    path: &Path,
+    hash: &HashAlgorithm,
+    dry_run: bool,
+    trust_hashes: bool,
+) -> Result<()> {
+    let decisions: Decisions = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let doomed: HashSet<PathBuf> = decisions.delete.iter().map(|p| decode_path(p)).collect();
+
+    let deletions = delete_duplicates(filer, &doomed, hash, dry_run, trust_hashes)?;
+    let mut deleted = 0;
+    for deletion in &deletions {
+        match &deletion.refused {
+            Some(reason) => println!("Not deleting {}: {}", deletion.path.display(), reason),
+            None if dry_run => println!("Would delete {}", deletion.path.display()),
+            None => println!("Deleted {}", deletion.path.display()),
+        }
+        if deletion.refused.is_none() {
+            deleted += 1;
+        }
+    }
+
+    eprintln!(
+        "{} {} files, refused {}.",
+        if dry_run { "Would delete" } else { "Deleted" },
+        deleted,
+        deletions.len() - deleted
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::imt::hashers::{default_hash_algorithm, hash_algorithm};
+
+    // Writes `contents` to `name` and catalogs it with the hash `hash`.
+    fn add(filer: &Filer, dir: &TempDir, name: &str, contents: &str, hash: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        if !path.exists() {
+            fs::write(&path, contents).unwrap();
+        }
+        filer.set_stat(&path, FileStat::from_path(&path).unwrap());
+        filer
+            .add_hash(&path, default_hash_algorithm().name, hash)
+            .unwrap();
+        path
+    }
+
+    // Whether each of `doomed` would be deleted, in a dry run.
+    fn would_delete(filer: &Filer, doomed: &[&PathBuf]) -> Vec<bool> {
+        let doomed: HashSet<PathBuf> = doomed.iter().map(|p| p.to_path_buf()).collect();
+        delete_duplicates(filer, &doomed, default_hash_algorithm(), true, false)
+            .unwrap()
+            .iter()
+            .map(|d| d.refused.is_none())
+            .collect()
+    }
+
+    #[test]
+    fn one_copy_is_always_kept() {
+        let dir = TempDir::new().unwrap();
+        let filer = Filer::new().unwrap();
+        let a = add(&filer, &dir, "a", "same", "h");
+        let b = add(&filer, &dir, "b", "same", "h");
+        assert_eq!(would_delete(&filer, &[&a]), [true]);
+        assert_eq!(would_delete(&filer, &[&a, &b]), [false, false]);
+    }
+
+    #[test]
+    fn same_file_under_two_keys_is_not_its_own_copy() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let filer = Filer::new().unwrap();
+        let a = add(&filer, &dir, "a", "only copy", "h");
+        add(&filer, &dir, "sub/../a", "", "h");
+        assert_eq!(would_delete(&filer, &[&a]), [false]);
+    }
+
+    #[test]
+    fn mismatch_refuses_the_whole_group() {
+        let dir = TempDir::new().unwrap();
+        let filer = Filer::new().unwrap();
+        let a = add(&filer, &dir, "a", "same", "h");
+        add(&filer, &dir, "b", "same", "h");
+        // A hash collision, or a file that changed without its mtime changing.
+        let c = add(&filer, &dir, "c", "diff", "h");
+        assert_eq!(would_delete(&filer, &[&a, &c]), [false, false]);
+    }
+
+    #[test]
+    fn changed_file_is_not_deleted() {
+        let dir = TempDir::new().unwrap();
+        let filer = Filer::new().unwrap();
+        let a = add(&filer, &dir, "a", "same", "h");
+        add(&filer, &dir, "b", "same", "h");
+        fs::write(&a, "something new").unwrap();
+        assert_eq!(would_delete(&filer, &[&a]), [false]);
+    }
+
+    #[test]
+    fn only_cryptographic_hashes_are_trusted() {
+        let filer = Filer::new().unwrap();
+        let doomed = HashSet::default();
+        let xxh3 = hash_algorithm("XXH3").unwrap();
+        assert!(delete_duplicates(&filer, &doomed, xxh3, true, true).is_err());
+        assert!(delete_duplicates(&filer, &doomed, default_hash_algorithm(), true, true).is_ok());
+    }
+}
@@ -1,6 +1,68 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
 use walkdir::DirEntry;
 
+use crate::imt::archive::archive_of;
+
 pub fn is_hidden(e: &DirEntry) -> bool {
     let name = e.path().file_name();
-    name.map_or(false, |n| n.to_string_lossy().starts_with('.'))
+    name.is_some_and(|n| n.as_encoded_bytes().starts_with(b"."))
+}
+
+/// The directory that the paths found by crawling `dir` are relative to: `dir` itself, or its
+/// parent if it is a single file.
+pub fn crawl_root(dir: &Path) -> &Path {
+    match dir.parent() {
+        Some(parent) if dir.is_file() => parent,
+        _ => dir,
+    }
+}
+
+/// Creates the output directory of a command, and returns its canonical path, so that the
+/// crawl can tell when it gets there.
+pub fn prepare_output_dir(dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    Ok(fs::canonicalize(dir)?)
+}
+
+/// Whether `e` is the directory `dir`, which must be canonical, or is inside of it.
+pub fn is_inside(e: &DirEntry, dir: &Path) -> bool {
+    fs::canonicalize(e.path()).is_ok_and(|p| p.starts_with(dir))
+}
+
+pub fn has_extension(path: &Path) -> bool {
+    path.extension().map(|e| !e.is_empty()).unwrap_or(false)
+}
+
+pub fn is_missing(path: &Path) -> bool {
+    // An archive member is there for as long as its archive is.
+    if let Some(archive) = archive_of(path) {
+        return is_missing(&archive);
+    }
+    // Anything other than NotFound (e.g., permission denied) means that the file may still
+    // be there.
+    match std::fs::symlink_metadata(path) {
+        Ok(_) => false,
+        Err(err) => err.kind() == ErrorKind::NotFound,
+    }
+}
+
+// Write through a temporary file, so that we never leave a partly written image behind.
+pub fn replace_contents(path: &Path, bytes: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("no file name: {}", path.display()))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(".imt-tmp");
+    let tmp = path.with_file_name(tmp_name);
+    fs::write(&tmp, bytes)?;
+    fs::set_permissions(&tmp, fs::metadata(path)?.permissions())?;
+    fs::rename(&tmp, path)?;
+    Ok(())
 }
@@ -0,0 +1,298 @@
+// A static HTML page for reviewing groups of duplicates. Everything, including the thumbnails,
+// is inside the one file, so it works from the local filesystem without a network. Checking
+// files and pressing "Export" saves a decisions file for 'find-dups --apply-decisions'.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use base64::Engine;
+use chrono::DateTime;
+use log::debug;
+
+use crate::imt::filer::{encode_path, Filer};
+use crate::imt::thumbs::thumbnail_png;
+
+const THUMBNAIL_SIZE: u32 = 160;
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1em 2em; }
+section { border-top: 1px solid #ccc; padding: 0.5em 0; }
+section.all-deleted { background: #fdd; }
+.files { display: flex; flex-wrap: wrap; gap: 1em; }
+.file { width: 220px; padding: 0.5em; border: 2px solid #ddd; border-radius: 4px; }
+.file.keeper { border-color: #2a2; background: #efe; }
+.thumb { width: 160px; height: 160px; background: #eee center no-repeat; }
+.path { font-family: monospace; font-size: 0.8em; word-break: break-all; }
+.details { font-size: 0.8em; color: #555; }
+#toolbar { position: sticky; top: 0; background: white; padding: 0.5em 0; }
+"#;
+
+// Collects the checked files into a decisions file, and marks groups where every copy would be
+// deleted. 'find-dups --apply-decisions' refuses to delete those anyway.
+const SCRIPT: &str = r#"
+function update() {
+  document.querySelectorAll('section').forEach(function (s) {
+    var boxes = s.querySelectorAll('input[type=checkbox]');
+    var all = Array.prototype.every.call(boxes, function (b) { return b.checked; });
+    s.classList.toggle('all-deleted', all);
+  });
+}
+function exportDecisions() {
+  var paths = [];
+  document.querySelectorAll('input[type=checkbox]:checked').forEach(function (b) {
+    paths.push(b.dataset.path);
+  });
+  var blob = new Blob([JSON.stringify({ delete: paths }, null, 2)], { type: 'application/json' });
+  var a = document.createElement('a');
+  a.href = URL.createObjectURL(blob);
+  a.download = 'decisions.json';
+  a.click();
+}
+document.addEventListener('change', update);
+document.addEventListener('DOMContentLoaded', update);
+"#;
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The index of the file in `files` to keep: the oldest, and then the one with the shortest
+/// path, since copies tend to be made later and deeper.
+pub fn proposed_keeper(filer: &Filer, files: &[PathBuf]) -> usize {
+    files
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, p)| {
+            let mtime = filer.stat(p.as_path()).and_then(|s| s.mtime);
+            // None sorts before Some, but unknown dates shouldn't win.
+            (mtime.is_none(), mtime, p.as_os_str().len(), p.as_path())
+        })
+        .map_or(0, |(i, _)| i)
+}
+
+// The thumbnails in the page, as CSS classes, so that copies of one file share one image.
+#[derive(Default)]
+struct Thumbnails {
+    classes: HashMap<String, String>,
+    style: String,
+}
+
+impl Thumbnails {
+    // The CSS class of the thumbnail for `path`, or None if it can't be read or decoded. Files
+    // with the same `hash_name` hash share one.
+    fn class_for(&mut self, filer: &Filer, hash_name: &str, path: &Path) -> Option<String> {
+        let key = filer
+            .hash_value(path, hash_name)
+            .unwrap_or_else(|| encode_path(path));
+        if let Some(class) = self.classes.get(&key) {
+            return Some(class.clone());
+        }
+        let png = fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| thumbnail_png(&bytes, THUMBNAIL_SIZE, &[]));
+        let png = match png {
+            Ok(png) => png,
+            Err(err) => {
+                debug!("No thumbnail for {}: {}", path.display(), err);
+                return None;
+            }
+        };
+        let class = format!("t{}", self.classes.len());
+        let _ = writeln!(
+            self.style,
+            ".{} {{ background-image: url(data:image/png;base64,{}); }}",
+            class,
+            base64::engine::general_purpose::STANDARD.encode(png)
+        );
+        self.classes.insert(key, class.clone());
+        Some(class)
+    }
+}
+
+/// The dimensions, size, and date of a cataloged file, for people to read.
+pub fn describe(filer: &Filer, path: &Path) -> String {
+    let mut details: Vec<String> = Vec::default();
+    if let Some((width, height)) = filer.dimensions(path) {
+        details.push(format!("{}x{}", width, height));
+    }
+    let stat = filer.stat(path).unwrap_or_default();
+    if let Some(size) = stat.size {
+        details.push(format!("{} bytes", size));
+    }
+    if let Some(date) = stat
+        .mtime
+        .and_then(|m| DateTime::from_timestamp(m as i64, 0))
+    {
+        details.push(date.format("%Y-%m-%d %H:%M").to_string());
+    }
+    details.join(", ")
+}
+
+/// Write the review page for `groups`, which are files with the same `hash_name` hash, to `out`.
+pub fn write_html_report(
+    filer: &Filer,
+    groups: &[Vec<PathBuf>],
+    hash_name: &str,
+    out: &Path,
+) -> Result<()> {
+    let mut thumbnails = Thumbnails::default();
+    let mut body = String::new();
+    for (n, files) in groups.iter().enumerate() {
+        let keeper = proposed_keeper(filer, files);
+        let _ = writeln!(
+            body,
+            "<section><h2>Group {} ({} files)</h2><div class=\"files\">",
+            n + 1,
+            files.len()
+        );
+        for (i, path) in files.iter().enumerate() {
+            let thumb_class = thumbnails
+                .class_for(filer, hash_name, path)
+                .unwrap_or_default();
+            let display = escape_html(&path.to_string_lossy());
+            let _ = writeln!(
+                body,
+                concat!(
+                    "<div class=\"file{keeper}\"><div class=\"thumb {thumb}\"></div>",
+                    "<label><input type=\"checkbox\" data-path=\"{key}\"{checked}> delete</label>",
+                    "<div class=\"path\">{path}</div><div class=\"details\">{details}</div>",
+                    "{note}</div>"
+                ),
+                keeper = if i == keeper { " keeper" } else { "" },
+                thumb = thumb_class,
+                key = escape_html(&encode_path(path)),
+                checked = if i == keeper { "" } else { " checked" },
+                path = display,
+                details = escape_html(&describe(filer, path)),
+                note = if i == keeper {
+                    "<div class=\"details\">proposed keeper</div>"
+                } else {
+                    ""
+                },
+            );
+        }
+        body.push_str("</div></section>\n");
+    }
+
+    let html = format!(
+        concat!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">",
+            "<title>Duplicates</title>\n<style>{style}{thumbnails}</style>\n",
+            "<script>{script}</script></head>\n<body>\n",
+            "<div id=\"toolbar\"><strong>{groups} groups.</strong> ",
+            "Checked files will be deleted. ",
+            "<button onclick=\"exportDecisions()\">Export decisions</button></div>\n",
+            "{body}</body></html>\n"
+        ),
+        style = STYLE,
+        thumbnails = thumbnails.style,
+        script = SCRIPT,
+        groups = groups.len(),
+        body = body,
+    );
+    fs::write(out, html)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::imt::filer::FileStat;
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;",
+            escape_html("<a href=\"x\">Tom & Jerry's</a>")
+        );
+        assert_eq!(
+            "/photos/caf\u{e9}.jpg",
+            escape_html("/photos/caf\u{e9}.jpg")
+        );
+    }
+
+    fn filer_with_mtimes(files: &[(&str, Option<u64>)]) -> (Filer, Vec<PathBuf>) {
+        let filer = Filer::new().unwrap();
+        let paths = files
+            .iter()
+            .map(|(path, mtime)| {
+                let path = PathBuf::from(path);
+                filer.set_stat(
+                    &path,
+                    FileStat {
+                        mtime: *mtime,
+                        ..FileStat::default()
+                    },
+                );
+                path
+            })
+            .collect();
+        (filer, paths)
+    }
+
+    #[test]
+    fn oldest_file_is_kept() {
+        let (filer, paths) = filer_with_mtimes(&[
+            ("/a", Some(300)),
+            ("/photos/2020/copy of b", Some(100)),
+            ("/c", Some(200)),
+        ]);
+        assert_eq!(1, proposed_keeper(&filer, &paths));
+    }
+
+    #[test]
+    fn unknown_dates_are_not_kept() {
+        let (filer, paths) = filer_with_mtimes(&[("/a", None), ("/photos/b", Some(100))]);
+        assert_eq!(1, proposed_keeper(&filer, &paths));
+    }
+
+    #[test]
+    fn shortest_path_breaks_ties() {
+        let (filer, paths) = filer_with_mtimes(&[
+            ("/photos/copy/b", Some(100)),
+            ("/photos/b", Some(100)),
+            ("/photos/a", Some(100)),
+        ]);
+        assert_eq!(2, proposed_keeper(&filer, &paths));
+    }
+
+    #[test]
+    fn thumbnails_are_shared_by_hash() {
+        let dir = TempDir::new().unwrap();
+        let paths: Vec<PathBuf> = ["a.jpg", "b.jpg", "c.jpg"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let filer = Filer::new().unwrap();
+        for (path, hash) in paths.iter().zip(["same", "same", "other"]) {
+            fs::copy("test_images/test.jpg", path).unwrap();
+            filer.set_stat(path, FileStat::from_path(path).unwrap());
+            filer.add_hash(path, "BLAKE3", hash).unwrap();
+        }
+
+        let mut thumbnails = Thumbnails::default();
+        let classes: Vec<Option<String>> = paths
+            .iter()
+            .map(|path| thumbnails.class_for(&filer, "BLAKE3", path))
+            .collect();
+        assert_eq!(classes[0], classes[1]);
+        assert_ne!(classes[0], classes[2]);
+        assert_eq!(2, thumbnails.style.lines().count());
+    }
+}
@@ -0,0 +1,181 @@
+// Just enough EXIF to find the Orientation tag. The EXIF data is a TIFF file inside of a JPEG
+// APP1 segment, starting with "Exif\0\0".
+
+use std::convert::TryInto;
+
+pub const EXIF_IDENTIFIER: &[u8] = b"Exif\0\0";
+
+const ORIENTATION_TAG: u16 = 0x0112;
+const SHORT_TYPE: u16 = 3;
+
+// The "normal" orientation, where the pixels are already the right way up.
+pub const ORIENTATION_NORMAL: u16 = 1;
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Tiff<'a>> {
+        let little_endian = match data.get(0..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    // The offset, in `data`, of the value of the orientation tag in IFD0.
+    fn orientation_offset(&self) -> Option<usize> {
+        let ifd = self.u32_at(4)? as usize;
+        let count = self.u16_at(ifd)?;
+        // Each entry is tag(2), type(2), count(4), value or offset(4).
+        (0..usize::from(count))
+            .map(|i| ifd + 2 + i * 12)
+            .find(|entry| self.u16_at(*entry) == Some(ORIENTATION_TAG))
+            .filter(|entry| self.u16_at(entry + 2) == Some(SHORT_TYPE))
+            .map(|entry| entry + 8)
+    }
+}
+
+/// The Orientation tag (1 to 8) from the data of an APP1 segment, if it has one.
+pub fn orientation(app1: &[u8]) -> Option<u16> {
+    let tiff = Tiff::new(app1.strip_prefix(EXIF_IDENTIFIER)?)?;
+    tiff.u16_at(tiff.orientation_offset()?)
+}
+
+/// Overwrite the Orientation tag in the data of an APP1 segment. Returns false if there isn't
+/// one.
+pub fn set_orientation(app1: &mut [u8], orientation: u16) -> bool {
+    let tiff = match app1.strip_prefix(EXIF_IDENTIFIER).and_then(Tiff::new) {
+        Some(tiff) => tiff,
+        None => return false,
+    };
+    let (little_endian, offset) = match tiff.orientation_offset() {
+        Some(offset) => (tiff.little_endian, offset),
+        None => return false,
+    };
+    let bytes = if little_endian {
+        orientation.to_le_bytes()
+    } else {
+        orientation.to_be_bytes()
+    };
+    let pos = EXIF_IDENTIFIER.len() + offset;
+    app1[pos..pos + 2].copy_from_slice(&bytes);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    // The data of an APP1 segment whose IFD0 has an unrelated tag, then `tag`, with a SHORT
+    // value of `value`.
+    fn app1(little_endian: bool, tag: u16, value: u16) -> Vec<u8> {
+        let u16_bytes = |v: u16| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+        let u32_bytes = |v: u32| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+        let mut data = EXIF_IDENTIFIER.to_vec();
+        data.extend_from_slice(if little_endian { b"II*\0" } else { b"MM\0*" });
+        data.extend_from_slice(&u32_bytes(8));
+        data.extend_from_slice(&u16_bytes(2));
+        // ImageWidth, a LONG.
+        data.extend_from_slice(&u16_bytes(0x0100));
+        data.extend_from_slice(&u16_bytes(4));
+        data.extend_from_slice(&u32_bytes(1));
+        data.extend_from_slice(&u32_bytes(640));
+        data.extend_from_slice(&u16_bytes(tag));
+        data.extend_from_slice(&u16_bytes(SHORT_TYPE));
+        data.extend_from_slice(&u32_bytes(1));
+        data.extend_from_slice(&u16_bytes(value));
+        data.extend_from_slice(&[0, 0]);
+        // No next IFD.
+        data.extend_from_slice(&u32_bytes(0));
+        data
+    }
+
+    #[test_case(true ; "little endian")]
+    #[test_case(false ; "big endian")]
+    fn orientation_is_read(little_endian: bool) {
+        assert_eq!(
+            Some(6),
+            orientation(&app1(little_endian, ORIENTATION_TAG, 6))
+        );
+    }
+
+    #[test_case(true ; "little endian")]
+    #[test_case(false ; "big endian")]
+    fn orientation_is_set(little_endian: bool) {
+        let mut data = app1(little_endian, ORIENTATION_TAG, 6);
+        assert!(set_orientation(&mut data, ORIENTATION_NORMAL));
+        assert_eq!(
+            app1(little_endian, ORIENTATION_TAG, ORIENTATION_NORMAL),
+            data
+        );
+        assert_eq!(Some(ORIENTATION_NORMAL), orientation(&data));
+    }
+
+    #[test]
+    fn missing_orientation_is_left_alone() {
+        // Copyright, not Orientation.
+        let mut data = app1(true, 0x8298, 6);
+        assert_eq!(None, orientation(&data));
+        let before = data.clone();
+        assert!(!set_orientation(&mut data, ORIENTATION_NORMAL));
+        assert_eq!(before, data);
+    }
+
+    #[test]
+    fn orientation_with_wrong_type_is_ignored() {
+        let mut data = app1(true, ORIENTATION_TAG, 6);
+        // The type of the second entry: 6 bytes of "Exif\0\0", 8 of header, 2 of count, 12
+        // of the first entry, and 2 of tag.
+        data[6 + 8 + 2 + 12 + 2] = 4;
+        assert_eq!(None, orientation(&data));
+        assert!(!set_orientation(&mut data, ORIENTATION_NORMAL));
+    }
+
+    #[test_case(b"" ; "empty")]
+    #[test_case(b"http://ns.adobe.com/xap/1.0/\0" ; "xmp")]
+    #[test_case(b"Exif\0\0XX*\0\0\0\0\x08" ; "bad byte order")]
+    #[test_case(b"Exif\0\0II*\0\x08\0\0\0\x05\0" ; "truncated")]
+    fn not_exif(data: &[u8]) {
+        assert_eq!(None, orientation(data));
+        assert!(!set_orientation(&mut data.to_vec(), ORIENTATION_NORMAL));
+    }
+}
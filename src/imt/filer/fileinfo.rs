@@ -1,9 +1,13 @@
+use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::Metadata;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-use crate::imt::image_type::ImageType;
-use anyhow::Result;
+use crate::imt::filer::pathkey::{decode_path, encode_path};
+use crate::imt::image_type::{Animation, ImageType};
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::Write;
@@ -13,11 +17,32 @@ pub struct Files {
     files: HashMap<PathBuf, FileInfo>,
 }
 
+/// The files that were in both catalogs being merged, as different versions.
+#[derive(Debug, Default)]
+pub struct MergeConflicts {
+    pub count: usize,
+    // The ones with the same mtime, where the entry that was already there was kept. Sorted.
+    pub ties: Vec<PathBuf>,
+}
+
+/// How two catalogs differ. Each list is sorted.
+#[derive(Debug, Default)]
+pub struct FilesDiff {
+    pub only_in_self: Vec<PathBuf>,
+    pub only_in_other: Vec<PathBuf>,
+    // Files in both, with a different value for a hash that both have.
+    pub different_hashes: Vec<PathBuf>,
+}
+
 impl Files {
     pub fn file_iter(&self) -> impl Iterator<Item = &PathBuf> {
         self.files.keys()
     }
 
+    pub fn info_iter(&self) -> impl Iterator<Item = (&PathBuf, &FileInfo)> {
+        self.files.iter()
+    }
+
     pub fn image_type<P: Into<PathBuf>>(&self, path: P) -> Option<ImageType> {
         self.files.get(&path.into()).and_then(|fi| fi.image_type)
     }
@@ -29,14 +54,84 @@ impl Files {
             .image_type = Some(image_type);
     }
 
+    pub fn dimensions<P: Into<PathBuf>>(&self, path: P) -> Option<(u32, u32)> {
+        self.files.get(&path.into()).and_then(|fi| fi.dimensions())
+    }
+
+    pub fn set_dimensions<P: Into<PathBuf>>(&mut self, path: P, width: u32, height: u32) {
+        let info = self.files.entry(path.into()).or_insert_with(FileInfo::new);
+        info.width = Some(width);
+        info.height = Some(height);
+    }
+
+    pub fn animation<P: Into<PathBuf>>(&self, path: P) -> Option<Animation> {
+        self.files.get(&path.into()).and_then(|fi| fi.animation())
+    }
+
+    pub fn set_animation<P: Into<PathBuf>>(&mut self, path: P, animation: Animation) {
+        let info = self.files.entry(path.into()).or_insert_with(FileInfo::new);
+        info.frames = Some(animation.frames);
+        info.duration_ms = Some(animation.duration_ms);
+    }
+
+    pub fn converted_from<P: Into<PathBuf>>(&self, path: P) -> Option<PathBuf> {
+        self.files
+            .get(&path.into())
+            .and_then(|fi| fi.converted_from())
+    }
+
+    pub fn set_converted_from<P: Into<PathBuf>>(&mut self, path: P, source: &Path) {
+        self.files
+            .entry(path.into())
+            .or_insert_with(FileInfo::new)
+            .converted_from = Some(encode_path(source));
+    }
+
     pub fn add_file<P: Into<PathBuf>>(&mut self, path: P) {
         self.files.entry(path.into()).or_insert_with(FileInfo::new);
     }
 
+    pub fn remove_file<P: Into<PathBuf>>(&mut self, path: P) -> bool {
+        self.files.remove(&path.into()).is_some()
+    }
+
+    pub fn contains_file<P: Into<PathBuf>>(&self, path: P) -> bool {
+        self.files.contains_key(&path.into())
+    }
+
+    // Moves the entry at 'from' to 'to', keeping all of the cached data. If 'to' already has
+    // an entry, its values win over the ones being moved.
+    pub fn move_file<P: Into<PathBuf>, Q: Into<PathBuf>>(&mut self, from: P, to: Q) -> bool {
+        match self.files.remove(&from.into()) {
+            None => false,
+            Some(old) => {
+                self.files
+                    .entry(to.into())
+                    .or_insert_with(FileInfo::new)
+                    .absorb(old);
+                true
+            }
+        }
+    }
+
+    pub fn stat<P: Into<PathBuf>>(&self, path: P) -> Option<FileStat> {
+        self.files.get(&path.into()).map(|fi| fi.stat())
+    }
+
+    // Records the stat for 'path', adding it if necessary. If the size or mod time changed
+    // since we last saw the file, then the cached data is stale, so we drop it.
+    pub fn set_stat<P: Into<PathBuf>>(&mut self, path: P, stat: FileStat) {
+        let info = self.files.entry(path.into()).or_insert_with(FileInfo::new);
+        if info.has_changed(&stat) {
+            info.clear_cached_data();
+        }
+        info.set_stat(stat);
+    }
+
     pub fn contains_hash<P: Into<PathBuf>, S: Into<String>>(&self, path: P, hash_name: S) -> bool {
         self.files
             .get(&path.into())
-            .map_or(false, |fi| fi.hashes.contains_key(&hash_name.into()))
+            .is_some_and(|fi| fi.hashes.contains_key(&hash_name.into()))
     }
 
     pub fn hash_value<P: Into<PathBuf>, S: Into<String>>(
@@ -54,19 +149,110 @@ impl Files {
         path: P,
         hash_name: S,
         hash_value: V,
-    ) {
-        let entry = self.files.entry(path.into());
+    ) -> Result<()> {
+        let path = path.into();
+        match self.files.get_mut(&path) {
+            Some(info) => info.add_hash(hash_name.into(), hash_value.into()),
+            None => bail!("{} isn't in the catalog", path.display()),
+        }
+    }
 
-        match entry {
-            Entry::Vacant(_) => unimplemented!("put an error here"),
-            Entry::Occupied(mut occupied) => occupied
-                .get_mut()
-                .add_hash(hash_name.into(), hash_value.into()),
+    // Adds the files in 'other'. Where both have a file, the entry with the newer mtime wins,
+    // since the other one describes a file that has since changed. If they describe the same
+    // version of the file, anything missing here is filled in from 'other'. If they are
+    // different versions with the same mtime, like a file that was changed and had its mtime
+    // put back, there's no telling which is right, so the entry already here is kept.
+    pub fn merge(&mut self, other: Files) -> MergeConflicts {
+        let mut conflicts = MergeConflicts::default();
+        for (path, theirs) in other.files {
+            match self.files.entry(path) {
+                Entry::Vacant(vacant) => {
+                    vacant.insert(theirs);
+                }
+                Entry::Occupied(mut occupied) => {
+                    let (mine, their_stat) = (occupied.get().stat(), theirs.stat());
+                    if mine.same_size_and_mtime(&their_stat) {
+                        occupied.get_mut().absorb(theirs);
+                        continue;
+                    }
+                    conflicts.count += 1;
+                    match their_stat.cmp_mtime(&mine) {
+                        Ordering::Greater => *occupied.get_mut() = theirs,
+                        Ordering::Less => {}
+                        Ordering::Equal => conflicts.ties.push(occupied.key().clone()),
+                    }
+                }
+            }
         }
+        conflicts.ties.sort();
+        conflicts
+    }
+
+    pub fn diff(&self, other: &Files) -> FilesDiff {
+        let mut diff = FilesDiff::default();
+        for (path, mine) in &self.files {
+            match other.files.get(path) {
+                None => diff.only_in_self.push(path.clone()),
+                Some(theirs) if mine.hashes_differ(theirs) => {
+                    diff.different_hashes.push(path.clone())
+                }
+                Some(_) => {}
+            }
+        }
+        diff.only_in_other = other
+            .files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .cloned()
+            .collect();
+        diff.only_in_self.sort();
+        diff.only_in_other.sort();
+        diff.different_hashes.sort();
+        diff
+    }
+
+    // The files at or below 'prefix'. With 'strip', their paths are made relative to it, so
+    // that the catalog can be used from that directory. A leading "./" on either is ignored.
+    pub fn subtree(&self, prefix: &Path, strip: bool) -> Files {
+        let prefix = without_curdir(prefix);
+        let files = self
+            .files
+            .iter()
+            .filter_map(|(path, info)| {
+                let relative = without_curdir(path).strip_prefix(prefix).ok()?;
+                let mut info = info.clone();
+                if !strip {
+                    return Some((path.clone(), info));
+                }
+                if let Some(source) = info.converted_from() {
+                    if let Ok(source) = without_curdir(&source).strip_prefix(prefix) {
+                        info.converted_from = Some(encode_path(source));
+                    }
+                }
+                Some((relative.to_path_buf(), info))
+            })
+            .collect();
+        Files { files }
+    }
+
+    pub fn read_from_path<P: AsRef<Path>>(path: P) -> Result<Files> {
+        let s = std::fs::read_to_string(path)?;
+        let encoded: HashMap<String, FileInfo> = toml::from_str(&s)?;
+        let files = encoded
+            .into_iter()
+            .map(|(key, info)| (decode_path(&key), info))
+            .collect();
+        Ok(Files { files })
     }
 
     pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let s = toml::to_string(&self.files)?;
+        // Sorted, so that the file is easier to read and to diff.
+        let encoded: BTreeMap<String, &FileInfo> = self
+            .files
+            .iter()
+            .map(|(path, info)| (encode_path(path), info))
+            .collect();
+        let s = toml::to_string(&encoded)?;
         let mut file = File::create(path)?;
         file.write_all(s.as_bytes())?;
         file.flush()?;
@@ -74,25 +260,380 @@ impl Files {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+// 'path' without any "./" at the start.
+fn without_curdir(path: &Path) -> &Path {
+    let mut components = path.components();
+    while components.clone().next() == Some(Component::CurDir) {
+        components.next();
+    }
+    components.as_path()
+}
+
+/// The parts of a file's metadata that we use to notice when a file has changed or moved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+    // The fraction of a second of the mtime, so that two changes in the same second can be
+    // told apart. Catalogs from before it was recorded don't have it.
+    pub mtime_nsec: Option<u32>,
+    pub device: Option<u64>,
+    pub inode: Option<u64>,
+}
+
+impl FileStat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<FileStat> {
+        Ok(FileStat::from_metadata(&std::fs::metadata(path)?))
+    }
+
+    pub fn from_metadata(md: &Metadata) -> FileStat {
+        let since_epoch = md
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+        let (device, inode) = device_and_inode(md);
+        FileStat {
+            size: Some(md.len()),
+            mtime: since_epoch.map(|d| d.as_secs()),
+            mtime_nsec: since_epoch.map(|d| d.subsec_nanos()),
+            device,
+            inode,
+        }
+    }
+
+    pub fn same_inode(&self, other: &FileStat) -> bool {
+        self.inode.is_some() && self.device == other.device && self.inode == other.inode
+    }
+
+    // Which was modified later. The fraction of a second only counts if both know it.
+    pub fn cmp_mtime(&self, other: &FileStat) -> Ordering {
+        self.mtime
+            .cmp(&other.mtime)
+            .then_with(|| match self.mtime_nsec.zip(other.mtime_nsec) {
+                Some((mine, theirs)) => mine.cmp(&theirs),
+                None => Ordering::Equal,
+            })
+    }
+
+    // If either side doesn't know the fraction of a second, only the whole seconds are
+    // compared, so entries in old catalogs still match.
+    pub fn same_size_and_mtime(&self, other: &FileStat) -> bool {
+        self.size.is_some()
+            && self.size == other.size
+            && self.mtime == other.mtime
+            && self
+                .mtime_nsec
+                .zip(other.mtime_nsec)
+                .is_none_or(|(mine, theirs)| mine == theirs)
+    }
+}
+
+#[cfg(unix)]
+fn device_and_inode(md: &Metadata) -> (Option<u64>, Option<u64>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(md.dev()), Some(md.ino()))
+}
+
+#[cfg(not(unix))]
+fn device_and_inode(_md: &Metadata) -> (Option<u64>, Option<u64>) {
+    (None, None)
+}
+
+// Scalar values are listed first, since TOML requires them to come before any tables.
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct FileInfo {
-    hashes: HashMap<String, String>,
+    size: Option<u64>,
+    mtime: Option<u64>,
+    mtime_nsec: Option<u32>,
+    device: Option<u64>,
+    inode: Option<u64>,
     image_type: Option<ImageType>,
+    width: Option<u32>,
+    height: Option<u32>,
+    frames: Option<u32>,
+    duration_ms: Option<u64>,
+    // The file that 'convert' made this one from, encoded like the catalog keys.
+    converted_from: Option<String>,
+    hashes: HashMap<String, String>,
 }
 
 impl FileInfo {
     pub fn new() -> FileInfo {
         FileInfo {
-            hashes: HashMap::new(),
+            size: None,
+            mtime: None,
+            mtime_nsec: None,
+            device: None,
+            inode: None,
             image_type: Option::default(),
+            width: None,
+            height: None,
+            frames: None,
+            duration_ms: None,
+            converted_from: None,
+            hashes: HashMap::new(),
+        }
+    }
+
+    pub fn image_type(&self) -> Option<ImageType> {
+        self.image_type
+    }
+
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.width.zip(self.height)
+    }
+
+    pub fn animation(&self) -> Option<Animation> {
+        self.frames.map(|frames| Animation {
+            frames,
+            duration_ms: self.duration_ms.unwrap_or(0),
+        })
+    }
+
+    pub fn converted_from(&self) -> Option<PathBuf> {
+        self.converted_from.as_deref().map(decode_path)
+    }
+
+    pub fn contains_hash(&self, hash_name: &str) -> bool {
+        self.hashes.contains_key(hash_name)
+    }
+
+    pub fn hash_value(&self, hash_name: &str) -> Option<&String> {
+        self.hashes.get(hash_name)
+    }
+
+    pub fn stat(&self) -> FileStat {
+        FileStat {
+            size: self.size,
+            mtime: self.mtime,
+            mtime_nsec: self.mtime_nsec,
+            device: self.device,
+            inode: self.inode,
+        }
+    }
+
+    pub fn set_stat(&mut self, stat: FileStat) {
+        self.size = stat.size;
+        self.mtime = stat.mtime;
+        self.mtime_nsec = stat.mtime_nsec;
+        self.device = stat.device;
+        self.inode = stat.inode;
+    }
+
+    fn hashes_differ(&self, other: &FileInfo) -> bool {
+        self.hashes
+            .iter()
+            .any(|(name, value)| other.hashes.get(name).is_some_and(|v| v != value))
+    }
+
+    fn has_changed(&self, stat: &FileStat) -> bool {
+        self.size.is_some() && !self.stat().same_size_and_mtime(stat)
+    }
+
+    fn clear_cached_data(&mut self) {
+        self.hashes.clear();
+        self.image_type = None;
+        self.width = None;
+        self.height = None;
+        self.frames = None;
+        self.duration_ms = None;
+        self.converted_from = None;
+    }
+
+    // Fills in anything we don't know from 'other'.
+    fn absorb(&mut self, other: FileInfo) {
+        if self.size.is_none() {
+            self.set_stat(other.stat());
+        }
+        self.image_type = self.image_type.or(other.image_type);
+        if self.dimensions().is_none() {
+            self.width = other.width;
+            self.height = other.height;
+        }
+        if self.frames.is_none() {
+            self.frames = other.frames;
+            self.duration_ms = other.duration_ms;
+        }
+        if self.converted_from.is_none() {
+            self.converted_from = other.converted_from;
+        }
+        for (name, value) in other.hashes {
+            self.hashes.entry(name).or_insert(value);
         }
     }
 
-    pub fn add_hash<S: Into<String>>(&mut self, hash_name: S, hash_value: S) {
+    pub fn add_hash<S: Into<String>>(&mut self, hash_name: S, hash_value: S) -> Result<()> {
         let name = hash_name.into();
         if self.hashes.contains_key(&name) {
-            panic!("fileinfo already contains hash for {}", name);
+            bail!("already have a {} hash", name);
         }
         self.hashes.insert(name, hash_value.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(size: u64, mtime: u64, mtime_nsec: Option<u32>) -> FileStat {
+        FileStat {
+            size: Some(size),
+            mtime: Some(mtime),
+            mtime_nsec,
+            ..FileStat::default()
+        }
+    }
+
+    #[test]
+    fn changes_within_a_second_are_noticed() {
+        assert!(stat(10, 100, Some(5)).same_size_and_mtime(&stat(10, 100, Some(5))));
+        assert!(!stat(10, 100, Some(5)).same_size_and_mtime(&stat(10, 100, Some(6))));
+        assert!(!stat(10, 100, Some(5)).same_size_and_mtime(&stat(11, 100, Some(5))));
+    }
+
+    #[test]
+    fn old_entries_without_nanoseconds_still_match() {
+        assert!(stat(10, 100, None).same_size_and_mtime(&stat(10, 100, Some(5))));
+        assert!(!stat(10, 100, None).same_size_and_mtime(&stat(10, 101, Some(5))));
+    }
+
+    #[test]
+    fn changed_file_drops_cached_data() {
+        let mut files = Files::default();
+        files.set_stat("a", stat(10, 100, Some(5)));
+        files.add_hash("a", "SHA256", "abc").unwrap();
+        files.set_stat("a", stat(10, 100, Some(5)));
+        assert!(files.contains_hash("a", "SHA256"));
+        files.set_stat("a", stat(10, 100, Some(6)));
+        assert!(!files.contains_hash("a", "SHA256"));
+    }
+
+    #[test]
+    fn hash_needs_a_cataloged_file_without_one() {
+        let mut files = Files::default();
+        assert!(files.add_hash("a", "SHA256", "abc").is_err());
+        files.set_stat("a", stat(10, 100, None));
+        files.add_hash("a", "SHA256", "abc").unwrap();
+        assert!(files.add_hash("a", "SHA256", "def").is_err());
+        assert_eq!(
+            files.hash_value("a", "SHA256").map(String::as_str),
+            Some("abc")
+        );
+    }
+
+    fn files(entries: &[(&str, FileStat, &str)]) -> Files {
+        let mut files = Files::default();
+        for (path, stat, hash) in entries {
+            files.set_stat(*path, *stat);
+            files.add_hash(*path, "SHA256", *hash).unwrap();
+        }
+        files
+    }
+
+    fn hash(files: &Files, path: &str) -> Option<String> {
+        files.hash_value(path, "SHA256").cloned()
+    }
+
+    #[test]
+    fn merge_keeps_the_newer_version() {
+        let mut merged = files(&[
+            ("a", stat(1, 100, None), "old"),
+            ("b", stat(1, 200, None), "new"),
+        ]);
+        let conflicts = merged.merge(files(&[
+            ("a", stat(2, 101, None), "new"),
+            ("b", stat(2, 199, None), "old"),
+            ("c", stat(1, 100, None), "only"),
+        ]));
+        assert_eq!(conflicts.count, 2);
+        assert!(conflicts.ties.is_empty());
+        assert_eq!(hash(&merged, "a").as_deref(), Some("new"));
+        assert_eq!(hash(&merged, "b").as_deref(), Some("new"));
+        assert_eq!(hash(&merged, "c").as_deref(), Some("only"));
+    }
+
+    #[test]
+    fn merge_keeps_the_first_of_a_tie() {
+        let mut merged = files(&[("a", stat(1, 100, Some(5)), "first")]);
+        let conflicts = merged.merge(files(&[("a", stat(2, 100, Some(5)), "second")]));
+        assert_eq!(conflicts.count, 1);
+        assert_eq!(conflicts.ties, [PathBuf::from("a")]);
+        assert_eq!(hash(&merged, "a").as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn merge_fills_in_the_same_version() {
+        let mut merged = files(&[("a", stat(1, 100, None), "abc")]);
+        let mut theirs = files(&[("a", stat(1, 100, None), "abc")]);
+        theirs.add_hash("a", "BLAKE3", "def").unwrap();
+        let conflicts = merged.merge(theirs);
+        assert_eq!(conflicts.count, 0);
+        assert!(merged.contains_hash("a", "BLAKE3"));
+    }
+
+    #[test]
+    fn diff_sorts_files_into_categories() {
+        let a = files(&[
+            ("both", stat(1, 100, None), "same"),
+            ("changed", stat(1, 100, None), "one"),
+            ("left", stat(1, 100, None), "x"),
+        ]);
+        let mut b = files(&[
+            ("both", stat(1, 100, None), "same"),
+            ("changed", stat(1, 100, None), "two"),
+            ("right", stat(1, 100, None), "x"),
+        ]);
+        // Only hashes that both have are compared.
+        b.add_hash("both", "BLAKE3", "extra").unwrap();
+        let diff = a.diff(&b);
+        assert_eq!(diff.only_in_self, [PathBuf::from("left")]);
+        assert_eq!(diff.only_in_other, [PathBuf::from("right")]);
+        assert_eq!(diff.different_hashes, [PathBuf::from("changed")]);
+    }
+
+    #[test]
+    fn strip_prefix_rewrites_paths_and_sources() {
+        let mut all = files(&[
+            ("photos/a.png", stat(1, 100, None), "a"),
+            ("photos/a.jpg", stat(1, 100, None), "b"),
+            ("photos/b.png", stat(1, 100, None), "c"),
+            ("other/c.jpg", stat(1, 100, None), "d"),
+        ]);
+        all.set_converted_from("photos/a.png", Path::new("photos/a.jpg"));
+        all.set_converted_from("photos/b.png", Path::new("other/c.jpg"));
+
+        let subtree = all.subtree(Path::new("./photos"), true);
+        let mut paths: Vec<&PathBuf> = subtree.file_iter().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["a.jpg", "a.png", "b.png"]
+                .map(PathBuf::from)
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            subtree.converted_from("a.png"),
+            Some(PathBuf::from("a.jpg"))
+        );
+        // Sources outside of the prefix are left alone.
+        assert_eq!(
+            subtree.converted_from("b.png"),
+            Some(PathBuf::from("other/c.jpg"))
+        );
+
+        let unstripped = all.subtree(Path::new("photos"), false);
+        assert!(unstripped.contains_file("photos/a.png"));
+        assert!(!unstripped.contains_file("other/c.jpg"));
+    }
+
+    #[test]
+    fn leading_curdir_in_keys_is_ignored() {
+        let all = files(&[("./photos/a.jpg", stat(1, 100, None), "a")]);
+        assert!(all
+            .subtree(Path::new("photos"), true)
+            .contains_file("a.jpg"));
+        assert_eq!(all.subtree(Path::new("."), false).file_iter().count(), 1);
     }
 }
@@ -4,8 +4,8 @@ use std::sync::Arc;
 use anyhow::Result;
 use parking_lot::RwLock;
 
-use crate::imt::filer::fileinfo::Files;
-use crate::imt::image_type::ImageType;
+use crate::imt::filer::fileinfo::{FileInfo, FileStat, Files};
+use crate::imt::image_type::{Animation, ImageType};
 
 #[derive(Clone)]
 pub struct Filer {
@@ -18,7 +18,17 @@ impl Filer {
         Ok(Filer { files })
     }
 
-    pub fn set_image_type<P: Into<PathBuf>>(&mut self, path: P, image_type: ImageType) {
+    pub fn read_from_path<P: AsRef<Path>>(path: P) -> Result<Filer> {
+        let files = Arc::new(RwLock::new(Files::read_from_path(path)?));
+        Ok(Filer { files })
+    }
+
+    // Replaces everything in the catalog with `files`.
+    pub fn replace(&self, files: Files) {
+        *self.files.write() = files;
+    }
+
+    pub fn set_image_type<P: Into<PathBuf>>(&self, path: P, image_type: ImageType) {
         self.files.write().set_image_type(path, image_type)
     }
 
@@ -26,10 +36,54 @@ impl Filer {
         self.files.read().image_type(path)
     }
 
+    pub fn dimensions<P: Into<PathBuf>>(&self, path: P) -> Option<(u32, u32)> {
+        self.files.read().dimensions(path)
+    }
+
+    pub fn set_dimensions<P: Into<PathBuf>>(&self, path: P, width: u32, height: u32) {
+        self.files.write().set_dimensions(path, width, height)
+    }
+
+    pub fn animation<P: Into<PathBuf>>(&self, path: P) -> Option<Animation> {
+        self.files.read().animation(path)
+    }
+
+    pub fn set_animation<P: Into<PathBuf>>(&self, path: P, animation: Animation) {
+        self.files.write().set_animation(path, animation)
+    }
+
+    pub fn converted_from<P: Into<PathBuf>>(&self, path: P) -> Option<PathBuf> {
+        self.files.read().converted_from(path)
+    }
+
+    pub fn set_converted_from<P: Into<PathBuf>>(&self, path: P, source: &Path) {
+        self.files.write().set_converted_from(path, source)
+    }
+
     pub fn add_file<P: Into<PathBuf>>(&self, path: P) {
         self.files.write().add_file(path)
     }
 
+    pub fn remove_file<P: Into<PathBuf>>(&self, path: P) -> bool {
+        self.files.write().remove_file(path)
+    }
+
+    pub fn contains_file<P: Into<PathBuf>>(&self, path: P) -> bool {
+        self.files.read().contains_file(path)
+    }
+
+    pub fn move_file<P: Into<PathBuf>, Q: Into<PathBuf>>(&self, from: P, to: Q) -> bool {
+        self.files.write().move_file(from, to)
+    }
+
+    pub fn stat<P: Into<PathBuf>>(&self, path: P) -> Option<FileStat> {
+        self.files.read().stat(path)
+    }
+
+    pub fn set_stat<P: Into<PathBuf>>(&self, path: P, stat: FileStat) {
+        self.files.write().set_stat(path, stat)
+    }
+
     pub fn with_files<F>(&self, mut f: F)
     where
         F: FnMut(&PathBuf),
@@ -39,12 +93,21 @@ impl Filer {
         })
     }
 
+    pub fn with_file_infos<F>(&self, mut f: F)
+    where
+        F: FnMut(&PathBuf, &FileInfo),
+    {
+        self.files.read().info_iter().for_each(|(path, info)| {
+            f(path, info);
+        })
+    }
+
     pub fn add_hash<P: Into<PathBuf>, S: Into<String>, V: Into<String>>(
         &self,
         path: P,
         hash_name: S,
         hash_value: V,
-    ) {
+    ) -> Result<()> {
         self.files.write().add_hash(path, hash_name, hash_value)
     }
 
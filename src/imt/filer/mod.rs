@@ -1,4 +1,10 @@
 mod fileinfo;
 mod filerimpl;
+mod pathkey;
 
+pub use fileinfo::{FileInfo, FileStat, Files};
 pub use filerimpl::Filer;
+pub use pathkey::{decode_path, encode_path};
+
+/// Where the catalog is read from at startup and written to afterwards.
+pub const CATALOG_PATH: &str = "files.toml";
@@ -0,0 +1,120 @@
+// TOML keys (and JSON strings) have to be UTF-8, but paths don't. Paths that are valid UTF-8
+// are stored as is. Anything else is stored with a leading NUL, which can't appear in a real
+// path, followed by the valid parts of the name with '%' escaped as "%25", and the invalid
+// bytes as "%XX". This keeps old catalogs readable and round trips every path losslessly on
+// unix. A name that really does start with NUL is escaped too, so it can't be mistaken for one
+// of these.
+//
+// Elsewhere, the raw bytes of a path aren't something that we can safely rebuild an OsString
+// from, so the invalid parts of a name come back as U+FFFD, and the path won't match the file.
+
+use std::path::{Path, PathBuf};
+
+const ESCAPED_PREFIX: char = '\0';
+
+pub fn encode_path(path: &Path) -> String {
+    if let Some(s) = path.to_str() {
+        if !s.starts_with(ESCAPED_PREFIX) {
+            return s.to_string();
+        }
+    }
+
+    let mut encoded = String::new();
+    encoded.push(ESCAPED_PREFIX);
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '%' {
+                encoded.push_str("%25");
+            } else {
+                encoded.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+pub fn decode_path(s: &str) -> PathBuf {
+    match s.strip_prefix(ESCAPED_PREFIX) {
+        None => PathBuf::from(s),
+        Some(rest) => PathBuf::from(os_string_from_bytes(unescape(rest))),
+    }
+}
+
+fn unescape(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' {
+            s.get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(b) => {
+                result.push(b);
+                i += 3;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> std::ffi::OsString {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(bytes)
+}
+
+// Lossy, see above.
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> std::ffi::OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("photos/cat.jpg" ; "plain")]
+    #[test_case("100%/cat.jpg" ; "percent")]
+    #[test_case("caf\u{e9}/\u{732b}.jpg" ; "non ascii")]
+    fn utf8_is_stored_as_is(s: &str) {
+        assert_eq!(encode_path(Path::new(s)), s);
+        assert_eq!(decode_path(s), PathBuf::from(s));
+    }
+
+    #[test_case("\0" ; "only nul")]
+    #[test_case("\0abc" ; "leading nul")]
+    #[test_case("\0%41" ; "leading nul and escape")]
+    fn leading_nul_round_trips(s: &str) {
+        let encoded = encode_path(Path::new(s));
+        assert_ne!(encoded, s);
+        assert_eq!(decode_path(&encoded), PathBuf::from(s));
+    }
+
+    #[cfg(unix)]
+    #[test_case(b"bad\xff.jpg" ; "invalid byte")]
+    #[test_case(b"\xff%25%.jpg" ; "invalid byte and percents")]
+    #[test_case(b"\xe2\x82/x" ; "truncated sequence")]
+    #[test_case(b"\0\xff" ; "leading nul and invalid byte")]
+    fn invalid_utf8_round_trips(bytes: &[u8]) {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(bytes));
+        let encoded = encode_path(path);
+        assert!(encoded.starts_with(ESCAPED_PREFIX));
+        assert_eq!(decode_path(&encoded), path);
+    }
+}
@@ -1,35 +1,147 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use sha2::{Digest, Sha256};
+use log::debug;
 use structopt::StructOpt;
 use walkdir::DirEntry;
 
+use crate::imt::archive::{
+    cataloged_archives, for_each_member, is_archive, member_path, remove_members,
+};
 use crate::imt::crawler::{CrawlHelper, Crawler};
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::CrawlOptions;
+use crate::imt::decisions::apply_decisions;
 use crate::imt::direntryutil::is_hidden;
-use crate::imt::filer::Filer;
-use std::collections::HashMap;
+use crate::imt::dupreport::write_html_report;
+use crate::imt::filer::{FileStat, Filer};
+use crate::imt::gc::prune_missing;
+use crate::imt::hashers::{hash_algorithm, hash_reader, HashAlgorithm};
+use crate::imt::image_type::{ImageType, Sniffed};
+use crate::imt::moved::MoveDetector;
+use crate::imt::progress::Progress;
 
-const HASH_NAME: &str = "SHA256";
+pub const HASH_NAME: &str = "SHA256";
+
+// How much of an archive member we hold in memory to find its type. Anything bigger is
+// streamed through the hash.
+const MEMBER_PREFIX_LEN: u64 = 1 << 20;
 
 #[derive(StructOpt, Debug)]
 pub struct FindDups {
+    /// The hash to compare files by: SHA256, BLAKE3, XXH3, SHA1, PIXELS (the decoded image,
+    /// ignoring metadata), or JPEG-SCAN (only the compressed data of JPEGs).
+    #[structopt(long, default_value = HASH_NAME, parse(try_from_str = hash_algorithm))]
+    hash: &'static HashAlgorithm,
+
+    /// Write a page for reviewing the duplicates, with thumbnails, to this HTML file.
+    #[structopt(long, parse(from_os_str))]
+    html: Option<PathBuf>,
+
+    /// Delete the files in a decisions file exported from the HTML page, instead of searching.
+    /// Files are only deleted if an identical copy is being kept.
+    #[structopt(long, parse(from_os_str), conflicts_with = "html")]
+    apply_decisions: Option<PathBuf>,
+
+    /// With --apply-decisions, print what would be deleted. No files are changed.
+    #[structopt(short = "n", long, requires = "apply-decisions")]
+    dry_run: bool,
+
+    /// With --apply-decisions, trust that files with the same hash are identical, instead of
+    /// comparing them byte for byte before deleting. Copies found with PIXELS can only be
+    /// deleted this way. Hashes that aren't cryptographic, like XXH3, SHA1, and JPEG-SCAN,
+    /// can't be trusted.
+    #[structopt(long, requires = "apply-decisions")]
+    trust_hashes: bool,
+
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
+
     /// The directories to search
-    // TODO: figure out what this does if the filename is not UTF-8.
-    #[structopt(min_values(1))]
-    directories: Vec<String>,
+    #[structopt(min_values(1), required_unless = "apply-decisions", parse(from_os_str))]
+    directories: Vec<PathBuf>,
 }
 
-struct FindDupsHelper {
+struct FindDupsHelper<'a> {
     filer: Filer,
+    hash: &'static HashAlgorithm,
+    moves: &'a MoveDetector,
+    progress: &'a Progress,
+    errors: &'a CrawlErrors,
+
+    // Only set if we are looking inside of archives.
+    cataloged_archives: Option<&'a HashSet<PathBuf>>,
 }
 
 #[derive(Debug, Default)]
-struct FindDupsInfo;
+struct FindDupsInfo {
+    stat: FileStat,
+}
+
+impl<'a> FindDupsHelper<'a> {
+    // Catalogs from before we counted frames have images without them.
+    fn needs_image_type(&self, path: &Path) -> bool {
+        match self.filer.image_type(path) {
+            None => true,
+            Some(ImageType::UNKNOWN) => false,
+            Some(_) => self.filer.animation(path).is_none(),
+        }
+    }
+
+    // Files that aren't what the hash is taken of, like non-images for PIXELS, never get one.
+    fn needs_hash(&self, path: &Path) -> bool {
+        !self.filer.contains_hash(path, self.hash.name)
+            && self
+                .filer
+                .image_type(path)
+                .is_none_or(|image_type| self.hash.applies_to(image_type))
+    }
+
+    fn should_read_archive(&self, path: &Path) -> bool {
+        self.cataloged_archives.is_some() && is_archive(path)
+    }
 
-impl CrawlHelper for FindDupsHelper {
+    // Catalog the members of the archive at `path`, replacing whatever we knew about them.
+    fn catalog_members(&self, path: &Path) -> Result<()> {
+        remove_members(&self.filer, path);
+        for_each_member(path, |member| {
+            let member_path = member_path(path, &member.path);
+            if self.filer.stat(&member_path).is_some() {
+                // The same name twice in one archive. We only keep the first.
+                return Ok(());
+            }
+            let stat = FileStat {
+                size: Some(member.size),
+                mtime: member.mtime,
+                mtime_nsec: None,
+                device: None,
+                inode: None,
+            };
+            self.filer.set_stat(&member_path, stat);
+
+            let mut prefix = Vec::default();
+            (&mut *member.data)
+                .take(MEMBER_PREFIX_LEN)
+                .read_to_end(&mut prefix)?;
+            if (prefix.len() as u64) < MEMBER_PREFIX_LEN {
+                record_image_type(&self.filer, &member_path, &mut Cursor::new(&prefix));
+            } else {
+                record_partial_image_type(&self.filer, &member_path, &prefix);
+            }
+            if self.needs_hash(&member_path) {
+                let mut data = Cursor::new(&prefix).chain(member.data);
+                let hash = hash_reader(&mut data, self.hash, self.progress)?;
+                self.filer.add_hash(&member_path, self.hash.name, hash)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<'a> CrawlHelper for FindDupsHelper<'a> {
     type InfoType = FindDupsInfo;
 
     fn should_descend(&self, e: &DirEntry) -> Result<bool> {
@@ -37,73 +149,266 @@ impl CrawlHelper for FindDupsHelper {
         Ok(!is_hidden(e))
     }
 
-    fn should_process_file(&self, e: &DirEntry, _it: &mut Self::InfoType) -> Result<bool> {
+    fn should_process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<bool> {
         // TODO: add is_image()
-        Ok(!self.filer.contains_hash(e.path(), HASH_NAME))
+        // Recording the stat drops the cached hashes if the file has changed, and picks them
+        // up from the old entry if the file was moved here.
+        it.stat = FileStat::from_path(e.path())?;
+        self.moves.add_file(&self.filer, e.path(), it.stat);
+        let unread_archive = self.should_read_archive(e.path())
+            && !self
+                .cataloged_archives
+                .is_some_and(|archives| archives.contains(e.path()));
+        Ok(self.needs_hash(e.path()) || self.needs_image_type(e.path()) || unread_archive)
     }
 
-    fn process_file(&self, e: &DirEntry, _it: &mut Self::InfoType) -> Result<()> {
-        // TODO: put mod time in filer.
-        // TODO: if mod time changes, then delete cached data
-        // TODO: don't read file if cached
-        self.filer.add_file(e.path());
+    fn process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<()> {
+        if self.needs_image_type(e.path()) {
+            record_image_type(&self.filer, e.path(), &mut File::open(e.path())?);
+        }
+        if self.needs_hash(e.path()) {
+            let hash = hash_reader(&mut File::open(e.path())?, self.hash, self.progress)?;
+            self.filer
+                .add_hash(e.path(), self.hash.name, hash.as_str())?;
+            self.moves
+                .add_hash(&self.filer, e.path(), &it.stat, self.hash.name, &hash);
+        }
+        if self.should_read_archive(e.path()) {
+            self.catalog_members(e.path())?;
+        }
+        Ok(())
+    }
 
-        let mut hasher = Sha256::new();
-        let mut file = File::open(e.path())?;
-        let mut buffer = [0; 10000];
+    fn progress(&self) -> Option<&Progress> {
+        Some(self.progress)
+    }
 
-        loop {
-            let n = file.read(&mut buffer)?;
-            if n == 0 {
-                break;
-            }
-            hasher.input(&buffer[0..n]);
-        }
-        let result_str = hex::encode(hasher.result());
+    fn errors(&self) -> Option<&CrawlErrors> {
+        Some(self.errors)
+    }
+}
 
-        self.filer.add_hash(e.path(), HASH_NAME, result_str);
-        Ok(())
+pub fn record_image_type<R: Read + Seek>(filer: &Filer, path: &Path, reader: &mut R) {
+    // Anything that we can't read the header of is not an image that we understand.
+    let image_type = ImageType::type_of_file(reader).unwrap_or_else(|err| {
+        debug!("Can't determine image type of {}: {}", path.display(), err);
+        ImageType::UNKNOWN
+    });
+    filer.set_image_type(path, image_type);
+    match image_type.dimensions(reader) {
+        Ok(Some((width, height))) => filer.set_dimensions(path, width, height),
+        Ok(None) => {}
+        Err(err) => debug!("Can't read dimensions of {}: {}", path.display(), err),
+    }
+    match image_type.animation(reader) {
+        Ok(Some(animation)) => filer.set_animation(path, animation),
+        Ok(None) => {}
+        Err(err) => debug!("Can't count frames of {}: {}", path.display(), err),
+    }
+}
+
+// Like record_image_type, for the start of a file that is too big to hold in memory. The
+// type and dimensions are in the headers, but the frames can't be counted.
+fn record_partial_image_type(filer: &Filer, path: &Path, prefix: &[u8]) {
+    let image_type = match ImageType::sniff(prefix) {
+        Sniffed::Type(image_type) => image_type,
+        Sniffed::NeedMoreBytes(_) => ImageType::UNKNOWN,
+    };
+    filer.set_image_type(path, image_type);
+    match image_type.dimensions_of_bytes(prefix) {
+        Ok(Some((width, height))) => filer.set_dimensions(path, width, height),
+        Ok(None) => {}
+        Err(err) => debug!("Can't read dimensions of {}: {}", path.display(), err),
     }
 }
 
-fn look_for_dups(filer: &Filer) -> Result<Vec<Vec<PathBuf>>> {
+/// Groups of cataloged files with the same `hash_name` hash, sorted by path.
+pub fn duplicate_groups(filer: &Filer, hash_name: &str) -> Vec<Vec<PathBuf>> {
     let mut hash_to_paths: HashMap<String, Vec<PathBuf>> = HashMap::default();
     filer.with_files(|p| {
         // TODO: some sort of progress meter.
-        if let Some(sha) = filer.hash_value(p, HASH_NAME) {
-            hash_to_paths.entry(sha).or_default().push(p.clone());
-        }
-    });
-    let mut dups: Vec<Vec<PathBuf>> = Vec::default();
-    hash_to_paths.values().for_each(|v| {
-        if v.len() > 1 {
-            dups.push(v.clone());
-            eprintln!("{:?}", v);
+        if let Some(hash) = filer.hash_value(p, hash_name) {
+            hash_to_paths.entry(hash).or_default().push(p.clone());
         }
     });
+    let mut dups: Vec<Vec<PathBuf>> = hash_to_paths
+        .into_values()
+        .filter(|v| v.len() > 1)
+        .map(|mut v| {
+            v.sort();
+            v
+        })
+        .collect();
+    dups.sort();
+    dups
+}
+
+fn look_for_dups(filer: &Filer, hash_name: &str) -> Result<Vec<Vec<PathBuf>>> {
+    let dups = duplicate_groups(filer, hash_name);
+    dups.iter().for_each(|group| eprintln!("{:?}", group));
     Ok(dups)
 }
 
-fn report_dups(dups: &[Vec<PathBuf>]) -> Result<()> {
-    eprintln!("SHOO: {:?}", dups);
-    Ok(())
+/// Crawl `path`, recording the type and `hash` of every file in the catalog.
+pub fn catalog_files(
+    path: &Path,
+    options: &CrawlOptions,
+    hash: &'static HashAlgorithm,
+    filer: &Filer,
+    moves: &MoveDetector,
+    progress: &Progress,
+    errors: &CrawlErrors,
+) -> Result<()> {
+    let archives = if options.archives {
+        Some(cataloged_archives(filer))
+    } else {
+        None
+    };
+    let crawler = Crawler::new(
+        path,
+        options,
+        FindDupsHelper {
+            filer: filer.clone(),
+            hash,
+            moves,
+            progress,
+            errors,
+            cataloged_archives: archives.as_ref(),
+        },
+    );
+    crawler.crawl()
 }
 
 pub fn process_finddups(fd: &FindDups, filer: &Filer) -> Result<()> {
+    if let Some(decisions) = &fd.apply_decisions {
+        return apply_decisions(filer, decisions, fd.hash, fd.dry_run, fd.trust_hashes);
+    }
+
+    let moves = MoveDetector::new(filer);
+    let progress = Progress::new();
+    let errors = CrawlErrors::new(fd.crawl_options.fail_fast);
     for dir in &fd.directories {
-        let crawler = Crawler::new(
+        catalog_files(
             dir,
-            FindDupsHelper {
-                filer: filer.clone(),
-            },
+            &fd.crawl_options,
+            fd.hash,
+            filer,
+            &moves,
+            &progress,
+            &errors,
+        )?;
+        if errors.should_stop() {
+            break;
+        }
+    }
+    progress.finish();
+
+    if !errors.should_stop() {
+        let removed = prune_missing(filer, &fd.directories, false);
+        eprintln!("Removed {} missing files from the catalog.", removed);
+
+        let dups = look_for_dups(filer, fd.hash.name)?;
+        if let Some(html) = &fd.html {
+            write_html_report(filer, &dups, fd.hash.name, html)?;
+            eprintln!("Wrote {} groups to {}.", dups.len(), html.display());
+        }
+    }
+
+    errors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imt::hashers::default_hash_algorithm;
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_survive_crawl_and_catalog() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Not the default ".tmp" name, since hidden directories aren't crawled.
+        let dir = tempfile::Builder::new().prefix("imt").tempdir().unwrap();
+        let path = dir.path().join(OsStr::from_bytes(b"caf\xe9 100%.jpg"));
+        std::fs::write(&path, "not really a jpeg").unwrap();
+
+        let filer = Filer::new().unwrap();
+        let errors = CrawlErrors::new(true);
+        catalog_files(
+            dir.path(),
+            &CrawlOptions::default(),
+            default_hash_algorithm(),
+            &filer,
+            &MoveDetector::new(&filer),
+            &Progress::new(),
+            &errors,
+        )
+        .unwrap();
+        assert!(!errors.should_stop());
+        assert!(filer.contains_hash(&path, HASH_NAME));
+
+        let catalog = dir.path().join("files.toml");
+        filer.write_to_path(&catalog).unwrap();
+        let reread = Filer::read_from_path(&catalog).unwrap();
+        assert_eq!(
+            reread.hash_value(&path, HASH_NAME),
+            filer.hash_value(&path, HASH_NAME)
         );
-        crawler.crawl()?;
+        assert_eq!(reread.stat(&path), filer.stat(&path));
     }
 
-    let dups = look_for_dups(filer)?;
-    report_dups(&dups)?;
+    #[test]
+    fn archive_members_are_streamed() {
+        let dir = tempfile::Builder::new().prefix("imt").tempdir().unwrap();
+        let path = dir.path().join("backup.tar");
+        let jpeg = std::fs::read("test_images/test.jpg").unwrap();
+        // Too big to sniff whole, so only the start of it is kept in memory.
+        let big = [jpeg.as_slice(), &vec![0; MEMBER_PREFIX_LEN as usize]].concat();
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        for (name, data) in [("small.jpg", &jpeg), ("big.jpg", &big)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, data.as_slice())
+                .unwrap();
+        }
+        builder.finish().unwrap();
 
-    // TODO: have an arg for this destination file.
-    filer.write_to_path("imtfiles.toml")?;
-    Ok(())
+        let filer = Filer::new().unwrap();
+        let options = CrawlOptions {
+            archives: true,
+            ..CrawlOptions::default()
+        };
+        catalog_files(
+            dir.path(),
+            &options,
+            default_hash_algorithm(),
+            &filer,
+            &MoveDetector::new(&filer),
+            &Progress::new(),
+            &CrawlErrors::new(true),
+        )
+        .unwrap();
+
+        let hash_of = |data: &[u8]| {
+            hash_reader(
+                &mut Cursor::new(data),
+                default_hash_algorithm(),
+                &Progress::new(),
+            )
+            .unwrap()
+        };
+        for (name, data) in [("small.jpg", &jpeg), ("big.jpg", &big)] {
+            let member = member_path(&path, Path::new(name));
+            assert_eq!(filer.stat(&member).unwrap().size, Some(data.len() as u64));
+            assert_eq!(filer.hash_value(&member, HASH_NAME), Some(hash_of(data)));
+            assert_eq!(filer.image_type(&member), Some(ImageType::JPEG));
+            assert_eq!(filer.dimensions(&member), Some((585, 1080)));
+        }
+        // Only a whole file can be checked for frames.
+        let big = member_path(&path, Path::new("big.jpg"));
+        assert!(filer.animation(&big).is_none());
+    }
 }
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use structopt::StructOpt;
+
+use crate::imt::image_type::FORMATS;
+
+/// List the image formats that we can detect.
+#[derive(StructOpt, Debug)]
+pub struct Formats {
+    /// Print the list as JSON.
+    #[structopt(long)]
+    json: bool,
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+pub fn process_formats(f: &Formats) -> Result<()> {
+    if f.json {
+        println!("{}", serde_json::to_string_pretty(FORMATS)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<6} {:<12} {:<24} {:<9} {:<12} {:<8}",
+        "NAME", "MIME", "EXTENSIONS", "ANIMATION", "TRANSPARENCY", "LOSSLESS"
+    );
+    for format in FORMATS {
+        println!(
+            "{:<6} {:<12} {:<24} {:<9} {:<12} {:<8}",
+            format.name,
+            format.mime_type,
+            format.extensions.join(","),
+            yes_no(format.animation),
+            yes_no(format.transparency),
+            yes_no(format.lossless)
+        );
+    }
+    Ok(())
+}
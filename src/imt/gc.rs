@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::info;
+use structopt::StructOpt;
+
+use crate::imt::archive::archive_of;
+use crate::imt::direntryutil::is_missing;
+use crate::imt::filer::Filer;
+
+/// Remove catalog entries for files that no longer exist.
+#[derive(StructOpt, Debug)]
+pub struct Gc {
+    /// Print missing files only. The catalog is left untouched.
+    #[structopt(short = "n", long)]
+    dry_run: bool,
+
+    /// Only check entries under these directories. Defaults to the whole catalog.
+    #[structopt(parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+/// Stat every catalog path under `roots` (or every path, if `roots` is empty) and drop the
+/// entries for files that are gone. Returns the number of missing entries.
+pub fn prune_missing<P: AsRef<Path>>(filer: &Filer, roots: &[P], dry_run: bool) -> usize {
+    let mut missing: Vec<PathBuf> = Vec::default();
+    filer.with_files(|p| {
+        let archive = archive_of(p);
+        let in_scope = roots.is_empty()
+            || roots
+                .iter()
+                .any(|r| p.starts_with(r) || archive.as_ref().is_some_and(|a| a.starts_with(r)));
+        if in_scope && is_missing(p) {
+            missing.push(p.clone());
+        }
+    });
+
+    for path in &missing {
+        if dry_run {
+            eprintln!("Missing: {}", path.display());
+        } else {
+            info!("Removing {} from catalog.", path.display());
+            filer.remove_file(path);
+        }
+    }
+
+    missing.len()
+}
+
+pub fn process_gc(gc: &Gc, filer: &Filer) -> Result<()> {
+    let count = prune_missing(filer, &gc.directories, gc.dry_run);
+    if gc.dry_run {
+        eprintln!("{} catalog entries refer to missing files.", count);
+    } else {
+        eprintln!("Removed {} catalog entries.", count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::imt::archive::member_path;
+
+    // A catalog with "kept" and "sub/kept", which exist, and "gone", "sub/gone", and a member
+    // of "old.zip", which don't.
+    fn catalog() -> (TempDir, Filer) {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let filer = Filer::new().unwrap();
+        for name in ["kept", "sub/kept"] {
+            fs::write(dir.path().join(name), "here").unwrap();
+        }
+        for name in ["kept", "sub/kept", "gone", "sub/gone"] {
+            filer.add_file(dir.path().join(name));
+        }
+        filer.add_file(member_path(
+            &dir.path().join("old.zip"),
+            Path::new("inner.jpg"),
+        ));
+        (dir, filer)
+    }
+
+    fn count(filer: &Filer) -> usize {
+        let mut count = 0;
+        filer.with_files(|_| count += 1);
+        count
+    }
+
+    #[test]
+    fn missing_files_are_removed() {
+        let (dir, filer) = catalog();
+        assert_eq!(prune_missing::<&Path>(&filer, &[], false), 3);
+        assert_eq!(count(&filer), 2);
+        assert!(filer.contains_file(dir.path().join("sub/kept")));
+    }
+
+    #[test]
+    fn only_files_under_the_roots_are_checked() {
+        let (dir, filer) = catalog();
+        assert_eq!(prune_missing(&filer, &[dir.path().join("sub")], false), 1);
+        assert!(!filer.contains_file(dir.path().join("sub/gone")));
+        assert!(filer.contains_file(dir.path().join("gone")));
+    }
+
+    #[test]
+    fn dry_run_removes_nothing() {
+        let (_dir, filer) = catalog();
+        assert_eq!(prune_missing::<&Path>(&filer, &[], true), 3);
+        assert_eq!(count(&filer), 5);
+    }
+}
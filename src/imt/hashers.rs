@@ -0,0 +1,362 @@
+use std::io::{Cursor, Read};
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Frame};
+use sha2::{Digest, Sha256};
+
+use crate::imt::image_metadata::decode_upright;
+use crate::imt::image_type::{jpeg_segments, ImageType};
+use crate::imt::progress::Progress;
+
+/// Something that digests a file, a chunk at a time, into a hex string.
+pub trait FileHasher {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> Result<String>;
+}
+
+/// A hash that can be recorded in the catalog, under `name`.
+#[derive(Debug)]
+pub struct HashAlgorithm {
+    pub name: &'static str,
+    // Whether two different files can be assumed to never have the same hash.
+    pub cryptographic: bool,
+    // Which types of file the hash can be taken of.
+    applies_to: fn(ImageType) -> bool,
+    new: fn() -> Box<dyn FileHasher>,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(&self) -> Box<dyn FileHasher> {
+        (self.new)()
+    }
+
+    pub fn applies_to(&self, image_type: ImageType) -> bool {
+        (self.applies_to)(image_type)
+    }
+}
+
+/// Every hash we know, with the default first.
+pub const HASH_ALGORITHMS: &[HashAlgorithm] = &[
+    HashAlgorithm {
+        name: "SHA256",
+        cryptographic: true,
+        applies_to: |_| true,
+        new: || Box::new(Sha256Hasher(Sha256::default())),
+    },
+    HashAlgorithm {
+        // Much faster than SHA-256.
+        name: "BLAKE3",
+        cryptographic: true,
+        applies_to: |_| true,
+        new: || Box::new(Blake3Hasher(blake3::Hasher::new())),
+    },
+    HashAlgorithm {
+        // 128-bit xxHash: very fast, but not cryptographic, so only for prefiltering.
+        name: "XXH3",
+        cryptographic: false,
+        applies_to: |_| true,
+        new: || Box::new(Xxh3Hasher(xxhash_rust::xxh3::Xxh3::new())),
+    },
+    HashAlgorithm {
+        // To match against manifests made by other tools. Collisions can be made on purpose,
+        // so it isn't trusted.
+        name: "SHA1",
+        cryptographic: false,
+        applies_to: |_| true,
+        new: || Box::new(Sha1Hasher(sha1::Sha1::default())),
+    },
+    HashAlgorithm {
+        // The SHA-256 of the decoded pixels, so copies that only differ in their metadata, or
+        // in how they were compressed, are the same.
+        name: "PIXELS",
+        cryptographic: true,
+        applies_to: |image_type| image_type != ImageType::UNKNOWN,
+        new: || Box::new(PixelHasher::default()),
+    },
+    HashAlgorithm {
+        // The SHA-256 of only the compressed scan data of a JPEG, which is much cheaper than
+        // decoding it, and skips the metadata all the same. The tables it is decoded with
+        // aren't included, so the same scan data can still be two different pictures.
+        name: "JPEG-SCAN",
+        cryptographic: false,
+        applies_to: |image_type| image_type == ImageType::JPEG,
+        new: || Box::new(JpegScanHasher::default()),
+    },
+];
+
+pub fn default_hash_algorithm() -> &'static HashAlgorithm {
+    &HASH_ALGORITHMS[0]
+}
+
+/// The algorithm called `name`, ignoring case and dashes, so "sha-256" works too.
+pub fn hash_algorithm(name: &str) -> Result<&'static HashAlgorithm> {
+    let wanted = name.replace('-', "");
+    HASH_ALGORITHMS
+        .iter()
+        .find(|a| a.name.replace('-', "").eq_ignore_ascii_case(&wanted))
+        .ok_or_else(|| {
+            let names: Vec<&str> = HASH_ALGORITHMS.iter().map(|a| a.name).collect();
+            anyhow!("unknown hash: {} (try {})", name, names.join(", "))
+        })
+}
+
+/// Returns the hash of everything in `reader`, in hex.
+pub fn hash_reader<R: Read>(
+    reader: &mut R,
+    algorithm: &HashAlgorithm,
+    progress: &Progress,
+) -> Result<String> {
+    let mut hasher = algorithm.hasher();
+    let mut buffer = [0; 10000];
+
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[0..n]);
+        progress.add_bytes(n as u64);
+    }
+    hasher.finish()
+}
+
+struct Sha256Hasher(Sha256);
+
+impl FileHasher for Sha256Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.input(data);
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        Ok(hex::encode(self.0.result()))
+    }
+}
+
+struct Blake3Hasher(blake3::Hasher);
+
+impl FileHasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        Ok(self.0.finalize().to_hex().to_string())
+    }
+}
+
+struct Xxh3Hasher(xxhash_rust::xxh3::Xxh3);
+
+impl FileHasher for Xxh3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        Ok(format!("{:032x}", self.0.digest128()))
+    }
+}
+
+struct Sha1Hasher(sha1::Sha1);
+
+impl FileHasher for Sha1Hasher {
+    fn update(&mut self, data: &[u8]) {
+        sha1::Digest::update(&mut self.0, data);
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        Ok(hex::encode(sha1::Digest::finalize(self.0)))
+    }
+}
+
+// Both of these need the whole file before they can start.
+#[derive(Default)]
+struct PixelHasher(Vec<u8>);
+
+impl FileHasher for PixelHasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        let mut sha = Sha256::default();
+        for frame in decode_frames(&self.0)? {
+            // The size and timing are part of the picture too, so a 2x3 image can't match a
+            // 3x2 one, and an animation can't match one with the same frames played faster.
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let buffer = frame.into_buffer();
+            for n in [buffer.width(), buffer.height(), numer, denom] {
+                sha.input(n.to_le_bytes());
+            }
+            sha.input(buffer.as_raw());
+        }
+        Ok(hex::encode(sha.result()))
+    }
+}
+
+// Every frame of the image, as full-size RGBA, so the same picture hashes the same in any
+// format. Still images are turned upright first, since a copy with a different orientation
+// looks different.
+fn decode_frames(bytes: &[u8]) -> Result<Vec<Frame>> {
+    let image_type = ImageType::type_of_bytes(bytes)?;
+    let animated = image_type
+        .animation(&mut Cursor::new(bytes))?
+        .is_some_and(|a| a.is_animated());
+    let frames = match image_type {
+        ImageType::GIF if animated => GifDecoder::new(bytes)?.into_frames().collect_frames()?,
+        ImageType::PNG if animated => PngDecoder::new(bytes)?
+            .apng()
+            .into_frames()
+            .collect_frames()?,
+        ImageType::WEBP if animated => WebPDecoder::new(bytes)?.into_frames().collect_frames()?,
+        _ => vec![Frame::new(decode_upright(bytes, image_type)?.into_rgba8())],
+    };
+    Ok(frames)
+}
+
+#[derive(Default)]
+struct JpegScanHasher(Vec<u8>);
+
+impl FileHasher for JpegScanHasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+
+    fn finish(self: Box<Self>) -> Result<String> {
+        let scan = jpeg_scan_data(&self.0)?;
+        Ok(hex::encode(Sha256::digest(&self.0[scan])))
+    }
+}
+
+// Where the compressed data is: after the first SOS segment, up to the EOI marker. A
+// progressive JPEG has more tables and scans in between, which are part of it too. Anything
+// after EOI, like the extra images some cameras append, is left out.
+fn jpeg_scan_data(bytes: &[u8]) -> Result<Range<usize>> {
+    let no_eoi = || anyhow!("JPEG has no end of image");
+    let sos = jpeg_segments(bytes)?
+        .into_iter()
+        .find(|s| s.id == 0xda)
+        .ok_or_else(|| anyhow!("JPEG has no scan data"))?;
+    // Skip any fill bytes before the marker, then the marker and its header.
+    let mut pos = sos.range.start;
+    while bytes.get(pos + 1) == Some(&0xff) {
+        pos += 1;
+    }
+    let start = pos + 2 + segment_length(bytes, pos).ok_or_else(no_eoi)?;
+
+    pos = start;
+    while pos + 1 < bytes.len() {
+        if bytes[pos] != 0xff {
+            pos += 1;
+            continue;
+        }
+        match bytes[pos + 1] {
+            // Fill bytes before a marker.
+            0xff => pos += 1,
+            // A stuffed zero, which is data, or a restart marker, which has no length.
+            0x00 | 0xd0..=0xd7 => pos += 2,
+            0xd9 => return Ok(start..pos),
+            _ => pos += 2 + segment_length(bytes, pos).ok_or_else(no_eoi)?,
+        }
+    }
+    Err(no_eoi())
+}
+
+// The length of the marker segment at `pos`, which counts itself but not the marker.
+fn segment_length(bytes: &[u8], pos: usize) -> Option<usize> {
+    let len = bytes.get(pos + 2..pos + 4)?;
+    Some(usize::from(u16::from_be_bytes([len[0], len[1]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    // SOI, a DQT segment, and an SOS header, then `scan`.
+    fn jpeg(scan: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xd8, 0xff, 0xdb, 0x00, 0x04, 0x01, 0x02];
+        bytes.extend_from_slice(&[0xff, 0xda, 0x00, 0x04, 0x03, 0x04]);
+        bytes.extend_from_slice(scan);
+        bytes
+    }
+    const SCAN_START: usize = 14;
+
+    #[test_case(&[0x12, 0x34, 0xff, 0xd9], 2 ; "plain")]
+    #[test_case(&[0x12, 0xff, 0x00, 0x34, 0xff, 0xd9], 4 ; "stuffed byte")]
+    #[test_case(&[0x12, 0xff, 0xd0, 0x34, 0xff, 0xd9], 4 ; "restart marker")]
+    #[test_case(&[0x12, 0xff, 0xff, 0xd0, 0x34, 0xff, 0xd9], 5 ; "fill before a marker")]
+    #[test_case(&[0x12, 0xff, 0xc4, 0x00, 0x04, 0xff, 0xd9, 0x34, 0xff, 0xd9], 8 ; "tables between scans")]
+    #[test_case(&[0x12, 0xff, 0xd9, b'e', b'x', b't', b'r', b'a'], 1 ; "trailing data")]
+    fn scan_data_ends_at_eoi(scan: &[u8], len: usize) {
+        assert_eq!(
+            jpeg_scan_data(&jpeg(scan)).unwrap(),
+            SCAN_START..SCAN_START + len
+        );
+    }
+
+    #[test_case(&[0x12, 0x34] ; "no eoi")]
+    #[test_case(&[0x12, 0xff, 0xc4, 0x00] ; "truncated segment")]
+    fn scan_data_without_eoi_is_an_error(scan: &[u8]) {
+        assert!(jpeg_scan_data(&jpeg(scan)).is_err());
+    }
+
+    fn hash_of(bytes: &[u8], name: &str) -> String {
+        hash_reader(
+            &mut Cursor::new(bytes),
+            hash_algorithm(name).unwrap(),
+            &Progress::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn scan_hash_ignores_metadata() {
+        let bytes = std::fs::read("test_images/test.jpg").unwrap();
+        // An APP1 segment right after SOI, and something appended after EOI.
+        let mut tagged = [&bytes[..2], b"\xff\xe1\x00\x07hello", &bytes[2..]].concat();
+        tagged.extend_from_slice(b"trailer");
+        assert_eq!(hash_of(&bytes, "JPEG-SCAN"), hash_of(&tagged, "JPEG-SCAN"));
+        assert_ne!(hash_of(&bytes, "SHA256"), hash_of(&tagged, "SHA256"));
+    }
+
+    #[test]
+    fn pixel_hash_ignores_format() {
+        let image = image::open("test_images/test.jpg").unwrap();
+        let mut png = Cursor::new(Vec::default());
+        image.write_to(&mut png, image::ImageFormat::Png).unwrap();
+        let flipped = image.fliph();
+        let mut flipped_png = Cursor::new(Vec::default());
+        flipped
+            .write_to(&mut flipped_png, image::ImageFormat::Png)
+            .unwrap();
+
+        let jpeg = std::fs::read("test_images/test.jpg").unwrap();
+        assert_eq!(hash_of(&jpeg, "PIXELS"), hash_of(png.get_ref(), "PIXELS"));
+        assert_ne!(
+            hash_of(png.get_ref(), "PIXELS"),
+            hash_of(flipped_png.get_ref(), "PIXELS")
+        );
+    }
+
+    #[test]
+    fn scan_hash_is_not_trusted() {
+        assert!(!hash_algorithm("JPEG-SCAN").unwrap().cryptographic);
+    }
+
+    #[test]
+    fn sha1_is_not_trusted() {
+        assert!(!hash_algorithm("SHA1").unwrap().cryptographic);
+    }
+
+    #[test]
+    fn names_ignore_case_and_dashes() {
+        assert_eq!(hash_algorithm("jpegscan").unwrap().name, "JPEG-SCAN");
+        assert_eq!(hash_algorithm("sha-256").unwrap().name, "SHA256");
+        assert!(hash_algorithm("md5").is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use structopt::StructOpt;
+
+use crate::imt::image_type::ImageType;
+
+/// Print the type and dimensions of files, without touching the catalog.
+#[derive(StructOpt, Debug)]
+pub struct Identify {
+    /// The files to identify. Use "-" to read from stdin.
+    #[structopt(min_values(1), parse(from_os_str))]
+    files: Vec<PathBuf>,
+}
+
+fn describe<R: Read + Seek>(reader: &mut R) -> Result<String> {
+    let image_type = ImageType::type_of_file(reader)?;
+    let mut description = image_type.name().to_string();
+    if let Some((width, height)) = image_type.dimensions(reader)? {
+        description += &format!(" {}x{}", width, height);
+    }
+    if let Some(animation) = image_type.animation(reader)? {
+        if animation.is_animated() {
+            description += &format!(
+                ", {} frames, {} ms",
+                animation.frames, animation.duration_ms
+            );
+        }
+    }
+    Ok(description)
+}
+
+fn identify(path: &Path) -> Result<String> {
+    if path == Path::new("-") {
+        // Stdin can't seek, so read the whole thing.
+        let mut bytes = Vec::default();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        return describe(&mut Cursor::new(bytes));
+    }
+    describe(&mut File::open(path)?)
+}
+
+pub fn process_identify(id: &Identify) -> Result<()> {
+    for path in &id.files {
+        let description = identify(path).with_context(|| path.display().to_string())?;
+        println!("{}: {}", path.display(), description);
+    }
+    Ok(())
+}
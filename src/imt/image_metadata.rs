@@ -0,0 +1,146 @@
+// The metadata of JPEG, PNG, and WebP files that matters once the pixels are decoded: the
+// EXIF orientation, and the ICC color profile.
+
+use std::io::Read;
+
+use anyhow::Result;
+use flate2::read::ZlibDecoder;
+use image::DynamicImage;
+
+use crate::imt::exif::{orientation, EXIF_IDENTIFIER, ORIENTATION_NORMAL};
+use crate::imt::image_type::{jpeg_segments, png_chunks, ImageType};
+
+/// The metadata that is worth carrying over when an image is re-encoded.
+#[derive(Default)]
+pub struct Metadata {
+    /// The EXIF data as it is in a JPEG APP1 segment, starting with "Exif\0\0".
+    pub exif: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+}
+
+pub const ICC_IDENTIFIER: &[u8] = b"ICC_PROFILE\0";
+
+fn with_exif_identifier(tiff: &[u8]) -> Vec<u8> {
+    if tiff.starts_with(EXIF_IDENTIFIER) {
+        return tiff.to_vec();
+    }
+    [EXIF_IDENTIFIER, tiff].concat()
+}
+
+pub fn jpeg_metadata(bytes: &[u8]) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut icc_chunks: Vec<(u8, &[u8])> = Vec::default();
+    for segment in jpeg_segments(bytes)? {
+        // Skip the marker and the length.
+        let data = bytes
+            .get(segment.range.start + 4..segment.range.end)
+            .unwrap_or_default();
+        match segment.id {
+            0xe1 if data.starts_with(EXIF_IDENTIFIER) && metadata.exif.is_none() => {
+                metadata.exif = Some(data.to_vec())
+            }
+            0xe2 if data.starts_with(ICC_IDENTIFIER) && data.len() > ICC_IDENTIFIER.len() + 2 => {
+                let sequence = data[ICC_IDENTIFIER.len()];
+                icc_chunks.push((sequence, &data[ICC_IDENTIFIER.len() + 2..]));
+            }
+            _ => {}
+        }
+    }
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(sequence, _)| *sequence);
+        metadata.icc = Some(
+            icc_chunks
+                .iter()
+                .flat_map(|(_, d)| d.iter())
+                .copied()
+                .collect(),
+        );
+    }
+    Ok(metadata)
+}
+
+fn png_metadata(bytes: &[u8]) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    for chunk in png_chunks(bytes)? {
+        // length(4), type(4), data, CRC(4)
+        let data = bytes
+            .get(chunk.range.start + 8..chunk.range.end - 4)
+            .unwrap_or_default();
+        match &chunk.id {
+            b"eXIf" => metadata.exif = Some(with_exif_identifier(data)),
+            b"iCCP" => {
+                // name, NUL, compression method, then the zlib stream.
+                if let Some(nul) = data.iter().position(|b| *b == 0) {
+                    let mut icc = Vec::default();
+                    let compressed = data.get(nul + 2..).unwrap_or_default();
+                    ZlibDecoder::new(compressed).read_to_end(&mut icc)?;
+                    metadata.icc = Some(icc);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+// Calls `f` with the fourcc and data of each chunk in a WebP file.
+fn for_each_webp_chunk<'a, F>(bytes: &'a [u8], mut f: F)
+where
+    F: FnMut(&'a [u8], &'a [u8]),
+{
+    let mut pos = 12;
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let data = match bytes.get(pos + 8..pos + 8 + len) {
+            Some(data) => data,
+            None => break,
+        };
+        f(&header[..4], data);
+        // Chunks are padded to an even length.
+        pos += 8 + len + (len & 1);
+    }
+}
+
+fn webp_metadata(bytes: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    for_each_webp_chunk(bytes, |fourcc, data| match fourcc {
+        b"EXIF" => metadata.exif = Some(with_exif_identifier(data)),
+        b"ICCP" => metadata.icc = Some(data.to_vec()),
+        _ => {}
+    });
+    metadata
+}
+
+pub fn read_metadata(bytes: &[u8], image_type: ImageType) -> Result<Metadata> {
+    Ok(match image_type {
+        ImageType::JPEG => jpeg_metadata(bytes)?,
+        ImageType::PNG => png_metadata(bytes)?,
+        ImageType::WEBP => webp_metadata(bytes),
+        _ => Metadata::default(),
+    })
+}
+
+/// Turns the pixels the way that the EXIF orientation says to display them.
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Decodes a still image, turned the way that its EXIF orientation says to display it.
+pub fn decode_upright(bytes: &[u8], image_type: ImageType) -> Result<DynamicImage> {
+    let image = image::load_from_memory(bytes)?;
+    let metadata = read_metadata(bytes, image_type)?;
+    let orientation = metadata.exif.as_deref().and_then(orientation);
+    Ok(apply_orientation(
+        image,
+        orientation.unwrap_or(ORIENTATION_NORMAL),
+    ))
+}
@@ -1,15 +1,20 @@
+use std::convert::TryInto;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Serialize, Deserialize)]
 pub enum ImageType {
     JPEG,
     GIF,
     PNG,
+    WEBP,
 
     // Either an image type that we don't know, or not an image.
     UNKNOWN,
@@ -21,67 +26,801 @@ impl ImageType {
         ImageType::type_of_file(&mut file)
     }
 
-    pub fn type_of_file(file: &mut File) -> Result<ImageType> {
-        let bytes = read_first_ten_bytes(file)?;
-        let image_type = if is_jpeg(file, &bytes)? {
-            ImageType::JPEG
-        } else if is_png(&bytes)? {
-            ImageType::PNG
-        } else if is_gif(&bytes)? {
-            ImageType::GIF
-        } else {
-            ImageType::UNKNOWN
+    // Anything too short to hold a signature is UNKNOWN, not an error.
+    pub fn type_of_file<R: Read + Seek>(file: &mut R) -> Result<ImageType> {
+        let header = read_header(file)?;
+        let image_type = match ImageType::sniff(&header) {
+            Sniffed::Type(ImageType::JPEG) if !has_jpeg_trailer(file)? => ImageType::UNKNOWN,
+            Sniffed::Type(image_type) => image_type,
+            // We already read as much as there is.
+            Sniffed::NeedMoreBytes(_) => ImageType::UNKNOWN,
         };
         Ok(image_type)
     }
 
-    pub fn preferred_extension(self) -> &'static str {
+    pub fn type_of_bytes(bytes: &[u8]) -> Result<ImageType> {
+        ImageType::type_of_file(&mut Cursor::new(bytes))
+    }
+
+    /// Guess the type from the first bytes of a file, for callers that can't seek, like
+    /// streams. Unlike type_of_file, this doesn't check that a JPEG is complete.
+    pub fn sniff(header: &[u8]) -> Sniffed {
+        let mut needed = 0;
+        for (signature, image_type) in SIGNATURES {
+            match bytes_needed(header, signature) {
+                Some(n) if n <= header.len() => return Sniffed::Type(*image_type),
+                Some(n) => needed = needed.max(n),
+                None => {}
+            }
+        }
+        if needed > 0 {
+            Sniffed::NeedMoreBytes(needed)
+        } else {
+            Sniffed::Type(ImageType::UNKNOWN)
+        }
+    }
+
+    pub fn dimensions_of_file_at<P: AsRef<Path>>(self, path: P) -> Result<Option<(u32, u32)>> {
+        let mut file = File::open(path)?;
+        self.dimensions(&mut file)
+    }
+
+    pub fn dimensions_of_bytes(self, bytes: &[u8]) -> Result<Option<(u32, u32)>> {
+        self.dimensions(&mut Cursor::new(bytes))
+    }
+
+    // Returns (width, height) as read from the image header, or None if we can't find it.
+    pub fn dimensions<R: Read + Seek>(self, file: &mut R) -> Result<Option<(u32, u32)>> {
         match self {
-            ImageType::JPEG => "jpg",
-            ImageType::GIF => "gif",
-            ImageType::PNG => "png",
+            ImageType::JPEG => jpeg_dimensions(file),
+            ImageType::GIF => gif_dimensions(file),
+            ImageType::PNG => png_dimensions(file),
+            ImageType::WEBP => webp_dimensions(file),
 
-            ImageType::UNKNOWN => "",
+            ImageType::UNKNOWN => Ok(None),
         }
     }
+
+    pub fn animation_of_file_at<P: AsRef<Path>>(path: P) -> Result<Option<Animation>> {
+        let mut file = File::open(path)?;
+        let image_type = ImageType::type_of_file(&mut file)?;
+        image_type.animation(&mut file)
+    }
+
+    // Returns the frame count and duration, or None if this isn't an image.
+    pub fn animation<R: Read + Seek>(self, file: &mut R) -> Result<Option<Animation>> {
+        match self {
+            ImageType::JPEG => Ok(Some(Animation::STILL)),
+            ImageType::GIF => gif_animation(file).map(Some),
+            ImageType::PNG => png_animation(file).map(Some),
+            ImageType::WEBP => webp_animation(file).map(Some),
+
+            ImageType::UNKNOWN => Ok(None),
+        }
+    }
+
+    /// What we know about the format, or None for UNKNOWN.
+    pub fn format(self) -> Option<&'static Format> {
+        FORMATS.iter().find(|f| f.image_type == self)
+    }
+
+    pub fn name(self) -> &'static str {
+        self.format().map_or("UNKNOWN", |f| f.name)
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        self.format()
+            .map_or("application/octet-stream", |f| f.mime_type)
+    }
+
+    pub fn preferred_extension(self) -> &'static str {
+        self.format().map_or("", |f| f.extensions[0])
+    }
+
+    // True if `path` has one of the extensions that we accept for this format. Case doesn't
+    // matter.
+    pub fn has_matching_extension(self, path: &Path) -> bool {
+        let ext = match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.to_ascii_lowercase(),
+            None => return false,
+        };
+        self.format()
+            .is_some_and(|f| f.extensions.contains(&ext.as_str()))
+    }
+}
+
+/// A format that we can detect.
+#[derive(Debug, Serialize)]
+pub struct Format {
+    pub image_type: ImageType,
+    pub name: &'static str,
+    pub mime_type: &'static str,
+    // Lower case, and the first one is the preferred one.
+    pub extensions: &'static [&'static str],
+    pub animation: bool,
+    pub transparency: bool,
+    pub lossless: bool,
 }
 
-type BufType = [u8; 10];
+pub const FORMATS: &[Format] = &[
+    Format {
+        image_type: ImageType::JPEG,
+        name: "JPEG",
+        mime_type: "image/jpeg",
+        extensions: &["jpg", "jpeg", "jpe", "jfif"],
+        animation: false,
+        transparency: false,
+        lossless: false,
+    },
+    Format {
+        image_type: ImageType::GIF,
+        name: "GIF",
+        mime_type: "image/gif",
+        extensions: &["gif"],
+        animation: true,
+        transparency: true,
+        lossless: true,
+    },
+    Format {
+        image_type: ImageType::PNG,
+        name: "PNG",
+        mime_type: "image/png",
+        extensions: &["png"],
+        // APNG.
+        animation: true,
+        transparency: true,
+        lossless: true,
+    },
+    Format {
+        image_type: ImageType::WEBP,
+        name: "WebP",
+        mime_type: "image/webp",
+        extensions: &["webp"],
+        animation: true,
+        transparency: true,
+        lossless: true,
+    },
+];
 
-fn read_first_ten_bytes(file: &mut File) -> Result<BufType> {
-    let mut buf = [0; 10];
-    read_first_bytes(file, &mut buf)?;
-    Ok(buf)
+/// How many frames an image has, and how long it takes to play them all once.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Animation {
+    pub frames: u32,
+    pub duration_ms: u64,
 }
 
-fn read_first_bytes(file: &mut File, buf: &mut [u8]) -> Result<()> {
-    read_bytes(file, buf, SeekFrom::Start(0))
+impl Animation {
+    pub const STILL: Animation = Animation {
+        frames: 1,
+        duration_ms: 0,
+    };
+
+    pub fn is_animated(&self) -> bool {
+        self.frames > 1
+    }
 }
 
-fn read_bytes(file: &mut File, buf: &mut [u8], location: SeekFrom) -> Result<()> {
+impl FromStr for ImageType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ImageType> {
+        // Accept the name or any of the extensions.
+        let s = s.to_ascii_lowercase();
+        if s == "unknown" {
+            return Ok(ImageType::UNKNOWN);
+        }
+        FORMATS
+            .iter()
+            .find(|f| f.name.eq_ignore_ascii_case(&s) || f.extensions.contains(&s.as_str()))
+            .map(|f| f.image_type)
+            .ok_or_else(|| anyhow!("unknown image type: {}", s))
+    }
+}
+
+/// The result of looking at the start of a file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sniffed {
+    Type(ImageType),
+    // The header is a prefix of a signature, so we need at least this many bytes to decide.
+    NeedMoreBytes(usize),
+}
+
+// The end of the longest signature, which is all that we ever need to read to sniff.
+pub const SNIFF_LEN: usize = 12;
+
+// A signature is a list of (offset, bytes) that must all match.
+type Signature = &'static [(usize, &'static [u8])];
+
+const SIGNATURES: &[(Signature, ImageType)] = &[
+    (&[(0, &[0xff, 0xd8])], ImageType::JPEG),
+    (&[(0, b"\x89PNG\r\n\x1a\n")], ImageType::PNG),
+    (&[(0, b"GIF87a")], ImageType::GIF),
+    (&[(0, b"GIF89a")], ImageType::GIF),
+    // The RIFF chunk size comes between the two.
+    (&[(0, b"RIFF"), (8, b"WEBP")], ImageType::WEBP),
+];
+
+// Returns None if the header doesn't match the signature. Otherwise, returns how many bytes
+// of header it takes to match all of it, which may be more than we have.
+fn bytes_needed(header: &[u8], signature: Signature) -> Option<usize> {
+    let mut needed = 0;
+    for (offset, bytes) in signature {
+        let available = header.get(*offset..).unwrap_or_default();
+        let n = available.len().min(bytes.len());
+        if available[..n] != bytes[..n] {
+            return None;
+        }
+        needed = needed.max(offset + bytes.len());
+    }
+    Some(needed)
+}
+
+// Reads up to SNIFF_LEN bytes from the start of the file, or less if the file is shorter.
+fn read_header<R: Read + Seek>(file: &mut R) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+fn read_bytes<R: Read + Seek>(file: &mut R, buf: &mut [u8], location: SeekFrom) -> Result<()> {
     file.seek(location)?;
     file.read_exact(buf)?;
     Ok(())
 }
 
-fn is_jpeg(file: &mut File, buf: &BufType) -> Result<bool> {
-    if buf[0..2] != [0xff, 0xd8] {
+fn has_jpeg_trailer<R: Read + Seek>(file: &mut R) -> Result<bool> {
+    // A file that is nothing but the SOI marker can't have an EOI marker too.
+    if file.seek(SeekFrom::End(0))? < 4 {
         return Ok(false);
     }
     let mut tail = [0; 2];
-    read_bytes(file, &mut tail, SeekFrom::End(0))?;
-    if tail != [0xff, 0xd9] {
-        return Ok(false);
+    read_bytes(file, &mut tail, SeekFrom::End(-2))?;
+    Ok(tail == [0xff, 0xd9])
+}
+
+fn png_dimensions<R: Read + Seek>(file: &mut R) -> Result<Option<(u32, u32)>> {
+    // The IHDR chunk is always first, and width and height are its first two fields.
+    let mut buf = [0; 8];
+    read_bytes(file, &mut buf, SeekFrom::Start(16))?;
+    let width = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let height = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    Ok(Some((width, height)))
+}
+
+fn gif_dimensions<R: Read + Seek>(file: &mut R) -> Result<Option<(u32, u32)>> {
+    // The logical screen size follows the signature.
+    let mut buf = [0; 4];
+    read_bytes(file, &mut buf, SeekFrom::Start(6))?;
+    let width = u16::from_le_bytes([buf[0], buf[1]]);
+    let height = u16::from_le_bytes([buf[2], buf[3]]);
+    Ok(Some((width.into(), height.into())))
+}
+
+fn jpeg_dimensions<R: Read + Seek>(file: &mut R) -> Result<Option<(u32, u32)>> {
+    // Walk the segments until we find a start-of-frame marker. The image data starts at SOS,
+    // so if we get there, then there is no frame header.
+    let mut pos = 2;
+    loop {
+        let mut marker = [0; 2];
+        read_bytes(file, &mut marker, SeekFrom::Start(pos))?;
+        if marker[0] != 0xff {
+            return Ok(None);
+        }
+        match marker[1] {
+            // Fill byte.
+            0xff => pos += 1,
+            // Markers without a length.
+            0x01 | 0xd0..=0xd8 => pos += 2,
+            // SOS or EOI.
+            0xda | 0xd9 => return Ok(None),
+            // SOFn, except for DHT, JPG, and DAC, which share the range.
+            0xc0..=0xcf if marker[1] != 0xc4 && marker[1] != 0xc8 && marker[1] != 0xcc => {
+                // length(2), precision(1), height(2), width(2)
+                let mut buf = [0; 7];
+                read_bytes(file, &mut buf, SeekFrom::Start(pos + 2))?;
+                let height = u16::from_be_bytes([buf[3], buf[4]]);
+                let width = u16::from_be_bytes([buf[5], buf[6]]);
+                return Ok(Some((width.into(), height.into())));
+            }
+            _ => {
+                let mut len = [0; 2];
+                read_bytes(file, &mut len, SeekFrom::Start(pos + 2))?;
+                pos += 2 + u64::from(u16::from_be_bytes(len));
+            }
+        }
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn gif_skip_color_table<R: Read + Seek>(r: &mut BufReader<R>, packed: u8) -> Result<()> {
+    if packed & 0x80 != 0 {
+        r.seek_relative(3 << ((packed & 0x07) + 1))?;
+    }
+    Ok(())
+}
+
+fn gif_skip_sub_blocks<R: Read + Seek>(r: &mut BufReader<R>) -> Result<()> {
+    loop {
+        match read_u8(r)? {
+            0 => return Ok(()),
+            n => r.seek_relative(n.into())?,
+        }
+    }
+}
+
+fn gif_animation<R: Read + Seek>(file: &mut R) -> Result<Animation> {
+    // Lots of tiny reads, so buffer them.
+    let mut r = BufReader::new(file);
+    // Skip the signature and screen size to get to the flags of the logical screen
+    // descriptor: packed(1), background color(1), aspect ratio(1).
+    r.seek(SeekFrom::Start(10))?;
+    let mut screen = [0; 3];
+    r.read_exact(&mut screen)?;
+    gif_skip_color_table(&mut r, screen[0])?;
+
+    let mut animation = Animation::default();
+    loop {
+        match read_u8(&mut r)? {
+            // Image descriptor: left(2), top(2), width(2), height(2), packed(1), then the
+            // LZW code size and the image data.
+            0x2c => {
+                let mut descriptor = [0; 9];
+                r.read_exact(&mut descriptor)?;
+                gif_skip_color_table(&mut r, descriptor[8])?;
+                read_u8(&mut r)?;
+                gif_skip_sub_blocks(&mut r)?;
+                animation.frames += 1;
+            }
+            // Extension. The graphic control extension has the delay, in 1/100s of a second,
+            // for the image that follows it: packed(1), delay(2), transparent index(1).
+            0x21 => {
+                let label = read_u8(&mut r)?;
+                if label == 0xf9 {
+                    let size = read_u8(&mut r)?;
+                    let mut data = vec![0; size.into()];
+                    r.read_exact(&mut data)?;
+                    if data.len() >= 3 {
+                        let delay = u16::from_le_bytes([data[1], data[2]]);
+                        animation.duration_ms += u64::from(delay) * 10;
+                    }
+                }
+                gif_skip_sub_blocks(&mut r)?;
+            }
+            // The trailer, or junk after the last image.
+            _ => break,
+        }
+    }
+    Ok(animation)
+}
+
+fn png_animation<R: Read + Seek>(file: &mut R) -> Result<Animation> {
+    // An APNG has an acTL chunk with the number of frames, and an fcTL chunk before each
+    // frame with its delay. Anything else is a still.
+    let mut frames = None;
+    let mut duration_ms = 0;
+    let mut pos = 8;
+    loop {
+        // length(4), type(4), data(length), crc(4)
+        let mut header = [0; 8];
+        read_bytes(file, &mut header, SeekFrom::Start(pos))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        match &header[4..8] {
+            // num_frames(4), num_plays(4)
+            b"acTL" => {
+                let mut buf = [0; 4];
+                read_bytes(file, &mut buf, SeekFrom::Start(pos + 8))?;
+                frames = Some(u32::from_be_bytes(buf));
+            }
+            // sequence(4), width(4), height(4), x(4), y(4), delay_num(2), delay_den(2), ...
+            b"fcTL" => {
+                let mut buf = [0; 24];
+                read_bytes(file, &mut buf, SeekFrom::Start(pos + 8))?;
+                let num = u16::from_be_bytes([buf[20], buf[21]]);
+                // A denominator of 0 means 1/100s.
+                let den = match u16::from_be_bytes([buf[22], buf[23]]) {
+                    0 => 100,
+                    den => den,
+                };
+                duration_ms += u64::from(num) * 1000 / u64::from(den);
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + u64::from(len);
+    }
+    Ok(match frames {
+        Some(frames) => Animation {
+            frames,
+            duration_ms,
+        },
+        None => Animation::STILL,
+    })
+}
+
+// Calls `f` with the type, data offset, and data size of each chunk in a WebP file, until it
+// returns false.
+fn for_each_webp_chunk<R, F>(file: &mut R, mut f: F) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(&mut R, &[u8], u64, u32) -> Result<bool>,
+{
+    // "RIFF", size(4), "WEBP", then the chunks. The size counts everything after itself.
+    let mut size = [0; 4];
+    read_bytes(file, &mut size, SeekFrom::Start(4))?;
+    let end = 8 + u64::from(u32::from_le_bytes(size));
+    let mut pos = 12;
+    while pos + 8 <= end {
+        // type(4), size(4), data(size), padded to an even length
+        let mut header = [0; 8];
+        read_bytes(file, &mut header, SeekFrom::Start(pos))?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if !f(file, &header[0..4], pos + 8, len)? {
+            break;
+        }
+        pos += 8 + u64::from(len) + u64::from(len & 1);
     }
-    Ok(true)
+    Ok(())
+}
+
+fn u24_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
+
+fn webp_dimensions<R: Read + Seek>(file: &mut R) -> Result<Option<(u32, u32)>> {
+    let mut dimensions = None;
+    for_each_webp_chunk(file, |file, chunk_type, data, _| {
+        match chunk_type {
+            // flags(1), reserved(3), canvas width - 1 (3), canvas height - 1 (3)
+            b"VP8X" => {
+                let mut buf = [0; 10];
+                read_bytes(file, &mut buf, SeekFrom::Start(data))?;
+                dimensions = Some((u24_le(&buf[4..7]) + 1, u24_le(&buf[7..10]) + 1));
+            }
+            // Lossy: frame tag(3), start code(3), width(2), height(2). The top two bits of
+            // each are the scale.
+            b"VP8 " => {
+                let mut buf = [0; 10];
+                read_bytes(file, &mut buf, SeekFrom::Start(data))?;
+                let width = u16::from_le_bytes([buf[6], buf[7]]) & 0x3fff;
+                let height = u16::from_le_bytes([buf[8], buf[9]]) & 0x3fff;
+                dimensions = Some((width.into(), height.into()));
+            }
+            // Lossless: signature(1), then 14 bits each of width - 1 and height - 1.
+            b"VP8L" => {
+                let mut buf = [0; 5];
+                read_bytes(file, &mut buf, SeekFrom::Start(data))?;
+                let bits = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]);
+                dimensions = Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1));
+            }
+            _ => return Ok(true),
+        }
+        // The first image chunk has the size of the whole image.
+        Ok(false)
+    })?;
+    Ok(dimensions)
+}
+
+fn webp_animation<R: Read + Seek>(file: &mut R) -> Result<Animation> {
+    // Each frame of an animation is in an ANMF chunk: x(3), y(3), width - 1 (3),
+    // height - 1 (3), duration in ms (3), flags(1), then the frame itself.
+    let mut animation = Animation::default();
+    for_each_webp_chunk(file, |file, chunk_type, data, _| {
+        if chunk_type == b"ANMF" {
+            let mut buf = [0; 15];
+            read_bytes(file, &mut buf, SeekFrom::Start(data))?;
+            animation.frames += 1;
+            animation.duration_ms += u64::from(u24_le(&buf[12..15]));
+        }
+        Ok(true)
+    })?;
+    if animation.frames == 0 {
+        animation = Animation::STILL;
+    }
+    Ok(animation)
+}
+
+/// A span of a file that can be kept or dropped as a unit: a JPEG marker segment, a PNG chunk,
+/// or a GIF block. Everything before the first segment is the file header.
+#[derive(Clone, Debug)]
+pub struct Segment<T> {
+    pub id: T,
+    pub range: Range<usize>,
+}
+
+fn truncated(format: &str) -> anyhow::Error {
+    anyhow!("truncated {} file", format)
+}
+
+fn be_u16_at(bytes: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        bytes.get(pos..pos + 2)?.try_into().ok()?,
+    ))
 }
 
-fn is_png(buf: &BufType) -> Result<bool> {
-    Ok(buf[0..8] == [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a])
+fn be_u32_at(bytes: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(pos..pos + 4)?.try_into().ok()?,
+    ))
+}
+
+/// The marker segments of a JPEG, identified by their marker byte. The scan data can't be
+/// split up, so the SOS segment runs to the end of the file.
+pub fn jpeg_segments(bytes: &[u8]) -> Result<Vec<Segment<u8>>> {
+    let mut segments = Vec::default();
+    let mut pos = 2;
+    loop {
+        if bytes.get(pos) != Some(&0xff) {
+            return Err(truncated("JPEG"));
+        }
+        // Any number of fill bytes may come before the marker.
+        let start = pos;
+        while bytes.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        let marker = *bytes.get(pos + 1).ok_or_else(|| truncated("JPEG"))?;
+        let end = match marker {
+            0xda | 0xd9 => bytes.len(),
+            0x01 | 0xd0..=0xd8 => pos + 2,
+            _ => {
+                let len = be_u16_at(bytes, pos + 2).ok_or_else(|| truncated("JPEG"))?;
+                pos + 2 + usize::from(len)
+            }
+        };
+        if end > bytes.len() {
+            return Err(truncated("JPEG"));
+        }
+        segments.push(Segment {
+            id: marker,
+            range: start..end,
+        });
+        if end == bytes.len() {
+            return Ok(segments);
+        }
+        pos = end;
+    }
+}
+
+/// The chunks of a PNG, identified by their type. Anything after IEND is left out.
+pub fn png_chunks(bytes: &[u8]) -> Result<Vec<Segment<[u8; 4]>>> {
+    let mut chunks = Vec::default();
+    let mut pos = 8;
+    loop {
+        // length(4), type(4), data(length), crc(4)
+        let len = be_u32_at(bytes, pos).ok_or_else(|| truncated("PNG"))?;
+        let id: [u8; 4] = bytes
+            .get(pos + 4..pos + 8)
+            .and_then(|t| t.try_into().ok())
+            .ok_or_else(|| truncated("PNG"))?;
+        let end = pos + 12 + len as usize;
+        if end > bytes.len() {
+            return Err(truncated("PNG"));
+        }
+        chunks.push(Segment {
+            id,
+            range: pos..end,
+        });
+        if &id == b"IEND" {
+            return Ok(chunks);
+        }
+        pos = end;
+    }
+}
+
+// The end of the sub-blocks that start at `pos`.
+fn gif_sub_blocks_end(bytes: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        match bytes.get(pos) {
+            None => return Err(truncated("GIF")),
+            Some(0) => return Ok(pos + 1),
+            Some(&n) => pos += 1 + usize::from(n),
+        }
+    }
+}
+
+fn gif_color_table_len(packed: u8) -> usize {
+    if packed & 0x80 != 0 {
+        3 << ((packed & 0x07) + 1)
+    } else {
+        0
+    }
 }
 
-fn is_gif(buf: &BufType) -> Result<bool> {
-    Ok(buf[0..4] == [0x47, 0x49, 0x46, 0x38] && // 'GIF8'
-        (buf[4..6] == [0x37, 0x61] || // '7a'
-            buf[4..6] == [0x39, 0x6a])) // '9a'
+/// The blocks of a GIF, after the header and global color table. Images are identified by
+/// [0x2c, 0], extensions by [0x21, label], and the trailer, which runs to the end of the file,
+/// by [0x3b, 0].
+pub fn gif_blocks(bytes: &[u8]) -> Result<Vec<Segment<[u8; 2]>>> {
+    let packed = *bytes.get(10).ok_or_else(|| truncated("GIF"))?;
+    let mut pos = 13 + gif_color_table_len(packed);
+    let mut blocks = Vec::default();
+    loop {
+        let (id, end) = match bytes.get(pos) {
+            // left(2), top(2), width(2), height(2), packed(1), color table, LZW code size(1)
+            Some(0x2c) => {
+                let packed = *bytes.get(pos + 9).ok_or_else(|| truncated("GIF"))?;
+                let data = pos + 10 + gif_color_table_len(packed) + 1;
+                ([0x2c, 0], gif_sub_blocks_end(bytes, data)?)
+            }
+            Some(0x21) => {
+                let label = *bytes.get(pos + 1).ok_or_else(|| truncated("GIF"))?;
+                ([0x21, label], gif_sub_blocks_end(bytes, pos + 2)?)
+            }
+            Some(0x3b) => ([0x3b, 0], bytes.len()),
+            Some(b) => return Err(anyhow!("unexpected GIF block {:#04x}", b)),
+            None => return Err(truncated("GIF")),
+        };
+        blocks.push(Segment {
+            id,
+            range: pos..end,
+        });
+        if end == bytes.len() {
+            return Ok(blocks);
+        }
+        pos = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(b"\xff\xd8\xff\xe0", ImageType::JPEG ; "jpeg")]
+    #[test_case(b"\x89PNG\r\n\x1a\n\0\0", ImageType::PNG ; "png")]
+    #[test_case(b"GIF87a..", ImageType::GIF ; "gif87a")]
+    #[test_case(b"GIF89a..", ImageType::GIF ; "gif89a")]
+    #[test_case(b"RIFF\x10\0\0\0WEBPVP8 ", ImageType::WEBP ; "webp")]
+    #[test_case(b"RIFF\x10\0\0\0WAVEfmt ", ImageType::UNKNOWN ; "other riff")]
+    #[test_case(b"hello, world", ImageType::UNKNOWN ; "text")]
+    fn sniff_full_header(header: &[u8], expected: ImageType) {
+        assert_eq!(ImageType::sniff(header), Sniffed::Type(expected));
+    }
+
+    #[test_case(b"", 12 ; "empty")]
+    #[test_case(b"\x89PN", 8 ; "partial png")]
+    #[test_case(b"GIF8", 6 ; "partial gif")]
+    #[test_case(b"RIFF\x10\0\0\0", 12 ; "riff without form type")]
+    fn sniff_short_header(header: &[u8], needed: usize) {
+        assert_eq!(ImageType::sniff(header), Sniffed::NeedMoreBytes(needed));
+    }
+
+    #[test_case(b"" ; "empty")]
+    #[test_case(b"\xff\xd8" ; "only soi")]
+    #[test_case(b"\xff\xd8\xff\xe0\0\x10" ; "no trailer")]
+    #[test_case(b"GIF8" ; "truncated signature")]
+    fn short_or_broken_files_are_unknown(bytes: &[u8]) {
+        assert_eq!(ImageType::type_of_bytes(bytes).unwrap(), ImageType::UNKNOWN);
+    }
+
+    #[test_case("test_images/test.jpg", ImageType::JPEG ; "jpeg")]
+    #[test_case("test_images/testjpg", ImageType::JPEG ; "jpeg without extension")]
+    #[test_case("test_images/testgif", ImageType::GIF ; "gif")]
+    #[test_case("test_images/testpng", ImageType::PNG ; "png")]
+    #[test_case("test_images/not_an_image", ImageType::UNKNOWN ; "not an image")]
+    fn type_of_test_images(path: &str, expected: ImageType) {
+        assert_eq!(ImageType::type_of_file_at(path).unwrap(), expected);
+    }
+
+    #[test]
+    fn jpeg_dimensions_come_from_the_frame_header() {
+        let dimensions = ImageType::JPEG.dimensions_of_file_at("test_images/test.jpg");
+        assert_eq!(dimensions.unwrap(), Some((585, 1080)));
+    }
+
+    fn animation_of(image_type: ImageType, bytes: &[u8]) -> Animation {
+        image_type
+            .animation(&mut Cursor::new(bytes))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn gif_frames_and_delays() {
+        let gif = std::fs::read("test_images/test.gif").unwrap();
+        let animation = animation_of(ImageType::GIF, &gif);
+        assert_eq!(animation.frames, 49);
+        assert_eq!(animation.duration_ms, 1960);
+        assert!(animation.is_animated());
+    }
+
+    #[test]
+    fn single_frame_gif_is_still() {
+        let mut gif = Vec::default();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut gif), image::ImageFormat::Gif)
+            .unwrap();
+        assert!(!animation_of(ImageType::GIF, &gif).is_animated());
+    }
+
+    // A PNG chunk. The CRC isn't checked, so it's left as zeros.
+    fn png_chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    // A frame control chunk with a delay of num/den seconds.
+    fn fctl(sequence: u32, num: u16, den: u16) -> Vec<u8> {
+        let mut data = sequence.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&num.to_be_bytes());
+        data.extend_from_slice(&den.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        png_chunk(b"fcTL", &data)
+    }
+
+    #[test]
+    fn apng_frames_and_delays() {
+        let png = [
+            &b"\x89PNG\r\n\x1a\n"[..],
+            &png_chunk(b"IHDR", &[0; 13]),
+            &png_chunk(b"acTL", &[0, 0, 0, 3, 0, 0, 0, 0]),
+            &fctl(0, 1, 4),
+            &png_chunk(b"IDAT", &[0; 5]),
+            // A denominator of 0 means hundredths.
+            &fctl(1, 5, 0),
+            &png_chunk(b"fdAT", &[0; 5]),
+            &fctl(2, 1, 1000),
+            &png_chunk(b"fdAT", &[0; 5]),
+            &png_chunk(b"IEND", &[]),
+        ]
+        .concat();
+        let animation = animation_of(ImageType::PNG, &png);
+        assert_eq!(animation.frames, 3);
+        assert_eq!(animation.duration_ms, 250 + 50 + 1);
+    }
+
+    #[test]
+    fn png_without_actl_is_still() {
+        let png = std::fs::read("test_images/testpng").unwrap();
+        assert_eq!(animation_of(ImageType::PNG, &png), Animation::STILL);
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = [&b"WEBP"[..], &chunks.concat()].concat();
+        [&b"RIFF"[..], &(body.len() as u32).to_le_bytes(), &body].concat()
+    }
+
+    fn webp_chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    // A frame with a duration in ms, and a tiny, odd-length image, to check the padding.
+    fn anmf(duration_ms: u32) -> Vec<u8> {
+        let mut data = vec![0; 12];
+        data.extend_from_slice(&duration_ms.to_le_bytes()[..3]);
+        data.push(0);
+        data.extend(webp_chunk(b"VP8L", &[0; 5]));
+        webp_chunk(b"ANMF", &data)
+    }
+
+    #[test]
+    fn webp_frames_and_durations() {
+        let animated = webp(&[
+            webp_chunk(b"VP8X", &[0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            webp_chunk(b"ANIM", &[0; 6]),
+            anmf(100),
+            anmf(250),
+        ]);
+        let animation = animation_of(ImageType::WEBP, &animated);
+        assert_eq!(animation.frames, 2);
+        assert_eq!(animation.duration_ms, 350);
+
+        let still = webp(&[webp_chunk(b"VP8L", &[0; 5])]);
+        assert_eq!(animation_of(ImageType::WEBP, &still), Animation::STILL);
+    }
 }
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::imt::direntryutil::has_extension;
+use crate::imt::filer::{FileInfo, Filer};
+use crate::imt::finddups::HASH_NAME;
+use crate::imt::image_type::ImageType;
+
+/// Report counts and sizes of the files in the catalog.
+#[derive(StructOpt, Debug)]
+pub struct Inventory {
+    /// Print the report as JSON.
+    #[structopt(long)]
+    json: bool,
+
+    /// Only count files under these directories. Defaults to the whole catalog.
+    #[structopt(parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Tally {
+    files: u64,
+    bytes: u64,
+}
+
+impl Tally {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    total: Tally,
+    by_type: BTreeMap<String, Tally>,
+    by_directory: BTreeMap<String, Tally>,
+    by_year: BTreeMap<String, Tally>,
+    // Files that we looked at, but which are not images that we understand.
+    non_images: Tally,
+    // Images that 'add-ext' would rename.
+    missing_extension: Tally,
+    // Images with an extension that belongs to some other format.
+    wrong_extension: Tally,
+    // Every copy of a file except for one.
+    duplicates: Tally,
+}
+
+// The top-level directory of 'path' under whichever root contains it, or the root itself for
+// files that are directly in it.
+fn top_level_directory(roots: &[PathBuf], path: &Path) -> String {
+    let root = roots
+        .iter()
+        .find(|r| path.starts_with(r))
+        .cloned()
+        .unwrap_or_default();
+    let rest = path.strip_prefix(&root).unwrap_or(path);
+    let mut components = rest
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)));
+    match (components.next(), components.next()) {
+        (Some(dir), Some(_)) => root.join(dir).display().to_string(),
+        _ if root.as_os_str().is_empty() => ".".to_string(),
+        _ => root.display().to_string(),
+    }
+}
+
+fn year(info: &FileInfo) -> String {
+    info.stat()
+        .mtime
+        .and_then(|m| DateTime::from_timestamp(m as i64, 0))
+        .map_or_else(|| "unknown".to_string(), |dt| dt.year().to_string())
+}
+
+fn type_name(image_type: Option<ImageType>) -> String {
+    match image_type {
+        Some(it) => it.name().to_string(),
+        None => "UNCHECKED".to_string(),
+    }
+}
+
+impl Report {
+    fn add(&mut self, roots: &[PathBuf], path: &Path, info: &FileInfo) {
+        let bytes = info.stat().size.unwrap_or(0);
+        self.total.add(bytes);
+        self.by_type
+            .entry(type_name(info.image_type()))
+            .or_default()
+            .add(bytes);
+        self.by_directory
+            .entry(top_level_directory(roots, path))
+            .or_default()
+            .add(bytes);
+        self.by_year.entry(year(info)).or_default().add(bytes);
+
+        match info.image_type() {
+            Some(ImageType::UNKNOWN) => self.non_images.add(bytes),
+            Some(_) if !has_extension(path) => self.missing_extension.add(bytes),
+            Some(it) if !it.has_matching_extension(path) => self.wrong_extension.add(bytes),
+            _ => {}
+        }
+    }
+
+    fn print(&self) {
+        print_tally("Total", &self.total);
+        print_tally("Not images", &self.non_images);
+        print_tally("Images without extensions", &self.missing_extension);
+        print_tally("Images with the wrong extension", &self.wrong_extension);
+        print_tally("Duplicate copies", &self.duplicates);
+        print_section("By type", &self.by_type);
+        print_section("By directory", &self.by_directory);
+        print_section("By year", &self.by_year);
+    }
+}
+
+fn print_tally(name: &str, tally: &Tally) {
+    println!("{}: {} files, {} bytes", name, tally.files, tally.bytes);
+}
+
+fn print_section(name: &str, tallies: &BTreeMap<String, Tally>) {
+    println!();
+    println!("{}:", name);
+    for (key, tally) in tallies {
+        println!(
+            "  {:<30} {:>10} files {:>16} bytes",
+            key, tally.files, tally.bytes
+        );
+    }
+}
+
+// The report on the files under 'directories', or on every file if there are none.
+fn build_report(directories: &[PathBuf], filer: &Filer) -> Report {
+    let mut report = Report::default();
+    let mut by_hash: HashMap<String, Vec<u64>> = HashMap::default();
+
+    filer.with_file_infos(|path, info| {
+        let in_scope = directories.is_empty() || directories.iter().any(|d| path.starts_with(d));
+        if !in_scope {
+            return;
+        }
+        report.add(directories, path, info);
+        if let Some(hash) = info.hash_value(HASH_NAME) {
+            by_hash
+                .entry(hash.clone())
+                .or_default()
+                .push(info.stat().size.unwrap_or(0));
+        }
+    });
+
+    for sizes in by_hash.values() {
+        sizes.iter().skip(1).for_each(|s| report.duplicates.add(*s));
+    }
+    report
+}
+
+pub fn process_inventory(inv: &Inventory, filer: &Filer) -> Result<()> {
+    let report = build_report(&inv.directories, filer);
+    if inv.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::imt::filer::FileStat;
+
+    #[test_case(&[], "a.jpg", "." ; "no roots, top level")]
+    #[test_case(&[], "trips/2020/a.jpg", "trips" ; "no roots, nested")]
+    #[test_case(&["photos"], "photos/a.jpg", "photos" ; "directly in the root")]
+    #[test_case(&["photos"], "photos/trips/2020/a.jpg", "photos/trips" ; "under the root")]
+    #[test_case(&["photos", "other"], "other/x/a.jpg", "other/x" ; "second root")]
+    fn top_level_directories(roots: &[&str], path: &str, expected: &str) {
+        let roots: Vec<PathBuf> = roots.iter().map(PathBuf::from).collect();
+        assert_eq!(top_level_directory(&roots, Path::new(path)), expected);
+    }
+
+    fn add(filer: &Filer, path: &str, image_type: ImageType, size: u64, hash: &str) {
+        filer.set_stat(
+            path,
+            FileStat {
+                size: Some(size),
+                ..FileStat::default()
+            },
+        );
+        filer.set_image_type(path, image_type);
+        filer.add_hash(path, HASH_NAME, hash).unwrap();
+    }
+
+    #[test]
+    fn report_tallies() {
+        let filer = Filer::new().unwrap();
+        add(&filer, "a/one.jpg", ImageType::JPEG, 10, "x");
+        add(&filer, "b/copy.jpg", ImageType::JPEG, 10, "x");
+        add(&filer, "b/copy2.jpeg", ImageType::JPEG, 10, "x");
+        add(&filer, "b/noext", ImageType::PNG, 20, "y");
+        add(&filer, "b/wrong.gif", ImageType::PNG, 30, "z");
+        add(&filer, "b/notes.txt", ImageType::UNKNOWN, 5, "w");
+
+        let report = build_report(&[], &filer);
+        assert_eq!((report.total.files, report.total.bytes), (6, 85));
+        assert_eq!((report.duplicates.files, report.duplicates.bytes), (2, 20));
+        assert_eq!(report.missing_extension.files, 1);
+        assert_eq!(report.wrong_extension.files, 1);
+        assert_eq!(report.non_images.files, 1);
+        assert_eq!(report.by_type["PNG"].bytes, 50);
+        assert_eq!(report.by_directory["b"].files, 5);
+
+        let report = build_report(&[PathBuf::from("a")], &filer);
+        assert_eq!(report.total.files, 1);
+        assert_eq!(report.duplicates.files, 0);
+    }
+}
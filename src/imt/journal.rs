@@ -0,0 +1,41 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::Utc;
+
+/// Where destructive actions, and the checks made before them, are recorded. It is only ever
+/// appended to, so it is a history of everything that was done to the files.
+pub const JOURNAL_PATH: &str = "imt-journal.log";
+
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open() -> Result<Journal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(JOURNAL_PATH)?;
+        Ok(Journal { file })
+    }
+
+    /// Appends one line: the time, the action, what came of it, and the paths involved.
+    pub fn record(&mut self, action: &str, outcome: &str, paths: &[&Path]) -> Result<()> {
+        let mut line = format!(
+            "{}\t{}\t{}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            action,
+            outcome
+        );
+        for path in paths {
+            line.push('\t');
+            line.push_str(&path.to_string_lossy());
+        }
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
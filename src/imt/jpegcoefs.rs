@@ -0,0 +1,1095 @@
+// Reading and writing the quantized DCT coefficients of a JPEG, so that they can be rearranged
+// without decoding the pixels, the way jpegtran does. Handles baseline and progressive 8-bit
+// Huffman-coded files. Files are always written back as a single baseline scan, with Huffman
+// tables built for the data, so progressive files come back sequential but otherwise
+// identical.
+
+use anyhow::{anyhow, Result};
+
+/// The natural (row-major) index of each coefficient, in the zigzag order that they are
+/// stored in.
+pub const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// An 8x8 block of coefficients, in natural order: index = row * 8 + column, where the row is
+/// the vertical frequency.
+pub type Block = [i16; 64];
+
+pub struct Component {
+    pub id: u8,
+    pub h: usize,
+    pub v: usize,
+    pub quant_table: usize,
+    // The blocks that cover the image, and the blocks that cover it padded out to whole MCUs.
+    pub blocks_wide: usize,
+    pub blocks_high: usize,
+    pub padded_wide: usize,
+    pub padded_high: usize,
+    // padded_wide * padded_high blocks, row by row.
+    pub blocks: Vec<Block>,
+}
+
+impl Component {
+    pub fn block(&self, x: usize, y: usize) -> &Block {
+        &self.blocks[y * self.padded_wide + x]
+    }
+
+    fn block_mut(&mut self, x: usize, y: usize) -> &mut Block {
+        &mut self.blocks[y * self.padded_wide + x]
+    }
+}
+
+#[derive(Clone)]
+pub struct QuantTable {
+    pub sixteen_bit: bool,
+    // In natural order.
+    pub values: [u16; 64],
+}
+
+pub struct Coefficients {
+    pub width: usize,
+    pub height: usize,
+    pub components: Vec<Component>,
+    pub quant_tables: [Option<QuantTable>; 4],
+    // The APPn and COM segments, as (marker, data without the length), in file order.
+    pub extra_segments: Vec<(u8, Vec<u8>)>,
+}
+
+impl Coefficients {
+    /// The size of an MCU, in pixels.
+    pub fn mcu_size(&self) -> (usize, usize) {
+        let max_h = self.components.iter().map(|c| c.h).max().unwrap_or(1);
+        let max_v = self.components.iter().map(|c| c.v).max().unwrap_or(1);
+        (8 * max_h, 8 * max_v)
+    }
+
+    /// Recompute the block counts of every component from the image size and the sampling
+    /// factors, and make room for that many blocks. Existing blocks are dropped.
+    pub fn layout(&mut self) {
+        let (mcu_width, mcu_height) = self.mcu_size();
+        let (max_h, max_v) = (mcu_width / 8, mcu_height / 8);
+        let mcus_wide = self.width.div_ceil(mcu_width);
+        let mcus_high = self.height.div_ceil(mcu_height);
+        for c in &mut self.components {
+            c.blocks_wide = (self.width * c.h).div_ceil(max_h).div_ceil(8);
+            c.blocks_high = (self.height * c.v).div_ceil(max_v).div_ceil(8);
+            c.padded_wide = mcus_wide * c.h;
+            c.padded_high = mcus_high * c.v;
+            c.blocks = vec![[0; 64]; c.padded_wide * c.padded_high];
+        }
+    }
+
+    fn mcus(&self) -> (usize, usize) {
+        let (mcu_width, mcu_height) = self.mcu_size();
+        (
+            self.width.div_ceil(mcu_width),
+            self.height.div_ceil(mcu_height),
+        )
+    }
+
+    // Every block in a scan of `scan_components`, as (component, x, y, starts an MCU), in the
+    // order that they are coded.
+    fn scan_order(&self, scan_components: &[usize]) -> Vec<(usize, usize, usize, bool)> {
+        let mut order = Vec::default();
+        if let [ci] = scan_components {
+            // A scan of one component isn't interleaved, and doesn't cover the padding.
+            let c = &self.components[*ci];
+            for y in 0..c.blocks_high {
+                for x in 0..c.blocks_wide {
+                    order.push((*ci, x, y, true));
+                }
+            }
+            return order;
+        }
+        let (mcus_wide, mcus_high) = self.mcus();
+        for my in 0..mcus_high {
+            for mx in 0..mcus_wide {
+                let mut first = true;
+                for ci in scan_components {
+                    let c = &self.components[*ci];
+                    for v in 0..c.v {
+                        for h in 0..c.h {
+                            order.push((*ci, mx * c.h + h, my * c.v + v, first));
+                            first = false;
+                        }
+                    }
+                }
+            }
+        }
+        order
+    }
+}
+
+fn invalid(what: &str) -> anyhow::Error {
+    anyhow!("invalid JPEG: {}", what)
+}
+
+fn be_u16(bytes: &[u8], pos: usize) -> Result<usize> {
+    match bytes.get(pos..pos + 2) {
+        Some(b) => Ok(usize::from(u16::from_be_bytes([b[0], b[1]]))),
+        None => Err(invalid("truncated")),
+    }
+}
+
+//
+// Reading
+//
+
+struct HuffmanTable {
+    // For each code length, the largest code of that length (or -1), the smallest code, and
+    // the index of its value.
+    max_code: [i32; 17],
+    min_code: [i32; 17],
+    first_value: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(counts: &[u8], values: &[u8]) -> HuffmanTable {
+        let mut table = HuffmanTable {
+            max_code: [-1; 17],
+            min_code: [0; 17],
+            first_value: [0; 17],
+            values: values.to_vec(),
+        };
+        let mut code = 0;
+        let mut k = 0;
+        for len in 1..=16 {
+            let count = i32::from(counts[len - 1]);
+            table.first_value[len] = k;
+            table.min_code[len] = code;
+            if count > 0 {
+                table.max_code[len] = code + count - 1;
+            }
+            code = (code + count) << 1;
+            k += count as usize;
+        }
+        table
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8> {
+        let mut code = 0;
+        for len in 1..=16 {
+            code = (code << 1) | reader.bit() as i32;
+            if code <= self.max_code[len] {
+                let index = self.first_value[len] + (code - self.min_code[len]) as usize;
+                return self
+                    .values
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| invalid("bad Huffman code"));
+            }
+        }
+        Err(invalid("bad Huffman code"))
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    bits_left: u32,
+    // Once we reach a marker, we feed zeros until the next restart.
+    at_marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            byte: 0,
+            bits_left: 0,
+            at_marker: false,
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.at_marker {
+            return 0;
+        }
+        match (self.data.get(self.pos), self.data.get(self.pos + 1)) {
+            // A stuffed zero byte.
+            (Some(0xff), Some(0)) => {
+                self.pos += 2;
+                0xff
+            }
+            (Some(0xff), _) | (None, _) => {
+                self.at_marker = true;
+                0
+            }
+            (Some(b), _) => {
+                self.pos += 1;
+                *b
+            }
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        if self.bits_left == 0 {
+            self.byte = self.next_byte();
+            self.bits_left = 8;
+        }
+        self.bits_left -= 1;
+        u32::from(self.byte >> self.bits_left) & 1
+    }
+
+    fn bits(&mut self, n: u32) -> u32 {
+        (0..n).fold(0, |acc, _| (acc << 1) | self.bit())
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.bits_left = 0;
+        self.at_marker = false;
+        while self.data.get(self.pos) == Some(&0xff) && self.data.get(self.pos + 1) == Some(&0xff) {
+            self.pos += 1;
+        }
+        match self.data.get(self.pos..self.pos + 2) {
+            Some([0xff, 0xd0..=0xd7]) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err(invalid("missing restart marker")),
+        }
+    }
+}
+
+// The value of `s` bits of magnitude, `v`, as described in F.2.2.1 of the spec.
+fn extend(v: u32, s: u32) -> i32 {
+    if s == 0 {
+        0
+    } else if v < (1 << (s - 1)) {
+        v as i32 - (1 << s) + 1
+    } else {
+        v as i32
+    }
+}
+
+fn read_value(reader: &mut BitReader, s: u8) -> i32 {
+    let s = u32::from(s);
+    extend(reader.bits(s), s)
+}
+
+struct Scan {
+    components: Vec<usize>,
+    dc_tables: Vec<usize>,
+    ac_tables: Vec<usize>,
+    start: usize,
+    end: usize,
+    high: u32,
+    low: u32,
+}
+
+#[derive(Default)]
+struct Tables {
+    dc: [Option<HuffmanTable>; 4],
+    ac: [Option<HuffmanTable>; 4],
+}
+
+impl Tables {
+    fn dc(&self, id: usize) -> Result<&HuffmanTable> {
+        self.dc[id]
+            .as_ref()
+            .ok_or_else(|| invalid("missing Huffman table"))
+    }
+
+    fn ac(&self, id: usize) -> Result<&HuffmanTable> {
+        self.ac[id]
+            .as_ref()
+            .ok_or_else(|| invalid("missing Huffman table"))
+    }
+}
+
+struct ScanDecoder<'a> {
+    scan: &'a Scan,
+    tables: &'a Tables,
+    predictions: Vec<i32>,
+    eob_run: u32,
+}
+
+impl<'a> ScanDecoder<'a> {
+    // Sequential files code the whole block at once.
+    fn sequential(&mut self, r: &mut BitReader, si: usize, block: &mut Block) -> Result<()> {
+        self.dc_first(r, si, block)?;
+        let table = self.tables.ac(self.scan.ac_tables[si])?;
+        let mut k = 1;
+        while k < 64 {
+            let rs = table.decode(r)?;
+            let (run, size) = (usize::from(rs >> 4), rs & 0x0f);
+            if size == 0 {
+                if run != 15 {
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += run;
+            if k > 63 {
+                return Err(invalid("coefficient index out of range"));
+            }
+            block[ZIGZAG[k]] = read_value(r, size) as i16;
+            k += 1;
+        }
+        Ok(())
+    }
+
+    fn dc_first(&mut self, r: &mut BitReader, si: usize, block: &mut Block) -> Result<()> {
+        let size = self.tables.dc(self.scan.dc_tables[si])?.decode(r)?;
+        if size > 11 {
+            return Err(invalid("DC difference too large"));
+        }
+        self.predictions[si] += read_value(r, size);
+        block[0] = (self.predictions[si] << self.scan.low) as i16;
+        Ok(())
+    }
+
+    fn dc_refine(&mut self, r: &mut BitReader, block: &mut Block) {
+        if r.bit() != 0 {
+            block[0] |= 1 << self.scan.low;
+        }
+    }
+
+    fn ac_first(&mut self, r: &mut BitReader, block: &mut Block) -> Result<()> {
+        if self.eob_run > 0 {
+            self.eob_run -= 1;
+            return Ok(());
+        }
+        let table = self.tables.ac(self.scan.ac_tables[0])?;
+        let mut k = self.scan.start;
+        while k <= self.scan.end {
+            let rs = table.decode(r)?;
+            let (run, size) = (u32::from(rs >> 4), rs & 0x0f);
+            if size == 0 {
+                if run != 15 {
+                    self.eob_run = (1 << run) + r.bits(run) - 1;
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += run as usize;
+            if k > 63 {
+                return Err(invalid("coefficient index out of range"));
+            }
+            block[ZIGZAG[k]] = (read_value(r, size) << self.scan.low) as i16;
+            k += 1;
+        }
+        Ok(())
+    }
+
+    // Adds a correction bit to a coefficient that is already non-zero.
+    fn refine(&self, r: &mut BitReader, coefficient: &mut i16) {
+        let bit = 1 << self.scan.low;
+        if r.bit() != 0 && (*coefficient & bit) == 0 {
+            if *coefficient >= 0 {
+                *coefficient += bit;
+            } else {
+                *coefficient -= bit;
+            }
+        }
+    }
+
+    // This follows decode_mcu_AC_refine() in libjpeg.
+    fn ac_refine(&mut self, r: &mut BitReader, block: &mut Block) -> Result<()> {
+        let table = self.tables.ac(self.scan.ac_tables[0])?;
+        let (start, end) = (self.scan.start, self.scan.end);
+        let bit = 1 << self.scan.low;
+        let mut k = start;
+        if self.eob_run == 0 {
+            while k <= end {
+                let rs = table.decode(r)?;
+                let (mut run, size) = (i32::from(rs >> 4), rs & 0x0f);
+                let mut value = 0;
+                if size != 0 {
+                    // New coefficients are always +/- 1 at this bit.
+                    value = if r.bit() != 0 { bit } else { -bit };
+                } else if run != 15 {
+                    self.eob_run = (1 << run) + r.bits(run as u32);
+                    break;
+                }
+                // Skip over `run` zero coefficients, refining the non-zero ones on the way.
+                while k <= end {
+                    let coefficient = &mut block[ZIGZAG[k]];
+                    if *coefficient != 0 {
+                        self.refine(r, coefficient);
+                    } else {
+                        run -= 1;
+                        if run < 0 {
+                            break;
+                        }
+                    }
+                    k += 1;
+                }
+                if value != 0 {
+                    if k > end {
+                        return Err(invalid("coefficient index out of range"));
+                    }
+                    block[ZIGZAG[k]] = value;
+                }
+                k += 1;
+            }
+        }
+        if self.eob_run > 0 {
+            // The rest of the band only has refinements.
+            while k <= end {
+                let coefficient = &mut block[ZIGZAG[k]];
+                if *coefficient != 0 {
+                    self.refine(r, coefficient);
+                }
+                k += 1;
+            }
+            self.eob_run -= 1;
+        }
+        Ok(())
+    }
+}
+
+// The entropy-coded data of a scan runs to the first marker that isn't a restart marker.
+fn scan_data_end(bytes: &[u8], mut pos: usize) -> usize {
+    while pos + 1 < bytes.len() {
+        if bytes[pos] == 0xff {
+            match bytes[pos + 1] {
+                0x00 | 0xd0..=0xd7 | 0xff => {}
+                _ => return pos,
+            }
+        }
+        pos += 1;
+    }
+    bytes.len()
+}
+
+impl Coefficients {
+    pub fn read(bytes: &[u8]) -> Result<Coefficients> {
+        if !bytes.starts_with(&[0xff, 0xd8]) {
+            return Err(invalid("missing SOI marker"));
+        }
+        let mut coefficients = Coefficients {
+            width: 0,
+            height: 0,
+            components: Vec::default(),
+            quant_tables: Default::default(),
+            extra_segments: Vec::default(),
+        };
+        let mut tables = Tables::default();
+        let mut restart_interval = 0;
+        let mut progressive = false;
+        let mut pos = 2;
+        loop {
+            while bytes.get(pos) == Some(&0xff) && bytes.get(pos + 1) == Some(&0xff) {
+                pos += 1;
+            }
+            let marker = match bytes.get(pos..pos + 2) {
+                Some([0xff, marker]) => *marker,
+                _ => return Err(invalid("expected a marker")),
+            };
+            pos += 2;
+            match marker {
+                0xd9 => break,
+                0x01 | 0xd0..=0xd7 => continue,
+                _ => {}
+            }
+            let len = be_u16(bytes, pos)?;
+            let segment = bytes
+                .get(pos + 2..pos + len)
+                .ok_or_else(|| invalid("truncated"))?;
+            pos += len;
+            match marker {
+                0xc0..=0xc2 => {
+                    progressive = marker == 0xc2;
+                    coefficients.read_frame(segment)?;
+                }
+                0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                    return Err(anyhow!(
+                        "unsupported JPEG: lossless, hierarchical, or arithmetic coded"
+                    ))
+                }
+                0xc4 => tables.read(segment)?,
+                0xdb => coefficients.read_quant_tables(segment)?,
+                0xdd => restart_interval = be_u16(segment, 0)?,
+                0xda => {
+                    let scan = coefficients.read_scan_header(segment, progressive)?;
+                    let end = scan_data_end(bytes, pos);
+                    coefficients.decode_scan(&scan, &tables, restart_interval, &bytes[pos..end])?;
+                    pos = end;
+                }
+                0xe0..=0xef | 0xfe => coefficients.extra_segments.push((marker, segment.to_vec())),
+                _ => {}
+            }
+        }
+        if coefficients.components.is_empty() {
+            return Err(invalid("no frame header"));
+        }
+        Ok(coefficients)
+    }
+
+    fn read_frame(&mut self, segment: &[u8]) -> Result<()> {
+        // precision(1), height(2), width(2), count(1), then id(1), sampling(1), table(1) each
+        if segment.first() != Some(&8) {
+            return Err(anyhow!(
+                "unsupported JPEG: only 8-bit samples are supported"
+            ));
+        }
+        self.height = be_u16(segment, 1)?;
+        self.width = be_u16(segment, 3)?;
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow!("unsupported JPEG: height defined by DNL"));
+        }
+        let count = usize::from(*segment.get(5).ok_or_else(|| invalid("truncated"))?);
+        for i in 0..count {
+            let c = segment
+                .get(6 + i * 3..9 + i * 3)
+                .ok_or_else(|| invalid("truncated"))?;
+            let (h, v) = (usize::from(c[1] >> 4), usize::from(c[1] & 0x0f));
+            if !(1..=4).contains(&h) || !(1..=4).contains(&v) || c[2] > 3 {
+                return Err(invalid("bad component"));
+            }
+            self.components.push(Component {
+                id: c[0],
+                h,
+                v,
+                quant_table: usize::from(c[2]),
+                blocks_wide: 0,
+                blocks_high: 0,
+                padded_wide: 0,
+                padded_high: 0,
+                blocks: Vec::default(),
+            });
+        }
+        // Sampling factors mean nothing with only one component. Every MCU is one block.
+        if let [c] = self.components.as_mut_slice() {
+            c.h = 1;
+            c.v = 1;
+        }
+        self.layout();
+        Ok(())
+    }
+
+    fn read_quant_tables(&mut self, segment: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        while pos < segment.len() {
+            let (sixteen_bit, id) = (segment[pos] >> 4 != 0, usize::from(segment[pos] & 0x0f));
+            if id > 3 {
+                return Err(invalid("bad quantization table"));
+            }
+            let size = if sixteen_bit { 2 } else { 1 };
+            let data = segment
+                .get(pos + 1..pos + 1 + 64 * size)
+                .ok_or_else(|| invalid("truncated"))?;
+            let mut values = [0; 64];
+            for (k, natural) in ZIGZAG.iter().enumerate() {
+                values[*natural] = if sixteen_bit {
+                    u16::from_be_bytes([data[2 * k], data[2 * k + 1]])
+                } else {
+                    u16::from(data[k])
+                };
+            }
+            self.quant_tables[id] = Some(QuantTable {
+                sixteen_bit,
+                values,
+            });
+            pos += 1 + 64 * size;
+        }
+        Ok(())
+    }
+
+    fn read_scan_header(&self, segment: &[u8], progressive: bool) -> Result<Scan> {
+        let count = usize::from(*segment.first().ok_or_else(|| invalid("truncated"))?);
+        let mut scan = Scan {
+            components: Vec::default(),
+            dc_tables: Vec::default(),
+            ac_tables: Vec::default(),
+            start: 0,
+            end: 63,
+            high: 0,
+            low: 0,
+        };
+        for i in 0..count {
+            let c = segment
+                .get(1 + i * 2..3 + i * 2)
+                .ok_or_else(|| invalid("truncated"))?;
+            let index = self
+                .components
+                .iter()
+                .position(|comp| comp.id == c[0])
+                .ok_or_else(|| invalid("scan of an unknown component"))?;
+            scan.components.push(index);
+            scan.dc_tables.push(usize::from(c[1] >> 4) & 3);
+            scan.ac_tables.push(usize::from(c[1] & 0x0f) & 3);
+        }
+        let rest = segment
+            .get(1 + count * 2..4 + count * 2)
+            .ok_or_else(|| invalid("truncated"))?;
+        if progressive {
+            scan.start = usize::from(rest[0]);
+            scan.end = usize::from(rest[1]);
+            scan.high = u32::from(rest[2] >> 4);
+            scan.low = u32::from(rest[2] & 0x0f);
+            let bad = scan.end > 63
+                || scan.start > scan.end
+                || scan.low > 13
+                || (scan.start == 0 && scan.end != 0)
+                || (scan.start > 0 && scan.components.len() != 1);
+            if bad {
+                return Err(invalid("bad progressive scan"));
+            }
+        }
+        Ok(scan)
+    }
+
+    fn decode_scan(
+        &mut self,
+        scan: &Scan,
+        tables: &Tables,
+        restart_interval: usize,
+        data: &[u8],
+    ) -> Result<()> {
+        if self.components.is_empty() {
+            return Err(invalid("scan before frame header"));
+        }
+        let mut reader = BitReader::new(data);
+        let mut decoder = ScanDecoder {
+            scan,
+            tables,
+            predictions: vec![0; scan.components.len()],
+            eob_run: 0,
+        };
+        let mut mcu = 0;
+        for (ci, x, y, starts_mcu) in self.scan_order(&scan.components) {
+            if starts_mcu {
+                if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                    reader.restart()?;
+                    decoder.predictions.iter_mut().for_each(|p| *p = 0);
+                    decoder.eob_run = 0;
+                }
+                mcu += 1;
+            }
+            let si = scan
+                .components
+                .iter()
+                .position(|c| *c == ci)
+                .unwrap_or_default();
+            let block = self.components[ci].block_mut(x, y);
+            match (scan.start, scan.high) {
+                (0, 0) if scan.end == 63 => decoder.sequential(&mut reader, si, block)?,
+                (0, 0) => decoder.dc_first(&mut reader, si, block)?,
+                (0, _) => decoder.dc_refine(&mut reader, block),
+                (_, 0) => decoder.ac_first(&mut reader, block)?,
+                (_, _) => decoder.ac_refine(&mut reader, block)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Tables {
+    fn read(&mut self, segment: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        while pos < segment.len() {
+            let (class, id) = (segment[pos] >> 4, usize::from(segment[pos] & 0x0f));
+            let counts = segment
+                .get(pos + 1..pos + 17)
+                .ok_or_else(|| invalid("truncated"))?;
+            let total: usize = counts.iter().map(|c| usize::from(*c)).sum();
+            let values = segment
+                .get(pos + 17..pos + 17 + total)
+                .ok_or_else(|| invalid("truncated"))?;
+            if id > 3 || class > 1 {
+                return Err(invalid("bad Huffman table"));
+            }
+            let table = Some(HuffmanTable::new(counts, values));
+            if class == 0 {
+                self.dc[id] = table;
+            } else {
+                self.ac[id] = table;
+            }
+            pos += 17 + total;
+        }
+        Ok(())
+    }
+}
+
+//
+// Writing
+//
+
+// The symbol counts for one Huffman table.
+type Frequencies = [u32; 256];
+
+struct HuffmanCode {
+    // The counts of codes of each length, 1 to 16, and the symbols in code order, as they go
+    // in a DHT segment.
+    counts: [u8; 16],
+    symbols: Vec<u8>,
+    // The code and its length for each symbol.
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanCode {
+    // Builds a code from the symbol frequencies, limited to 16 bits, with no code of all ones.
+    // This follows jpeg_gen_optimal_table() in libjpeg.
+    fn optimal(frequencies: &Frequencies) -> HuffmanCode {
+        let mut freq = [0u64; 257];
+        for (f, count) in freq.iter_mut().zip(frequencies.iter()) {
+            *f = u64::from(*count);
+        }
+        // A reserved symbol, so that no real symbol gets the code of all ones.
+        freq[256] = 1;
+        let mut code_size = [0usize; 257];
+        let mut others = [None; 257];
+
+        loop {
+            // The two least frequent symbols. Ties go to the larger symbol.
+            let mut c1: Option<usize> = None;
+            let mut c2: Option<usize> = None;
+            for i in 0..257 {
+                if freq[i] == 0 {
+                    continue;
+                }
+                if c1.is_none_or(|c| freq[i] <= freq[c]) {
+                    c2 = c1;
+                    c1 = Some(i);
+                } else if c2.is_none_or(|c| freq[i] <= freq[c]) {
+                    c2 = Some(i);
+                }
+            }
+            let (mut c1, mut c2) = match (c1, c2) {
+                (Some(c1), Some(c2)) => (c1, c2),
+                _ => break,
+            };
+            freq[c1] += freq[c2];
+            freq[c2] = 0;
+            code_size[c1] += 1;
+            while let Some(next) = others[c1] {
+                c1 = next;
+                code_size[c1] += 1;
+            }
+            others[c1] = Some(c2);
+            code_size[c2] += 1;
+            while let Some(next) = others[c2] {
+                c2 = next;
+                code_size[c2] += 1;
+            }
+        }
+
+        let mut bits = [0usize; 33];
+        for size in code_size.iter().filter(|s| **s > 0) {
+            bits[(*size).min(32)] += 1;
+        }
+        // Move codes that are too long up the tree, two at a time.
+        for i in (17..=32).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        // Drop the reserved symbol, which has one of the longest codes.
+        let mut longest = 16;
+        while bits[longest] == 0 {
+            longest -= 1;
+        }
+        bits[longest] -= 1;
+
+        let mut symbols = Vec::default();
+        for size in 1..=32 {
+            for (symbol, s) in code_size.iter().enumerate().take(256) {
+                if *s == size {
+                    symbols.push(symbol as u8);
+                }
+            }
+        }
+        let mut counts = [0; 16];
+        for (count, b) in counts.iter_mut().zip(bits[1..=16].iter()) {
+            *count = *b as u8;
+        }
+        HuffmanCode::from_counts(counts, symbols)
+    }
+
+    fn from_counts(counts: [u8; 16], symbols: Vec<u8>) -> HuffmanCode {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut k = 0;
+        for (len, count) in counts.iter().enumerate() {
+            for _ in 0..*count {
+                codes[usize::from(symbols[k])] = (code, len as u8 + 1);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanCode {
+            counts,
+            symbols,
+            codes,
+        }
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        for i in (0..len).rev() {
+            self.acc = (self.acc << 1) | ((value >> i) & 1);
+            self.bits += 1;
+            if self.bits == 8 {
+                self.flush_byte();
+            }
+        }
+    }
+
+    fn flush_byte(&mut self) {
+        let byte = self.acc as u8;
+        self.bytes.push(byte);
+        if byte == 0xff {
+            self.bytes.push(0);
+        }
+        self.acc = 0;
+        self.bits = 0;
+    }
+
+    // Pads the last byte with ones.
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.write(0x7f, 8 - self.bits);
+        }
+        self.bytes
+    }
+}
+
+// The number of bits in the magnitude of `value`, and those bits, coded as in F.1.2.1.
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+    (size as u8, (bits as u32) & ((1 << size) - 1))
+}
+
+// A symbol to code in one of the tables (0 for DC, 1 for AC), followed by extra bits.
+struct Symbol {
+    class: usize,
+    table: usize,
+    symbol: u8,
+    extra: u32,
+    extra_len: u8,
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) -> Result<()> {
+    if data.len() + 2 > 0xffff {
+        return Err(anyhow!("JPEG segment too large"));
+    }
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&((data.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+impl Coefficients {
+    // The luminance component gets the first pair of tables, and the others share the second.
+    fn table_for(ci: usize) -> usize {
+        ci.min(1)
+    }
+
+    // Calls `f` with every symbol of a single sequential scan of all of the components.
+    fn for_each_symbol<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(Symbol),
+    {
+        let all: Vec<usize> = (0..self.components.len()).collect();
+        let mut predictions = vec![0i32; self.components.len()];
+        for (ci, x, y, _) in self.scan_order(&all) {
+            let table = Coefficients::table_for(ci);
+            let block = self.components[ci].block(x, y);
+
+            let dc = i32::from(block[0]);
+            let (size, extra) = magnitude(dc - predictions[ci]);
+            predictions[ci] = dc;
+            if size > 11 {
+                return Err(anyhow!("DC coefficient out of range"));
+            }
+            f(Symbol {
+                class: 0,
+                table,
+                symbol: size,
+                extra,
+                extra_len: size,
+            });
+
+            let mut run = 0;
+            for natural in &ZIGZAG[1..] {
+                let value = i32::from(block[*natural]);
+                if value == 0 {
+                    run += 1;
+                    continue;
+                }
+                while run > 15 {
+                    f(Symbol {
+                        class: 1,
+                        table,
+                        symbol: 0xf0,
+                        extra: 0,
+                        extra_len: 0,
+                    });
+                    run -= 16;
+                }
+                let (size, extra) = magnitude(value);
+                if size > 10 {
+                    return Err(anyhow!("AC coefficient out of range"));
+                }
+                f(Symbol {
+                    class: 1,
+                    table,
+                    symbol: (run << 4) | size,
+                    extra,
+                    extra_len: size,
+                });
+                run = 0;
+            }
+            if run > 0 {
+                // End of block.
+                f(Symbol {
+                    class: 1,
+                    table,
+                    symbol: 0,
+                    extra: 0,
+                    extra_len: 0,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the coefficients as a baseline JPEG, with the extra segments first.
+    pub fn write(&self) -> Result<Vec<u8>> {
+        let mut frequencies = [[[0u32; 256]; 2]; 2];
+        self.for_each_symbol(|s| frequencies[s.class][s.table][usize::from(s.symbol)] += 1)?;
+        let table_count = if self.components.len() > 1 { 2 } else { 1 };
+        let codes: Vec<Vec<HuffmanCode>> = frequencies
+            .iter()
+            .map(|class| {
+                class[..table_count]
+                    .iter()
+                    .map(HuffmanCode::optimal)
+                    .collect()
+            })
+            .collect();
+
+        let mut out = vec![0xff, 0xd8];
+        for (marker, data) in &self.extra_segments {
+            write_segment(&mut out, *marker, data)?;
+        }
+
+        let mut sixteen_bit = false;
+        for (id, table) in self.quant_tables.iter().enumerate() {
+            if let Some(table) = table {
+                if !self.components.iter().any(|c| c.quant_table == id) {
+                    continue;
+                }
+                sixteen_bit |= table.sixteen_bit;
+                let mut data = vec![(u8::from(table.sixteen_bit) << 4) | id as u8];
+                for natural in &ZIGZAG {
+                    let value = table.values[*natural];
+                    if table.sixteen_bit {
+                        data.extend_from_slice(&value.to_be_bytes());
+                    } else {
+                        data.push(value as u8);
+                    }
+                }
+                write_segment(&mut out, 0xdb, &data)?;
+            }
+        }
+
+        // Baseline doesn't allow 16-bit quantization tables, but extended sequential does.
+        let mut frame = vec![8];
+        frame.extend_from_slice(&(self.height as u16).to_be_bytes());
+        frame.extend_from_slice(&(self.width as u16).to_be_bytes());
+        frame.push(self.components.len() as u8);
+        for c in &self.components {
+            frame.extend_from_slice(&[c.id, ((c.h << 4) | c.v) as u8, c.quant_table as u8]);
+        }
+        write_segment(&mut out, if sixteen_bit { 0xc1 } else { 0xc0 }, &frame)?;
+
+        for (class, tables) in codes.iter().enumerate() {
+            for (id, code) in tables.iter().enumerate() {
+                let mut data = vec![((class << 4) | id) as u8];
+                data.extend_from_slice(&code.counts);
+                data.extend_from_slice(&code.symbols);
+                write_segment(&mut out, 0xc4, &data)?;
+            }
+        }
+
+        let mut scan = vec![self.components.len() as u8];
+        for (ci, c) in self.components.iter().enumerate() {
+            let table = Coefficients::table_for(ci) as u8;
+            scan.extend_from_slice(&[c.id, (table << 4) | table]);
+        }
+        scan.extend_from_slice(&[0, 63, 0]);
+        write_segment(&mut out, 0xda, &scan)?;
+
+        let mut writer = BitWriter {
+            bytes: out,
+            acc: 0,
+            bits: 0,
+        };
+        self.for_each_symbol(|s| {
+            let (code, len) = codes[s.class][s.table].codes[usize::from(s.symbol)];
+            writer.write(u32::from(code), u32::from(len));
+            writer.write(s.extra, u32::from(s.extra_len));
+        })?;
+        let mut out = writer.finish();
+        out.extend_from_slice(&[0xff, 0xd9]);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewriting_keeps_the_pixels() {
+        let bytes = std::fs::read("test_images/test.jpg").unwrap();
+        let written = Coefficients::read(&bytes).unwrap().write().unwrap();
+        let before = image::load_from_memory(&bytes).unwrap().to_rgb8();
+        let after = image::load_from_memory(&written).unwrap().to_rgb8();
+        assert_eq!(before, after);
+    }
+
+    // progressive.jpg has spectral selection and successive approximation scans, so it goes
+    // through dc_first, dc_refine, ac_first, and ac_refine.
+    #[test]
+    fn progressive_is_rewritten_as_baseline() {
+        let bytes = std::fs::read("test_images/progressive.jpg").unwrap();
+        let coefficients = Coefficients::read(&bytes).unwrap();
+        assert_eq!((32, 23), (coefficients.width, coefficients.height));
+        let written = coefficients.write().unwrap();
+        assert!(written.windows(2).any(|w| w == [0xff, 0xc0]));
+
+        let before = image::load_from_memory(&bytes).unwrap().to_rgb8();
+        let after = image::load_from_memory(&written).unwrap().to_rgb8();
+        assert_eq!(before, after);
+
+        // And reading the baseline copy back gives the same coefficients.
+        let reread = Coefficients::read(&written).unwrap();
+        for (a, b) in coefficients.components.iter().zip(&reread.components) {
+            assert_eq!(a.blocks, b.blocks);
+        }
+    }
+
+    #[test]
+    fn truncated_jpeg_is_an_error() {
+        let bytes = std::fs::read("test_images/test.jpg").unwrap();
+        assert!(Coefficients::read(&bytes[..bytes.len() / 2]).is_err());
+    }
+}
@@ -1,11 +1,37 @@
 mod addext;
+mod archive;
+mod autorotate;
+mod catalog;
+mod checknew;
 mod command;
+mod convert;
 mod crawler;
+mod crawlerrors;
+mod crawloptions;
+mod decisions;
 mod direntryutil;
+mod dupreport;
+mod exif;
 mod filer;
 mod finddups;
 mod findneardups;
+mod formats;
+mod gc;
+mod hashers;
+mod identify;
+mod image_metadata;
 mod image_type;
+mod inventory;
+mod journal;
+mod jpegcoefs;
+mod moved;
+mod progress;
+mod query;
+mod serve;
+mod stripmeta;
+mod thumbs;
+mod watch;
 
 pub use crate::imt::command::{process_command, Command};
-pub use crate::imt::filer::Filer;
+pub use crate::imt::crawlerrors::CrawlFailed;
+pub use crate::imt::filer::{Filer, CATALOG_PATH};
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::imt::direntryutil::is_missing;
+use crate::imt::filer::{FileStat, Filer};
+
+/// Notices when a file that we haven't seen before is really a catalog entry that was moved
+/// or renamed, so that we can keep its cached data instead of recomputing it.
+pub struct MoveDetector {
+    by_inode: HashMap<(u64, u64), PathBuf>,
+    by_size_and_mtime: HashMap<(u64, u64), Vec<PathBuf>>,
+}
+
+impl MoveDetector {
+    /// Index the catalog as it is before the crawl starts.
+    pub fn new(filer: &Filer) -> MoveDetector {
+        let mut by_inode = HashMap::new();
+        let mut by_size_and_mtime: HashMap<(u64, u64), Vec<PathBuf>> = HashMap::new();
+        filer.with_files(|p| {
+            if let Some(stat) = filer.stat(p) {
+                if let (Some(device), Some(inode)) = (stat.device, stat.inode) {
+                    by_inode.insert((device, inode), p.clone());
+                }
+                if let (Some(size), Some(mtime)) = (stat.size, stat.mtime) {
+                    by_size_and_mtime
+                        .entry((size, mtime))
+                        .or_default()
+                        .push(p.clone());
+                }
+            }
+        });
+        MoveDetector {
+            by_inode,
+            by_size_and_mtime,
+        }
+    }
+
+    /// Record `path` in the catalog with its current `stat`. If `path` is new, and a vanished
+    /// entry had the same device, inode, size, and mod time, then that entry is moved to `path`.
+    /// Returns the old path if there was a move.
+    pub fn add_file(&self, filer: &Filer, path: &Path, stat: FileStat) -> Option<PathBuf> {
+        let is_new = !filer.contains_file(path);
+        filer.set_stat(path, stat);
+        if !is_new {
+            return None;
+        }
+
+        let old = self.by_inode.get(&(stat.device?, stat.inode?))?;
+        // The inode may have been reused by a different file, so check that it looks the same.
+        if filer.stat(old)?.same_size_and_mtime(&stat) {
+            carry_over(filer, old, path)
+        } else {
+            None
+        }
+    }
+
+    /// Look for a vanished entry with the same size, mod time, and hash as `path`, and move its
+    /// cached data to `path`. This catches moves across filesystems, where the inode changes.
+    pub fn add_hash(
+        &self,
+        filer: &Filer,
+        path: &Path,
+        stat: &FileStat,
+        hash_name: &str,
+        hash_value: &str,
+    ) -> Option<PathBuf> {
+        let candidates = self.by_size_and_mtime.get(&(stat.size?, stat.mtime?))?;
+        let old = candidates.iter().find(|c| {
+            c.as_path() != path
+                && filer.hash_value(c.as_path(), hash_name).as_deref() == Some(hash_value)
+                && is_missing(c)
+        })?;
+        carry_over(filer, old, path)
+    }
+}
+
+fn carry_over(filer: &Filer, old: &Path, new: &Path) -> Option<PathBuf> {
+    if old == new || !is_missing(old) {
+        return None;
+    }
+    if filer.move_file(old, new) {
+        info!("{} was moved to {}.", old.display(), new.display());
+        Some(old.to_path_buf())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // A catalog with one hashed file, `old`, in a new temp dir.
+    fn catalog_with(name: &str) -> (TempDir, Filer, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let old = dir.path().join(name);
+        fs::write(&old, "contents").unwrap();
+        let filer = Filer::new().unwrap();
+        filer.set_stat(&old, FileStat::from_path(&old).unwrap());
+        filer.add_hash(&old, "SHA256", "abc").unwrap();
+        (dir, filer, old)
+    }
+
+    #[test]
+    fn rename_keeps_cached_data() {
+        let (dir, filer, old) = catalog_with("old");
+        let new = dir.path().join("new");
+        fs::rename(&old, &new).unwrap();
+
+        let moves = MoveDetector::new(&filer);
+        let moved = moves.add_file(&filer, &new, FileStat::from_path(&new).unwrap());
+        assert_eq!(moved, Some(old.clone()));
+        assert!(!filer.contains_file(&old));
+        assert_eq!(filer.hash_value(&new, "SHA256").as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn reused_inode_is_not_a_move() {
+        let (dir, filer, old) = catalog_with("old");
+        let new = dir.path().join("new");
+        fs::rename(&old, &new).unwrap();
+        // The same inode, but the file has changed since it was cataloged.
+        let mut stat = filer.stat(&old).unwrap();
+        stat.size = Some(1);
+        filer.set_stat(&old, stat);
+
+        let moves = MoveDetector::new(&filer);
+        let moved = moves.add_file(&filer, &new, FileStat::from_path(&new).unwrap());
+        assert_eq!(moved, None);
+        assert!(filer.contains_file(&old));
+        assert!(!filer.contains_hash(&new, "SHA256"));
+    }
+
+    #[test]
+    fn existing_file_is_not_a_move() {
+        let (dir, filer, old) = catalog_with("old");
+        let copy = dir.path().join("copy");
+        fs::copy(&old, &copy).unwrap();
+
+        let moves = MoveDetector::new(&filer);
+        let stat = FileStat::from_path(&copy).unwrap();
+        assert_eq!(moves.add_file(&filer, &copy, stat), None);
+        assert_eq!(moves.add_hash(&filer, &copy, &stat, "SHA256", "abc"), None);
+        assert!(filer.contains_file(&old));
+    }
+
+    #[test]
+    fn move_across_filesystems_is_found_by_hash() {
+        let (dir, filer, old) = catalog_with("old");
+        let new = dir.path().join("new");
+        fs::rename(&old, &new).unwrap();
+        // As if the file had been copied to another filesystem, with a new inode.
+        let mut stat = filer.stat(&old).unwrap();
+        stat.inode = stat.inode.map(|inode| inode + 1);
+        filer.set_stat(&old, stat);
+
+        let moves = MoveDetector::new(&filer);
+        let new_stat = FileStat::from_path(&new).unwrap();
+        assert_eq!(moves.add_file(&filer, &new, new_stat), None);
+        let moved = moves.add_hash(&filer, &new, &new_stat, "SHA256", "abc");
+        assert_eq!(moved, Some(old.clone()));
+        assert!(!filer.contains_file(&old));
+    }
+}
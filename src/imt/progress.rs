@@ -0,0 +1,101 @@
+use std::cell::Cell;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+// How often to update the status line on a terminal.
+const LIVE_INTERVAL: Duration = Duration::from_millis(250);
+// How often to log progress when stderr is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Counts what a crawl has done so far and reports it periodically. On a terminal, this is a
+/// status line on stderr that is rewritten in place. Otherwise, it is an occasional log line.
+pub struct Progress {
+    live: bool,
+    start: Instant,
+    last_report: Cell<Instant>,
+
+    files_seen: Cell<u64>,
+    files_processed: Cell<u64>,
+    bytes_read: Cell<u64>,
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        let now = Instant::now();
+        Progress {
+            live: std::io::stderr().is_terminal(),
+            start: now,
+            last_report: Cell::new(now),
+            files_seen: Cell::default(),
+            files_processed: Cell::default(),
+            bytes_read: Cell::default(),
+        }
+    }
+
+    pub fn file_seen(&self) {
+        self.files_seen.set(self.files_seen.get() + 1);
+        self.maybe_report();
+    }
+
+    pub fn file_processed(&self) {
+        self.files_processed.set(self.files_processed.get() + 1);
+        self.maybe_report();
+    }
+
+    pub fn add_bytes(&self, n: u64) {
+        self.bytes_read.set(self.bytes_read.get() + n);
+        self.maybe_report();
+    }
+
+    /// Print the final counts, and leave the terminal on a fresh line.
+    pub fn finish(&self) {
+        if self.live {
+            eprintln!("\r{}\x1b[K", self.status());
+        } else {
+            info!("{}", self.status());
+        }
+    }
+
+    fn maybe_report(&self) {
+        let interval = if self.live {
+            LIVE_INTERVAL
+        } else {
+            LOG_INTERVAL
+        };
+        let now = Instant::now();
+        if now.duration_since(self.last_report.get()) < interval {
+            return;
+        }
+        self.last_report.set(now);
+
+        if self.live {
+            eprint!("\r{}\x1b[K", self.status());
+            // Nothing useful to do if stderr is gone.
+            let _ = std::io::stderr().flush();
+        } else {
+            info!("{}", self.status());
+        }
+    }
+
+    fn status(&self) -> String {
+        let secs = self.start.elapsed().as_secs_f64().max(0.001);
+        let processed = self.files_processed.get();
+        let megabytes = self.bytes_read.get() as f64 / (1024.0 * 1024.0);
+        format!(
+            "{} files seen, {} processed, {:.1} MB read ({:.1} files/s, {:.1} MB/s)",
+            self.files_seen.get(),
+            processed,
+            megabytes,
+            processed as f64 / secs,
+            megabytes / secs
+        )
+    }
+}
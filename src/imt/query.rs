@@ -0,0 +1,219 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use globset::{Glob, GlobMatcher};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::imt::filer::{encode_path, FileInfo, Filer};
+use crate::imt::image_type::ImageType;
+
+/// Print the catalog entries that match all of the filters.
+#[derive(StructOpt, Debug)]
+pub struct Query {
+    /// Only files whose path matches this glob.
+    #[structopt(long)]
+    glob: Option<String>,
+
+    /// Only files of this type (jpeg, gif, png, webp, or unknown).
+    #[structopt(long = "type")]
+    image_type: Option<ImageType>,
+
+    /// Only files that have a hash with this name.
+    #[structopt(long)]
+    has_hash: Option<String>,
+
+    /// Only files that don't have a hash with this name.
+    #[structopt(long)]
+    missing_hash: Option<String>,
+
+    /// Only files of at least this many bytes.
+    #[structopt(long)]
+    min_size: Option<u64>,
+
+    /// Only files of at most this many bytes.
+    #[structopt(long)]
+    max_size: Option<u64>,
+
+    /// Only images at least this wide.
+    #[structopt(long)]
+    min_width: Option<u32>,
+
+    /// Only images at most this wide.
+    #[structopt(long)]
+    max_width: Option<u32>,
+
+    /// Only images at least this tall.
+    #[structopt(long)]
+    min_height: Option<u32>,
+
+    /// Only images at most this tall.
+    #[structopt(long)]
+    max_height: Option<u32>,
+
+    /// Only files modified on or after this date (YYYY-MM-DD, UTC).
+    #[structopt(long, parse(try_from_str = parse_date))]
+    after: Option<u64>,
+
+    /// Only files modified before this date (YYYY-MM-DD, UTC).
+    #[structopt(long, parse(try_from_str = parse_date))]
+    before: Option<u64>,
+
+    /// Print the full records as JSON, one per line.
+    #[structopt(long)]
+    json: bool,
+}
+
+// Returns the start of the day as seconds since the epoch, to compare against mtimes.
+fn parse_date(s: &str) -> Result<u64> {
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")?;
+    let secs = date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+    Ok(secs.max(0) as u64)
+}
+
+fn in_range<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    if min.is_none() && max.is_none() {
+        return true;
+    }
+    // Files we know nothing about can't match a range.
+    match value {
+        None => false,
+        Some(v) => min.is_none_or(|m| v >= m) && max.is_none_or(|m| v <= m),
+    }
+}
+
+impl Query {
+    pub fn glob_matcher(&self) -> Result<Option<GlobMatcher>> {
+        Ok(match &self.glob {
+            Some(g) => Some(Glob::new(g)?.compile_matcher()),
+            None => None,
+        })
+    }
+
+    pub fn matches(&self, glob: Option<&GlobMatcher>, path: &Path, info: &FileInfo) -> bool {
+        let stat = info.stat();
+        let dimensions = info.dimensions();
+        glob.is_none_or(|g| g.is_match(path))
+            && self
+                .image_type
+                .is_none_or(|it| info.image_type() == Some(it))
+            && self.has_hash.as_ref().is_none_or(|h| info.contains_hash(h))
+            && self
+                .missing_hash
+                .as_ref()
+                .is_none_or(|h| !info.contains_hash(h))
+            && in_range(stat.size, self.min_size, self.max_size)
+            && in_range(dimensions.map(|d| d.0), self.min_width, self.max_width)
+            && in_range(dimensions.map(|d| d.1), self.min_height, self.max_height)
+            && in_range(stat.mtime, self.after, None)
+            && self
+                .before
+                .is_none_or(|b| stat.mtime.is_some_and(|m| m < b))
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    // Escaped the same way as in the catalog, since JSON strings must be UTF-8.
+    path: String,
+    #[serde(flatten)]
+    info: &'a FileInfo,
+}
+
+pub fn process_query(q: &Query, filer: &Filer) -> Result<()> {
+    let glob = q.glob_matcher()?;
+
+    // Sort by path so that the output is stable from run to run.
+    let mut lines: Vec<(PathBuf, String)> = Vec::default();
+    let mut result = Ok(());
+    filer.with_file_infos(|path, info| {
+        if result.is_err() || !q.matches(glob.as_ref(), path, info) {
+            return;
+        }
+        if q.json {
+            match serde_json::to_string(&Record {
+                path: encode_path(path),
+                info,
+            }) {
+                Ok(json) => lines.push((path.clone(), json)),
+                Err(err) => result = Err(err),
+            }
+        } else {
+            lines.push((path.clone(), path.display().to_string()));
+        }
+    });
+    result?;
+
+    lines.sort();
+    for (_, line) in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::imt::filer::{FileStat, Files};
+
+    #[test_case(None, None, None, true ; "no range")]
+    #[test_case(None, Some(1), None, false ; "unknown value")]
+    #[test_case(Some(5), Some(5), Some(5), true ; "inclusive")]
+    #[test_case(Some(4), Some(5), None, false ; "below")]
+    #[test_case(Some(6), None, Some(5), false ; "above")]
+    fn ranges(value: Option<u64>, min: Option<u64>, max: Option<u64>, expected: bool) {
+        assert_eq!(in_range(value, min, max), expected);
+    }
+
+    // One 800x600 JPEG, 1000 bytes, modified at the start of 2020-06-01, with a SHA256.
+    fn catalog() -> Files {
+        let mut files = Files::default();
+        let path = "photos/a.jpg";
+        files.set_stat(
+            path,
+            FileStat {
+                size: Some(1000),
+                mtime: Some(parse_date("2020-06-01").unwrap()),
+                ..FileStat::default()
+            },
+        );
+        files.set_image_type(path, ImageType::JPEG);
+        files.set_dimensions(path, 800, 600);
+        files.add_hash(path, "SHA256", "abc").unwrap();
+        files
+    }
+
+    #[test_case(&[] ; "everything")]
+    #[test_case(&["--glob", "photos/*.jpg"] ; "glob")]
+    #[test_case(&["--type", "jpeg"] ; "image type")]
+    #[test_case(&["--has-hash", "SHA256", "--missing-hash", "BLAKE3"] ; "hashes")]
+    #[test_case(&["--min-size", "1000", "--max-size", "1000"] ; "size")]
+    #[test_case(&["--min-width", "800", "--max-height", "600"] ; "dimensions")]
+    #[test_case(&["--after", "2020-06-01", "--before", "2020-06-02"] ; "dates")]
+    fn matching(args: &[&str]) {
+        assert!(run(args));
+    }
+
+    #[test_case(&["--glob", "*.png"] ; "glob")]
+    #[test_case(&["--type", "webp"] ; "image type")]
+    #[test_case(&["--has-hash", "BLAKE3"] ; "has hash")]
+    #[test_case(&["--missing-hash", "SHA256"] ; "missing hash")]
+    #[test_case(&["--min-size", "1001"] ; "size")]
+    #[test_case(&["--max-width", "799"] ; "width")]
+    #[test_case(&["--before", "2020-06-01"] ; "before is exclusive")]
+    #[test_case(&["--after", "2020-06-02"] ; "after")]
+    fn not_matching(args: &[&str]) {
+        assert!(!run(args));
+    }
+
+    fn run(args: &[&str]) -> bool {
+        let query = Query::from_iter_safe(["query"].iter().chain(args)).unwrap();
+        let glob = query.glob_matcher().unwrap();
+        let files = catalog();
+        let (path, info) = files.info_iter().next().unwrap();
+        query.matches(glob.as_ref(), path, info)
+    }
+}
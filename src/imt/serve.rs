@@ -0,0 +1,730 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+use structopt::StructOpt;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::imt::archive::archive_of;
+use crate::imt::decisions::delete_duplicates;
+use crate::imt::dupreport::{describe, escape_html, proposed_keeper};
+use crate::imt::filer::{decode_path, encode_path, Filer, CATALOG_PATH};
+use crate::imt::finddups::{duplicate_groups, HASH_NAME};
+use crate::imt::hashers::default_hash_algorithm;
+use crate::imt::image_type::{ImageType, FORMATS};
+use crate::imt::query::Query;
+use crate::imt::thumbs::thumbnail_png;
+
+/// Browse and curate the catalog in a web browser. The server only listens on 127.0.0.1, and
+/// everything it needs is built in.
+#[derive(StructOpt, Debug)]
+pub struct Serve {
+    /// The port to listen on.
+    #[structopt(long, default_value = "8080")]
+    port: u16,
+
+    /// Only show what deleting the marked files would do. No files are changed.
+    #[structopt(short = "n", long)]
+    dry_run: bool,
+
+    /// Trust that files with the same SHA256 are identical, instead of comparing them byte for
+    /// byte before deleting.
+    #[structopt(long)]
+    trust_hashes: bool,
+}
+
+const THUMBNAIL_SIZE: u32 = 160;
+
+// More files than this in one directory are cut off, to keep the page usable.
+const MAX_FILES: usize = 500;
+
+// How much memory the thumbnails that have been made are allowed to take up.
+const MAX_THUMBNAIL_BYTES: usize = 64 << 20;
+
+// How often to check for Ctrl-C while waiting for requests.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// The query filters that can be given in the URL, which are the same as the 'query' flags.
+const FILTERS: &[(&str, &str)] = &[
+    ("type", "Type"),
+    ("glob", "Path glob"),
+    ("min-width", "Min width"),
+    ("max-width", "Max width"),
+    ("min-height", "Min height"),
+    ("max-height", "Max height"),
+    ("min-size", "Min bytes"),
+    ("max-size", "Max bytes"),
+    ("after", "After (YYYY-MM-DD)"),
+    ("before", "Before (YYYY-MM-DD)"),
+];
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1em 2em; }
+nav a { margin-right: 1em; }
+section { border-top: 1px solid #ccc; padding: 0.5em 0; }
+.files { display: flex; flex-wrap: wrap; gap: 1em; }
+.file { width: 180px; padding: 0.5em; border: 2px solid #ddd; border-radius: 4px; }
+.file.keeper { border-color: #2a2; background: #efe; }
+.file img { max-width: 160px; max-height: 160px; display: block; margin: auto; }
+.path { font-family: monospace; font-size: 0.8em; word-break: break-all; }
+.details { font-size: 0.8em; color: #555; }
+.filters label { margin-right: 0.5em; font-size: 0.9em; }
+.filters input { width: 7em; }
+.actions { position: sticky; top: 0; background: white; padding: 0.5em 0; }
+.error { color: #a00; }
+"#;
+
+// Percent-encodes everything but unreserved characters, for query strings.
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // from_str_radix would also take a sign, as in "%+1".
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (b, _) => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// The name/value pairs of a query string or form body, in order.
+fn parse_params(s: &str) -> Vec<(String, String)> {
+    s.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (url_decode(name), url_decode(value)),
+            None => (url_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn path_param(params: &[(String, String)], name: &str) -> Option<PathBuf> {
+    param(params, name).map(decode_path)
+}
+
+// A link to `page` with `path` as the "path" (or "dir") parameter, and the filters.
+fn link(page: &str, name: &str, path: &Path, filters: &str) -> String {
+    format!(
+        "{}?{}={}{}",
+        page,
+        name,
+        url_encode(&encode_path(path)),
+        filters
+    )
+}
+
+// The filters in `params` as a Query, by handing them to the same parser as 'imt2 query'.
+fn query_from_params(params: &[(String, String)]) -> Result<Query> {
+    let mut args = vec!["query".to_string()];
+    for (name, _) in FILTERS {
+        if let Some(value) = param(params, name).filter(|v| !v.is_empty()) {
+            args.push(format!("--{}", name));
+            args.push(value.to_string());
+        }
+    }
+    Query::from_iter_safe(args).map_err(|err| anyhow!(err.message))
+}
+
+// The filters in `params`, ready to add to a link.
+fn filter_string(params: &[(String, String)]) -> String {
+    let mut s = String::new();
+    for (name, _) in FILTERS {
+        if let Some(value) = param(params, name).filter(|v| !v.is_empty()) {
+            let _ = write!(s, "&{}={}", name, url_encode(value));
+        }
+    }
+    s
+}
+
+fn html_response(body: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body).with_header(content_type("text/html; charset=utf-8"))
+}
+
+fn content_type(value: &str) -> Header {
+    // unwrap: the name and value are always valid header text.
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+fn not_found() -> Response<std::io::Cursor<Vec<u8>>> {
+    html_response(page("Not found", "<p>Not found.</p>")).with_status_code(404)
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        concat!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>",
+            "<style>{style}</style></head>\n<body>\n",
+            "<nav><a href=\"/browse\">Browse</a><a href=\"/dups\">Duplicates</a></nav>\n",
+            "<h1>{title}</h1>\n{body}</body></html>\n"
+        ),
+        title = escape_html(title),
+        style = STYLE,
+        body = body,
+    )
+}
+
+// The thumbnails that have been made, dropping the oldest once they take up more than
+// `max_bytes`.
+struct ThumbnailCache {
+    thumbnails: HashMap<PathBuf, Vec<u8>>,
+    // Oldest first.
+    order: VecDeque<PathBuf>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl ThumbnailCache {
+    fn new(max_bytes: usize) -> ThumbnailCache {
+        ThumbnailCache {
+            thumbnails: HashMap::default(),
+            order: VecDeque::default(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn get(&self, path: &Path) -> Option<&Vec<u8>> {
+        self.thumbnails.get(path)
+    }
+
+    fn insert(&mut self, path: PathBuf, png: Vec<u8>) {
+        self.bytes += png.len();
+        if let Some(old) = self.thumbnails.insert(path.clone(), png) {
+            self.bytes -= old.len();
+            self.order.retain(|p| *p != path);
+        }
+        self.order.push_back(path);
+        while self.bytes > self.max_bytes {
+            let oldest = match self.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(png) = self.thumbnails.remove(&oldest) {
+                self.bytes -= png.len();
+            }
+        }
+    }
+}
+
+struct Server<'a> {
+    serve: &'a Serve,
+    filer: &'a Filer,
+    // Where we are listening, to check the Host header against.
+    hosts: [String; 2],
+    thumbnails: ThumbnailCache,
+}
+
+impl<'a> Server<'a> {
+    // Browsers will send requests here from any page, so only answer requests that came from
+    // our own pages. Checking the Host also stops DNS rebinding. Following a link or loading
+    // an image doesn't send an Origin, but browsers always send one with a form POST, so
+    // anything that changes files has to have ours.
+    fn is_local_request(&self, method: &Method, headers: &[Header]) -> bool {
+        let header = |name: &'static str| {
+            headers
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.as_str().to_string())
+        };
+        let host_ok = header("Host").is_some_and(|h| self.hosts.contains(&h));
+        let origin_ok = match header("Origin") {
+            Some(o) => self.hosts.iter().any(|h| o == format!("http://{}", h)),
+            None => *method == Method::Get,
+        };
+        host_ok && origin_ok
+    }
+
+    fn handle(&mut self, mut request: Request) -> Result<()> {
+        if !self.is_local_request(request.method(), request.headers()) {
+            let response = Response::from_string("Forbidden").with_status_code(403);
+            return Ok(request.respond(response)?);
+        }
+        let url = request.url().to_string();
+        let (route, query) = url.split_once('?').unwrap_or((&url, ""));
+        let mut params = parse_params(query);
+        if *request.method() == Method::Post {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body)?;
+            params.extend(parse_params(&body));
+        }
+        debug!("{} {}", request.method(), url);
+
+        let response = match (request.method(), route) {
+            (Method::Get, "/") | (Method::Get, "/browse") => html_response(self.browse(&params)),
+            (Method::Get, "/dups") => html_response(self.dups()),
+            (Method::Get, "/thumb") => match self.thumbnail(&params) {
+                Some(png) => Response::from_data(png).with_header(content_type("image/png")),
+                None => not_found(),
+            },
+            (Method::Get, "/image") => match self.image(&params) {
+                Some((bytes, mime_type)) => {
+                    Response::from_data(bytes).with_header(content_type(mime_type))
+                }
+                None => not_found(),
+            },
+            (Method::Post, "/delete") => html_response(self.delete(&params)?),
+            _ => not_found(),
+        };
+        Ok(request.respond(response)?)
+    }
+
+    // Only files in the catalog are served, and never anything else on the disk.
+    fn cataloged_file(&self, params: &[(String, String)]) -> Option<PathBuf> {
+        path_param(params, "path")
+            .filter(|p| self.filer.contains_file(p.as_path()) && archive_of(p).is_none())
+    }
+
+    fn thumbnail(&mut self, params: &[(String, String)]) -> Option<Vec<u8>> {
+        let path = self.cataloged_file(params)?;
+        if let Some(png) = self.thumbnails.get(&path) {
+            return Some(png.clone());
+        }
+        let png = fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| thumbnail_png(&bytes, THUMBNAIL_SIZE, &[]));
+        match png {
+            Ok(png) => {
+                self.thumbnails.insert(path, png.clone());
+                Some(png)
+            }
+            Err(err) => {
+                debug!("No thumbnail for {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    fn image(&self, params: &[(String, String)]) -> Option<(Vec<u8>, &'static str)> {
+        let path = self.cataloged_file(params)?;
+        let mime_type = self
+            .filer
+            .image_type(path.as_path())
+            .and_then(|it| it.format())
+            .map_or("application/octet-stream", |f| f.mime_type);
+        Some((fs::read(&path).ok()?, mime_type))
+    }
+
+    fn card(&self, path: &Path, checked: bool, keeper: bool) -> String {
+        let image =
+            if self.filer.image_type(path).unwrap_or(ImageType::UNKNOWN) == ImageType::UNKNOWN {
+                String::new()
+            } else {
+                format!(
+                    "<a href=\"{}\"><img loading=\"lazy\" src=\"{}\"></a>",
+                    escape_html(&link("/image", "path", path, "")),
+                    escape_html(&link("/thumb", "path", path, ""))
+                )
+            };
+        format!(
+            concat!(
+                "<div class=\"file{keeper}\">{image}",
+                "<label><input type=\"checkbox\" name=\"path\" value=\"{key}\"{checked}> ",
+                "delete</label><div class=\"path\">{path}</div>",
+                "<div class=\"details\">{details}</div></div>\n"
+            ),
+            keeper = if keeper { " keeper" } else { "" },
+            image = image,
+            key = escape_html(&encode_path(path)),
+            checked = if checked { " checked" } else { "" },
+            path = escape_html(&path.to_string_lossy()),
+            details = escape_html(&describe(self.filer, path)),
+        )
+    }
+
+    fn delete_form(&self, cards: &str) -> String {
+        let delete_button = if self.serve.dry_run {
+            "(The server is in dry run mode, so nothing will be deleted.)"
+        } else {
+            "<button name=\"action\" value=\"delete\">Delete marked files</button>"
+        };
+        format!(
+            concat!(
+                "<form method=\"post\" action=\"/delete\"><div class=\"actions\">",
+                "<button name=\"action\" value=\"preview\">Preview deletion</button> {}",
+                "</div>\n{}</form>\n"
+            ),
+            delete_button, cards
+        )
+    }
+
+    fn filter_form(&self, dir: &Path, params: &[(String, String)]) -> String {
+        let mut form = String::from("<form class=\"filters\" method=\"get\" action=\"/browse\">");
+        let _ = write!(
+            form,
+            "<input type=\"hidden\" name=\"dir\" value=\"{}\">",
+            escape_html(&encode_path(dir))
+        );
+        for (name, label) in FILTERS {
+            let value = param(params, name).unwrap_or_default();
+            if *name == "type" {
+                let _ = write!(form, "<label>{} <select name=\"type\">", label);
+                let names = std::iter::once("").chain(FORMATS.iter().map(|f| f.name));
+                for n in names.chain(std::iter::once("unknown")) {
+                    let selected = if n.eq_ignore_ascii_case(value) {
+                        " selected"
+                    } else {
+                        ""
+                    };
+                    let _ = write!(form, "<option{}>{}</option>", selected, n);
+                }
+                form.push_str("</select></label>");
+            } else {
+                let _ = write!(
+                    form,
+                    "<label>{} <input name=\"{}\" value=\"{}\"></label>",
+                    label,
+                    name,
+                    escape_html(value)
+                );
+            }
+        }
+        form.push_str(" <button>Filter</button></form>\n");
+        form
+    }
+
+    fn browse(&self, params: &[(String, String)]) -> String {
+        let dir = path_param(params, "dir").unwrap_or_default();
+        let filters = filter_string(params);
+        let mut body = self.filter_form(&dir, params);
+        let query = match query_from_params(params) {
+            Ok(query) => query,
+            Err(err) => {
+                let _ = write!(
+                    body,
+                    "<p class=\"error\">{}</p>",
+                    escape_html(&err.to_string())
+                );
+                return page("Browse", &body);
+            }
+        };
+        let glob = match query.glob_matcher() {
+            Ok(glob) => glob,
+            Err(err) => {
+                let _ = write!(
+                    body,
+                    "<p class=\"error\">{}</p>",
+                    escape_html(&err.to_string())
+                );
+                return page("Browse", &body);
+            }
+        };
+
+        // The subdirectories of `dir`, with how many matching files are under each, and the
+        // matching files directly in it.
+        let mut subdirs: BTreeMap<PathBuf, usize> = BTreeMap::default();
+        let mut files: Vec<PathBuf> = Vec::default();
+        self.filer.with_file_infos(|path, info| {
+            let rest = match path.strip_prefix(&dir) {
+                Ok(rest) => rest,
+                Err(_) => return,
+            };
+            if !query.matches(glob.as_ref(), path, info) {
+                return;
+            }
+            let mut components = rest.components();
+            match (components.next(), components.next()) {
+                (Some(first), Some(_)) => *subdirs.entry(dir.join(first)).or_default() += 1,
+                (Some(Component::Normal(_)), None) => files.push(path.clone()),
+                _ => {}
+            }
+        });
+        files.sort();
+
+        // Breadcrumbs back up to the top.
+        body.push_str("<p><a href=\"/browse?dir=");
+        let _ = write!(body, "{}\">(top)</a>", filters);
+        let mut ancestor = PathBuf::new();
+        for component in dir.components() {
+            ancestor.push(component);
+            let _ = write!(
+                body,
+                " / <a href=\"{}\">{}</a>",
+                escape_html(&link("/browse", "dir", &ancestor, &filters)),
+                escape_html(&component.as_os_str().to_string_lossy())
+            );
+        }
+        body.push_str("</p>\n<ul>\n");
+        for (subdir, count) in &subdirs {
+            let _ = writeln!(
+                body,
+                "<li><a href=\"{}\">{}</a> ({} files)</li>",
+                escape_html(&link("/browse", "dir", subdir, &filters)),
+                escape_html(
+                    &subdir
+                        .file_name()
+                        .unwrap_or(subdir.as_os_str())
+                        .to_string_lossy()
+                ),
+                count
+            );
+        }
+        body.push_str("</ul>\n");
+
+        let mut cards = format!("<p>{} files here.", files.len());
+        if files.len() > MAX_FILES {
+            let _ = write!(cards, " Only the first {} are shown.", MAX_FILES);
+        }
+        cards.push_str("</p><div class=\"files\">\n");
+        for path in files.iter().take(MAX_FILES) {
+            cards.push_str(&self.card(path, false, false));
+        }
+        cards.push_str("</div>\n");
+        body.push_str(&self.delete_form(&cards));
+        page("Browse", &body)
+    }
+
+    fn dups(&self) -> String {
+        let groups = duplicate_groups(self.filer, HASH_NAME);
+        let mut cards = format!("<p>{} groups of identical files.</p>\n", groups.len());
+        for (n, files) in groups.iter().enumerate() {
+            let keeper = proposed_keeper(self.filer, files);
+            let _ = writeln!(
+                cards,
+                "<section><h2>Group {} ({} files)</h2><div class=\"files\">",
+                n + 1,
+                files.len()
+            );
+            for (i, path) in files.iter().enumerate() {
+                cards.push_str(&self.card(path, i != keeper, i == keeper));
+            }
+            cards.push_str("</div></section>\n");
+        }
+        page("Duplicates", &self.delete_form(&cards))
+    }
+
+    fn delete(&self, params: &[(String, String)]) -> Result<String> {
+        let doomed: HashSet<PathBuf> = params
+            .iter()
+            .filter(|(name, _)| name == "path")
+            .map(|(_, value)| decode_path(value))
+            .collect();
+        let dry_run = self.serve.dry_run || param(params, "action") != Some("delete");
+        let deletions = delete_duplicates(
+            self.filer,
+            &doomed,
+            default_hash_algorithm(),
+            dry_run,
+            self.serve.trust_hashes,
+        )?;
+        let deleted = deletions.iter().filter(|d| d.refused.is_none()).count();
+        if !dry_run && deleted > 0 {
+            info!("Writing {}", CATALOG_PATH);
+            self.filer.write_to_path(CATALOG_PATH)?;
+        }
+
+        let mut body = String::from("<ul>\n");
+        for deletion in &deletions {
+            let outcome = match &deletion.refused {
+                Some(reason) => format!("Not deleted: {}", reason),
+                None if dry_run => "Would be deleted".to_string(),
+                None => "Deleted".to_string(),
+            };
+            let _ = writeln!(
+                body,
+                "<li>{}: <span class=\"path\">{}</span></li>",
+                escape_html(&outcome),
+                escape_html(&deletion.path.to_string_lossy())
+            );
+        }
+        body.push_str("</ul>\n");
+        if dry_run && deleted > 0 && !self.serve.dry_run {
+            // The same choices again, for real this time.
+            let mut form = String::from("<form method=\"post\" action=\"/delete\">");
+            for deletion in deletions.iter().filter(|d| d.refused.is_none()) {
+                let _ = write!(
+                    form,
+                    "<input type=\"hidden\" name=\"path\" value=\"{}\">",
+                    escape_html(&encode_path(&deletion.path))
+                );
+            }
+            let _ = write!(
+                form,
+                "<button name=\"action\" value=\"delete\">Delete these {} files</button></form>",
+                deleted
+            );
+            body.push_str(&form);
+        }
+        let title = if dry_run {
+            "Deletion preview"
+        } else {
+            "Deleted"
+        };
+        Ok(page(title, &body))
+    }
+}
+
+pub fn process_serve(serve: &Serve, filer: &Filer) -> Result<()> {
+    let server = tiny_http::Server::http(("127.0.0.1", serve.port)).map_err(|err| anyhow!(err))?;
+    let mut state = Server {
+        serve,
+        filer,
+        hosts: [
+            format!("127.0.0.1:{}", serve.port),
+            format!("localhost:{}", serve.port),
+        ],
+        thumbnails: ThumbnailCache::new(MAX_THUMBNAIL_BYTES),
+    };
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))?;
+
+    eprintln!(
+        "Serving the catalog at http://127.0.0.1:{}/. Press Ctrl-C to stop.",
+        serve.port
+    );
+    while !interrupted.load(Ordering::SeqCst) {
+        if let Some(request) = server.recv_timeout(POLL_INTERVAL)? {
+            // One bad request shouldn't stop the server.
+            if let Err(err) = state.handle(request) {
+                error!("{}", err);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("a%20b", "a b" ; "escape")]
+    #[test_case("a+b", "a b" ; "plus")]
+    #[test_case("%2B%26%3d", "+&=" ; "reserved")]
+    #[test_case("caf%C3%A9", "caf\u{e9}" ; "utf8")]
+    #[test_case("100%", "100%" ; "percent at end")]
+    #[test_case("%4", "%4" ; "short escape")]
+    #[test_case("%zz%", "%zz%" ; "not hex")]
+    #[test_case("%+1", "% 1" ; "sign is not hex")]
+    #[test_case("%FF", "\u{fffd}" ; "invalid utf8")]
+    fn urls_are_decoded(s: &str, expected: &str) {
+        assert_eq!(expected, url_decode(s));
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let s = "/photos/my pics/caf\u{e9} & 100%+.jpg";
+        assert_eq!(s, url_decode(&url_encode(s)));
+    }
+
+    #[test]
+    fn params_are_parsed_in_order() {
+        assert_eq!(
+            vec![
+                ("path".to_string(), "a b".to_string()),
+                ("path".to_string(), "c=d".to_string()),
+                ("flag".to_string(), String::new()),
+                ("empty".to_string(), String::new()),
+            ],
+            parse_params("path=a+b&&path=c%3Dd&flag&empty=")
+        );
+        assert!(parse_params("").is_empty());
+    }
+
+    fn is_local(method: Method, headers: &[(&str, &str)]) -> bool {
+        let serve = Serve::from_iter_safe(["serve", "--port", "8080"]).unwrap();
+        let filer = Filer::new().unwrap();
+        let server = Server {
+            serve: &serve,
+            filer: &filer,
+            hosts: ["127.0.0.1:8080".to_string(), "localhost:8080".to_string()],
+            thumbnails: ThumbnailCache::new(0),
+        };
+        let headers: Vec<Header> = headers
+            .iter()
+            .map(|(name, value)| Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap())
+            .collect();
+        server.is_local_request(&method, &headers)
+    }
+
+    #[test]
+    fn local_requests_are_answered() {
+        assert!(is_local(Method::Get, &[("Host", "127.0.0.1:8080")]));
+        assert!(is_local(Method::Get, &[("Host", "localhost:8080")]));
+        assert!(is_local(
+            Method::Post,
+            &[
+                ("Host", "localhost:8080"),
+                ("Origin", "http://localhost:8080")
+            ]
+        ));
+    }
+
+    #[test_case(Method::Get, &[] ; "no host")]
+    #[test_case(Method::Get, &[("Host", "evil.example:8080")] ; "rebound host")]
+    #[test_case(Method::Get, &[("Host", "127.0.0.1:9090")] ; "other port")]
+    #[test_case(
+        Method::Get,
+        &[("Host", "127.0.0.1:8080"), ("Origin", "http://evil.example")] ;
+        "foreign origin"
+    )]
+    #[test_case(
+        Method::Post,
+        &[("Host", "127.0.0.1:8080"), ("Origin", "https://127.0.0.1:8080")] ;
+        "other scheme"
+    )]
+    #[test_case(Method::Post, &[("Host", "127.0.0.1:8080")] ; "post without origin")]
+    fn foreign_requests_are_refused(method: Method, headers: &[(&str, &str)]) {
+        assert!(!is_local(method, headers));
+    }
+
+    #[test]
+    fn thumbnail_cache_drops_the_oldest() {
+        let mut cache = ThumbnailCache::new(10);
+        cache.insert(PathBuf::from("a"), vec![0; 4]);
+        cache.insert(PathBuf::from("b"), vec![0; 4]);
+        // Replacing one doesn't count it twice.
+        cache.insert(PathBuf::from("a"), vec![0; 5]);
+        assert_eq!(9, cache.bytes);
+        cache.insert(PathBuf::from("c"), vec![0; 4]);
+        assert!(cache.get(Path::new("b")).is_none());
+        assert_eq!(Some(&vec![0; 5]), cache.get(Path::new("a")));
+        assert!(cache.get(Path::new("c")).is_some());
+        assert_eq!(9, cache.bytes);
+
+        // One that is too big on its own isn't kept at all.
+        cache.insert(PathBuf::from("d"), vec![0; 11]);
+        assert!(cache.thumbnails.is_empty());
+        assert_eq!(0, cache.bytes);
+    }
+}
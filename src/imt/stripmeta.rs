@@ -0,0 +1,461 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::info;
+use structopt::StructOpt;
+use walkdir::DirEntry;
+
+use crate::imt::crawler::{CrawlHelper, Crawler};
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::CrawlOptions;
+use crate::imt::direntryutil::{
+    crawl_root, is_hidden, is_inside, prepare_output_dir, replace_contents,
+};
+use crate::imt::filer::{FileStat, Filer};
+use crate::imt::image_type::{gif_blocks, jpeg_segments, png_chunks, ImageType, Segment};
+use crate::imt::progress::Progress;
+
+/// Remove metadata (EXIF, XMP, comments, text chunks) from JPEG, PNG, and GIF files, without
+/// re-encoding the pixels. Note that dropping EXIF also drops the orientation tag.
+#[derive(StructOpt, Debug)]
+pub struct StripMeta {
+    /// Print what would be removed. No files are changed.
+    #[structopt(short = "n", long)]
+    dry_run: bool,
+
+    /// Write the stripped files under this directory, instead of replacing the originals.
+    #[structopt(long, parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+
+    /// Remove embedded ICC color profiles too.
+    #[structopt(long)]
+    strip_icc: bool,
+
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
+
+    /// The directories to search
+    #[structopt(min_values(1), parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+// JPEG APPn segments that affect how the pixels are displayed, by marker and identifier.
+const KEPT_JPEG_SEGMENTS: &[(u8, &[u8])] = &[
+    (0xe0, b"JFIF\0"),
+    (0xe0, b"JFXX\0"),
+    (0xe2, b"ICC_PROFILE\0"),
+    // Says how to convert the colors of CMYK images.
+    (0xee, b"Adobe"),
+];
+const ICC_MARKER: u8 = 0xe2;
+
+const DROPPED_PNG_CHUNKS: &[&[u8; 4]] = &[b"tEXt", b"iTXt", b"zTXt", b"eXIf", b"tIME"];
+const ICC_CHUNK: &[u8; 4] = b"iCCP";
+
+// GIF application extensions that control looping, rather than describe the image.
+const KEPT_GIF_APPLICATIONS: &[&[u8]] = &[b"NETSCAPE", b"ANIMEXTS"];
+
+// The file with the dropped segments removed, and a description of each of them.
+struct Stripped {
+    bytes: Vec<u8>,
+    removed: Vec<String>,
+}
+
+// Keeps everything before the first segment, and the segments that `keep` accepts.
+fn rebuild<T, F>(bytes: &[u8], segments: &[Segment<T>], mut keep: F) -> Stripped
+where
+    F: FnMut(&Segment<T>) -> Option<String>,
+{
+    let header_len = segments.first().map_or(bytes.len(), |s| s.range.start);
+    let mut stripped = Stripped {
+        bytes: bytes[..header_len].to_vec(),
+        removed: Vec::default(),
+    };
+    for segment in segments {
+        match keep(segment) {
+            None => stripped
+                .bytes
+                .extend_from_slice(&bytes[segment.range.clone()]),
+            Some(description) => stripped.removed.push(description),
+        }
+    }
+    stripped
+}
+
+// The identifier at the start of an APPn segment, e.g. "Exif".
+fn jpeg_identifier(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end.min(32)]).into_owned()
+}
+
+fn strip_jpeg(bytes: &[u8], strip_icc: bool) -> Result<Stripped> {
+    let segments = jpeg_segments(bytes)?;
+    Ok(rebuild(bytes, &segments, |segment| {
+        let marker = segment.id;
+        match marker {
+            0xfe => Some("COM".to_string()),
+            0xe0..=0xef => {
+                // Skip the marker and the length.
+                let data = bytes
+                    .get(segment.range.start + 4..segment.range.end)
+                    .unwrap_or_default();
+                let kept = KEPT_JPEG_SEGMENTS
+                    .iter()
+                    .any(|(m, id)| *m == marker && data.starts_with(id));
+                if kept && !(strip_icc && marker == ICC_MARKER) {
+                    None
+                } else {
+                    Some(format!("APP{} ({})", marker - 0xe0, jpeg_identifier(data)))
+                }
+            }
+            _ => None,
+        }
+    }))
+}
+
+fn strip_png(bytes: &[u8], strip_icc: bool) -> Result<Stripped> {
+    let chunks = png_chunks(bytes)?;
+    Ok(rebuild(bytes, &chunks, |chunk| {
+        let dropped =
+            DROPPED_PNG_CHUNKS.contains(&&chunk.id) || (strip_icc && chunk.id == *ICC_CHUNK);
+        if dropped {
+            Some(String::from_utf8_lossy(&chunk.id).into_owned())
+        } else {
+            None
+        }
+    }))
+}
+
+fn strip_gif(bytes: &[u8]) -> Result<Stripped> {
+    let blocks = gif_blocks(bytes)?;
+    Ok(rebuild(bytes, &blocks, |block| match block.id {
+        [0x21, 0xfe] => Some("comment".to_string()),
+        [0x21, 0xff] => {
+            // block size(1), identifier(8), authentication code(3)
+            let identifier = bytes
+                .get(block.range.start + 3..block.range.start + 11)
+                .unwrap_or_default();
+            if KEPT_GIF_APPLICATIONS.contains(&identifier) {
+                None
+            } else {
+                Some(format!(
+                    "application ({})",
+                    String::from_utf8_lossy(identifier)
+                ))
+            }
+        }
+        _ => None,
+    }))
+}
+
+fn strip(bytes: &[u8], image_type: ImageType, strip_icc: bool) -> Result<Option<Stripped>> {
+    Ok(match image_type {
+        ImageType::JPEG => Some(strip_jpeg(bytes, strip_icc)?),
+        ImageType::PNG => Some(strip_png(bytes, strip_icc)?),
+        ImageType::GIF => Some(strip_gif(bytes)?),
+        _ => None,
+    })
+}
+
+struct Helper<'a> {
+    strip_meta: &'a StripMeta,
+    root: &'a Path,
+    // The canonical --output-dir, which isn't crawled, if it is inside of a directory that is.
+    output_dir: Option<&'a Path>,
+    filer: &'a Filer,
+    progress: &'a Progress,
+    errors: &'a CrawlErrors,
+}
+
+#[derive(Default)]
+struct Info {
+    image_type: Option<ImageType>,
+}
+
+impl<'a> Helper<'a> {
+    fn destination(&self, path: &Path) -> Result<Option<PathBuf>> {
+        Ok(match &self.strip_meta.output_dir {
+            None => None,
+            Some(dir) => Some(dir.join(path.strip_prefix(self.root)?)),
+        })
+    }
+
+    fn replace(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        replace_contents(path, bytes)?;
+        // Drops the cached hashes, since the contents changed.
+        if self.filer.contains_file(path) {
+            self.filer.set_stat(path, FileStat::from_path(path)?);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> CrawlHelper for Helper<'a> {
+    type InfoType = Info;
+
+    fn should_descend(&self, e: &DirEntry) -> Result<bool> {
+        // Basically just trimming off hidden directories.
+        Ok(!is_hidden(e) && !self.output_dir.is_some_and(|dir| is_inside(e, dir)))
+    }
+
+    fn should_process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<bool> {
+        let image_type = ImageType::type_of_file_at(e.path())?;
+        it.image_type = Some(image_type);
+        Ok(matches!(
+            image_type,
+            ImageType::JPEG | ImageType::PNG | ImageType::GIF
+        ))
+    }
+
+    fn process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<()> {
+        let path = e.path();
+        let bytes = fs::read(path)?;
+        self.progress.add_bytes(bytes.len() as u64);
+        let image_type = it.image_type.unwrap_or(ImageType::UNKNOWN);
+        let stripped = match strip(&bytes, image_type, self.strip_meta.strip_icc)? {
+            Some(stripped) => stripped,
+            None => return Ok(()),
+        };
+
+        if !stripped.removed.is_empty() {
+            println!("{}: {}", path.display(), stripped.removed.join(", "));
+        }
+        if self.strip_meta.dry_run {
+            info!("Dry run. File operation skipped.");
+            return Ok(());
+        }
+        match self.destination(path)? {
+            Some(dest) => {
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&dest, &stripped.bytes)?;
+            }
+            None if !stripped.removed.is_empty() => self.replace(path, &stripped.bytes)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn progress(&self) -> Option<&Progress> {
+        Some(self.progress)
+    }
+
+    fn errors(&self) -> Option<&CrawlErrors> {
+        Some(self.errors)
+    }
+}
+
+pub fn process_stripmeta(sm: &StripMeta, filer: &Filer) -> Result<()> {
+    let output_dir = match &sm.output_dir {
+        // A dry run doesn't create it, but still mustn't crawl it if it's already there.
+        Some(dir) if sm.dry_run => fs::canonicalize(dir).ok(),
+        Some(dir) => Some(prepare_output_dir(dir)?),
+        None => None,
+    };
+    let progress = Progress::new();
+    let errors = CrawlErrors::new(sm.crawl_options.fail_fast);
+    for dir in &sm.directories {
+        let crawler = Crawler::new(
+            dir,
+            &sm.crawl_options,
+            Helper {
+                strip_meta: sm,
+                root: crawl_root(dir),
+                output_dir: output_dir.as_deref(),
+                filer,
+                progress: &progress,
+                errors: &errors,
+            },
+        );
+        crawler.crawl()?;
+        if errors.should_stop() {
+            break;
+        }
+    }
+    progress.finish();
+    errors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use flate2::Crc;
+    use tempfile::{Builder, TempDir};
+
+    use super::*;
+
+    fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let len = (data.len() + 2) as u16;
+        [&[0xff, marker][..], &len.to_be_bytes(), data].concat()
+    }
+
+    fn png_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut crc = Crc::new();
+        crc.update(id);
+        crc.update(data);
+        [
+            &(data.len() as u32).to_be_bytes()[..],
+            id,
+            data,
+            &crc.sum().to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn gif_extension(label: u8, data: &[u8]) -> Vec<u8> {
+        [&[0x21, label, data.len() as u8][..], data, &[0]].concat()
+    }
+
+    // test.jpg is JFIF (APP0), then EXIF (APP1), then the tables and the image.
+    fn jpeg_fixture() -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) {
+        let original = fs::read("test_images/test.jpg").unwrap();
+        let segments = jpeg_segments(&original).unwrap();
+        assert_eq!(0xe0, segments[0].id);
+        assert_eq!(0xe1, segments[1].id);
+        let icc = jpeg_segment(0xe2, b"ICC_PROFILE\0\x01\x01profile");
+        let adobe = jpeg_segment(0xee, b"Adobe\0\x64\0\0\0\0\x01");
+        let with_meta = [
+            &original[..segments[1].range.end],
+            &jpeg_segment(0xfe, b"a comment"),
+            &icc,
+            &adobe,
+            &jpeg_segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"),
+            &original[segments[2].range.start..],
+        ]
+        .concat();
+        (original, with_meta, icc, adobe)
+    }
+
+    #[test]
+    fn jpeg_metadata_is_stripped() {
+        let (original, with_meta, icc, adobe) = jpeg_fixture();
+        let segments = jpeg_segments(&original).unwrap();
+
+        let stripped = strip_jpeg(&with_meta, false).unwrap();
+        assert_eq!(
+            vec!["APP1 (Exif)", "COM", "APP1 (http://ns.adobe.com/xap/1.0/)"],
+            stripped.removed
+        );
+        // JFIF, the color profile, and the Adobe segment stay, and everything from the tables
+        // on is untouched.
+        let expected = [
+            &original[..segments[1].range.start],
+            &icc,
+            &adobe,
+            &original[segments[2].range.start..],
+        ]
+        .concat();
+        assert_eq!(expected, stripped.bytes);
+    }
+
+    #[test]
+    fn jpeg_icc_profile_is_stripped_on_request() {
+        let (original, with_meta, _, adobe) = jpeg_fixture();
+        let segments = jpeg_segments(&original).unwrap();
+
+        let stripped = strip_jpeg(&with_meta, true).unwrap();
+        assert!(stripped.removed.contains(&"APP2 (ICC_PROFILE)".to_string()));
+        let expected = [
+            &original[..segments[1].range.start],
+            &adobe,
+            &original[segments[2].range.start..],
+        ]
+        .concat();
+        assert_eq!(expected, stripped.bytes);
+    }
+
+    #[test]
+    fn png_metadata_is_stripped() {
+        let original = fs::read("test_images/testpng").unwrap();
+        let chunks = png_chunks(&original).unwrap();
+        assert_eq!(b"IHDR", &chunks[0].id);
+        let after_header = chunks[0].range.end;
+        let iccp = png_chunk(b"iCCP", b"profile\0\0compressed");
+        let with_meta = [
+            &original[..after_header],
+            &png_chunk(b"tEXt", b"Comment\0hello"),
+            &iccp,
+            &png_chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>"),
+            &png_chunk(b"eXIf", b"MM\0\x2a\0\0\0\x08\0\0"),
+            &png_chunk(b"tIME", b"\x07\xea\x0a\x13\x0c\0\0"),
+            &original[after_header..],
+        ]
+        .concat();
+
+        let stripped = strip_png(&with_meta, false).unwrap();
+        assert_eq!(vec!["tEXt", "iTXt", "eXIf", "tIME"], stripped.removed);
+        let expected = [&original[..after_header], &iccp, &original[after_header..]].concat();
+        assert_eq!(expected, stripped.bytes);
+
+        let stripped = strip_png(&with_meta, true).unwrap();
+        assert_eq!(
+            vec!["tEXt", "iCCP", "iTXt", "eXIf", "tIME"],
+            stripped.removed
+        );
+        assert_eq!(original, stripped.bytes);
+    }
+
+    #[test]
+    fn gif_metadata_is_stripped() {
+        let original = fs::read("test_images/test.gif").unwrap();
+        let first_block = gif_blocks(&original).unwrap()[0].range.start;
+        let netscape = gif_extension(0xff, b"NETSCAPE2.0\x03\x01\0\0");
+        let with_meta = [
+            &original[..first_block],
+            &gif_extension(0xfe, b"a comment"),
+            &netscape,
+            &gif_extension(0xff, b"XMP DataXMP<x:xmpmeta/>"),
+            &original[first_block..],
+        ]
+        .concat();
+
+        let stripped = strip_gif(&with_meta).unwrap();
+        assert_eq!(vec!["comment", "application (XMP Data)"], stripped.removed);
+        let expected = [
+            &original[..first_block],
+            &netscape,
+            &original[first_block..],
+        ]
+        .concat();
+        assert_eq!(expected, stripped.bytes);
+    }
+
+    fn strip_meta(out: &Path, source: &Path) {
+        let args = [OsStr::new("strip-meta"), OsStr::new("--output-dir")];
+        let sm = StripMeta::from_iter_safe(
+            args.iter()
+                .copied()
+                .chain([out.as_os_str(), source.as_os_str()]),
+        )
+        .unwrap();
+        process_stripmeta(&sm, &Filer::new().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn single_file_goes_into_output_dir() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a.jpg");
+        fs::write(&file, jpeg_fixture().1).unwrap();
+        let out = dir.path().join("out");
+
+        strip_meta(&out, &file);
+        let stripped = fs::read(out.join("a.jpg")).unwrap();
+        assert!(strip_jpeg(&stripped, false).unwrap().removed.is_empty());
+    }
+
+    #[test]
+    fn output_dir_is_not_crawled() {
+        // Not TempDir::new(), since the crawl skips hidden directories.
+        let dir = Builder::new().prefix("imt").tempdir().unwrap();
+        fs::write(dir.path().join("a.jpg"), jpeg_fixture().1).unwrap();
+        let out = dir.path().join("stripped");
+
+        strip_meta(&out, dir.path());
+        strip_meta(&out, dir.path());
+        assert!(out.join("a.jpg").exists());
+        assert!(!out.join("stripped").exists());
+    }
+}
@@ -0,0 +1,467 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use md5::{Digest, Md5};
+use structopt::StructOpt;
+use walkdir::DirEntry;
+
+use crate::imt::crawler::{CrawlHelper, Crawler};
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::CrawlOptions;
+use crate::imt::direntryutil::is_hidden;
+use crate::imt::filer::{FileStat, Filer};
+use crate::imt::finddups::HASH_NAME;
+use crate::imt::hashers::{default_hash_algorithm, hash_reader};
+use crate::imt::image_type::{png_chunks, ImageType};
+use crate::imt::progress::Progress;
+
+const DEFAULT_CACHE_DIR: &str = ".imt-thumbs";
+
+// The freedesktop.org thumbnail directories, by the size of the thumbnails in them.
+const FREEDESKTOP_SIZES: &[(u32, &str)] = &[
+    (128, "normal"),
+    (256, "large"),
+    (512, "x-large"),
+    (1024, "xx-large"),
+];
+
+/// Write PNG thumbnails of images into a cache, skipping images that already have one.
+/// Thumbnails are named by the SHA256 of the image, so copies share a thumbnail.
+#[derive(StructOpt, Debug)]
+pub struct Thumbs {
+    /// The width and height of the box that the thumbnails fit in.
+    #[structopt(long, default_value = "256")]
+    size: u32,
+
+    /// Where to write the thumbnails.
+    #[structopt(long, parse(from_os_str), default_value = DEFAULT_CACHE_DIR)]
+    cache_dir: PathBuf,
+
+    /// Write the thumbnails where desktop file managers look for them, as described by the
+    /// freedesktop.org thumbnail spec. The size must be 128, 256, 512, or 1024.
+    #[structopt(long, conflicts_with = "cache-dir")]
+    freedesktop: bool,
+
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
+
+    /// The directories to search
+    #[structopt(min_values(1), parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+// Where the thumbnails go, and how they are named.
+enum Layout {
+    // <cache dir>/<size>/<first two digits of the SHA256>/<SHA256>.png
+    ContentAddressed(PathBuf),
+    // <thumbnail dir>/<MD5 of the file's URI>.png
+    Freedesktop(PathBuf),
+}
+
+impl Layout {
+    fn new(thumbs: &Thumbs) -> Result<Layout> {
+        if !thumbs.freedesktop {
+            return Ok(Layout::ContentAddressed(
+                thumbs.cache_dir.join(thumbs.size.to_string()),
+            ));
+        }
+        let name = FREEDESKTOP_SIZES
+            .iter()
+            .find(|(size, _)| *size == thumbs.size)
+            .map(|(_, name)| *name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "freedesktop thumbnails must be 128, 256, 512, or 1024 pixels, not {}",
+                    thumbs.size
+                )
+            })?;
+        Ok(Layout::Freedesktop(
+            freedesktop_cache_dir()?.join("thumbnails").join(name),
+        ))
+    }
+}
+
+fn freedesktop_cache_dir() -> Result<PathBuf> {
+    match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".cache"))
+            .ok_or_else(|| anyhow!("neither XDG_CACHE_HOME nor HOME is set")),
+    }
+}
+
+// The "file://" URI of an absolute path, as the freedesktop spec hashes it.
+fn file_uri(path: &Path) -> Result<String> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("{} is not valid UTF-8", path.display()))?;
+    let mut uri = "file://".to_string();
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri += &format!("%{:02X}", b);
+        }
+    }
+    Ok(uri)
+}
+
+// Thumbnails show what is in the images, so like the freedesktop spec says, only the owner
+// may read them.
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to new files, and a leftover temp file may be there already.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+// The value of a tEXt chunk in the PNG at `path`, if it has one.
+fn png_text(path: &Path, keyword: &str) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    png_chunks(&bytes)
+        .ok()?
+        .iter()
+        .filter(|chunk| &chunk.id == b"tEXt")
+        // length(4), type(4), data, CRC(4)
+        .filter_map(|chunk| bytes.get(chunk.range.start + 8..chunk.range.end - 4))
+        .find_map(|data| {
+            let text = data.strip_prefix(keyword.as_bytes())?.strip_prefix(b"\0")?;
+            Some(String::from_utf8_lossy(text).into_owned())
+        })
+}
+
+struct Helper<'a> {
+    thumbs: &'a Thumbs,
+    layout: &'a Layout,
+    filer: &'a Filer,
+    progress: &'a Progress,
+    errors: &'a CrawlErrors,
+}
+
+#[derive(Default)]
+struct Info {
+    stat: FileStat,
+}
+
+impl<'a> Helper<'a> {
+    // The SHA256 from the catalog, computing it if the catalog doesn't have it yet.
+    fn hash(&self, path: &Path, stat: FileStat) -> Result<String> {
+        // Drops the cached hash if the file has changed.
+        self.filer.set_stat(path, stat);
+        if let Some(hash) = self.filer.hash_value(path, HASH_NAME) {
+            return Ok(hash);
+        }
+        let hash = hash_reader(
+            &mut File::open(path)?,
+            default_hash_algorithm(),
+            self.progress,
+        )?;
+        self.filer.add_hash(path, HASH_NAME, hash.as_str())?;
+        Ok(hash)
+    }
+
+    // The path of the thumbnail, and the text to put in it.
+    fn thumbnail_path(
+        &self,
+        path: &Path,
+        stat: FileStat,
+    ) -> Result<(PathBuf, Vec<(&str, String)>)> {
+        match self.layout {
+            Layout::ContentAddressed(dir) => {
+                let hash = self.hash(path, stat)?;
+                let thumb = dir.join(&hash[..2]).join(format!("{}.png", hash));
+                Ok((thumb, Vec::default()))
+            }
+            Layout::Freedesktop(dir) => {
+                let uri = file_uri(&fs::canonicalize(path)?)?;
+                let name = hex::encode(Md5::digest(uri.as_bytes()));
+                let thumb = dir.join(format!("{}.png", name));
+                let mtime = stat.mtime.unwrap_or_default().to_string();
+                Ok((
+                    thumb,
+                    vec![
+                        ("Thumb::URI", uri),
+                        ("Thumb::MTime", mtime),
+                        ("Software", "imt2".to_string()),
+                    ],
+                ))
+            }
+        }
+    }
+
+    // Freedesktop thumbnails are stale once the image has been modified.
+    fn is_current(&self, thumb: &Path, text: &[(&str, String)]) -> bool {
+        if !thumb.exists() {
+            return false;
+        }
+        match text.iter().find(|(keyword, _)| *keyword == "Thumb::MTime") {
+            None => true,
+            Some((keyword, mtime)) => png_text(thumb, keyword).as_ref() == Some(mtime),
+        }
+    }
+
+    fn write_thumbnail(&self, path: &Path, thumb: &Path, text: &[(&str, String)]) -> Result<()> {
+        let bytes = fs::read(path)?;
+        self.progress.add_bytes(bytes.len() as u64);
+        let png = thumbnail_png(&bytes, self.thumbs.size, text)?;
+
+        let dir = thumb
+            .parent()
+            .ok_or_else(|| anyhow!("no directory for {}", thumb.display()))?;
+        create_private_dir(dir)?;
+        // Written to a temp file and renamed, so nothing sees a partial thumbnail.
+        let tmp = thumb.with_extension("png.imt-tmp");
+        write_private(&tmp, &png)?;
+        fs::rename(&tmp, thumb)?;
+        Ok(())
+    }
+}
+
+/// Decodes the image in `bytes` and encodes a PNG of it that fits in a `size` x `size` box,
+/// with `text` as tEXt chunks.
+pub fn thumbnail_png(bytes: &[u8], size: u32, text: &[(&str, String)]) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(bytes)?;
+    // Small images are used as they are, rather than blown up.
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+    let rgba = image.into_rgba8();
+
+    let mut png = Vec::default();
+    let mut encoder = png::Encoder::new(&mut png, rgba.width(), rgba.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    for (keyword, value) in text {
+        encoder.add_text_chunk(keyword.to_string(), value.clone())?;
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba.as_raw())?;
+    writer.finish()?;
+    Ok(png)
+}
+
+impl<'a> CrawlHelper for Helper<'a> {
+    type InfoType = Info;
+
+    fn should_descend(&self, e: &DirEntry) -> Result<bool> {
+        // Basically just trimming off hidden directories.
+        Ok(!is_hidden(e))
+    }
+
+    fn should_process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<bool> {
+        if ImageType::type_of_file_at(e.path())? == ImageType::UNKNOWN {
+            return Ok(false);
+        }
+        it.stat = FileStat::from_path(e.path())?;
+        Ok(true)
+    }
+
+    fn process_file(&self, e: &DirEntry, it: &mut Self::InfoType) -> Result<()> {
+        let path = e.path();
+        let (thumb, text) = self.thumbnail_path(path, it.stat)?;
+        if self.is_current(&thumb, &text) {
+            info!("{}: thumbnail exists", path.display());
+            return Ok(());
+        }
+        self.write_thumbnail(path, &thumb, &text)?;
+        info!("{}: wrote {}", path.display(), thumb.display());
+        Ok(())
+    }
+
+    fn progress(&self) -> Option<&Progress> {
+        Some(self.progress)
+    }
+
+    fn errors(&self) -> Option<&CrawlErrors> {
+        Some(self.errors)
+    }
+}
+
+pub fn process_thumbs(thumbs: &Thumbs, filer: &Filer) -> Result<()> {
+    let layout = Layout::new(thumbs)?;
+    let progress = Progress::new();
+    let errors = CrawlErrors::new(thumbs.crawl_options.fail_fast);
+    for dir in &thumbs.directories {
+        let crawler = Crawler::new(
+            dir,
+            &thumbs.crawl_options,
+            Helper {
+                thumbs,
+                layout: &layout,
+                filer,
+                progress: &progress,
+                errors: &errors,
+            },
+        );
+        crawler.crawl()?;
+        if errors.should_stop() {
+            break;
+        }
+    }
+    progress.finish();
+    errors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use tempfile::{Builder, TempDir};
+    use test_case::test_case;
+
+    use super::*;
+
+    fn thumbs(args: &[&OsStr]) -> Thumbs {
+        Thumbs::from_iter_safe([OsStr::new("thumbs")].iter().chain(args)).unwrap()
+    }
+
+    #[test_case("/photos/a.jpg", "file:///photos/a.jpg" ; "plain")]
+    #[test_case("/my photos/a b.jpg", "file:///my%20photos/a%20b.jpg" ; "spaces")]
+    #[test_case("/100%/#1?.jpg", "file:///100%25/%231%3F.jpg" ; "reserved")]
+    #[test_case("/caf\u{e9}/~x_y-z.jpg", "file:///caf%C3%A9/~x_y-z.jpg" ; "utf8")]
+    fn file_uris_are_escaped(path: &str, expected: &str) {
+        assert_eq!(expected, file_uri(Path::new(path)).unwrap());
+    }
+
+    #[test]
+    fn content_addressed_thumbnails_go_under_the_size() {
+        let thumbs = thumbs(&[
+            OsStr::new("--size"),
+            OsStr::new("100"),
+            OsStr::new("--cache-dir"),
+            OsStr::new("cache"),
+            OsStr::new("."),
+        ]);
+        match Layout::new(&thumbs).unwrap() {
+            Layout::ContentAddressed(dir) => assert_eq!(Path::new("cache/100"), dir),
+            Layout::Freedesktop(_) => panic!("expected content addressed"),
+        }
+    }
+
+    #[test_case("128", "normal")]
+    #[test_case("256", "large")]
+    #[test_case("1024", "xx-large")]
+    fn freedesktop_sizes_are_named(size: &str, name: &str) {
+        let thumbs = thumbs(&[
+            OsStr::new("--freedesktop"),
+            OsStr::new("--size"),
+            OsStr::new(size),
+            OsStr::new("."),
+        ]);
+        match Layout::new(&thumbs).unwrap() {
+            Layout::Freedesktop(dir) => assert!(dir.ends_with(Path::new("thumbnails").join(name))),
+            Layout::ContentAddressed(_) => panic!("expected freedesktop"),
+        }
+    }
+
+    #[test_case("100" ; "too small")]
+    #[test_case("300" ; "in between")]
+    fn other_freedesktop_sizes_are_rejected(size: &str) {
+        let thumbs = thumbs(&[
+            OsStr::new("--freedesktop"),
+            OsStr::new("--size"),
+            OsStr::new(size),
+            OsStr::new("."),
+        ]);
+        assert!(Layout::new(&thumbs).is_err());
+    }
+
+    #[test]
+    fn freedesktop_thumbnails_are_stale_after_a_change() {
+        let dir = TempDir::new().unwrap();
+        let thumb = dir.path().join("thumb.png");
+        let bytes = fs::read("test_images/test.jpg").unwrap();
+        let text = [("Thumb::MTime", "100".to_string())];
+        fs::write(&thumb, thumbnail_png(&bytes, 32, &text).unwrap()).unwrap();
+
+        let thumbs = thumbs(&[OsStr::new(".")]);
+        let layout = Layout::new(&thumbs).unwrap();
+        let filer = Filer::new().unwrap();
+        let progress = Progress::new();
+        let errors = CrawlErrors::new(false);
+        let helper = Helper {
+            thumbs: &thumbs,
+            layout: &layout,
+            filer: &filer,
+            progress: &progress,
+            errors: &errors,
+        };
+        assert!(helper.is_current(&thumb, &text));
+        assert!(!helper.is_current(&thumb, &[("Thumb::MTime", "200".to_string())]));
+        assert!(!helper.is_current(&dir.path().join("missing.png"), &text));
+        // Content addressed thumbnails don't go stale, since a change gives a new name.
+        assert!(helper.is_current(&thumb, &[]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn thumbnails_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Not TempDir::new(), since the crawl skips hidden directories.
+        let images = Builder::new().prefix("imt").tempdir().unwrap();
+        fs::copy("test_images/test.jpg", images.path().join("a.jpg")).unwrap();
+        let cache = TempDir::new().unwrap();
+        let cache_dir = cache.path().join("thumbs");
+        let thumbs = thumbs(&[
+            OsStr::new("--cache-dir"),
+            cache_dir.as_os_str(),
+            images.path().as_os_str(),
+        ]);
+        process_thumbs(&thumbs, &Filer::new().unwrap()).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let size_dir = cache_dir.join("256");
+        let hash_dir = fs::read_dir(&size_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let thumb = fs::read_dir(&hash_dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        for dir in [&cache_dir, &size_dir, &hash_dir] {
+            assert_eq!(0o700, mode(dir), "{}", dir.display());
+        }
+        assert_eq!(0o600, mode(&thumb));
+    }
+}
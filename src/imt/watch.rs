@@ -0,0 +1,440 @@
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{debug, error, info};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use structopt::StructOpt;
+
+use crate::imt::crawlerrors::CrawlErrors;
+use crate::imt::crawloptions::{CrawlOptions, IgnoreRules};
+use crate::imt::direntryutil::is_missing;
+use crate::imt::filer::{Filer, CATALOG_PATH};
+use crate::imt::finddups::catalog_files;
+use crate::imt::gc::prune_missing;
+use crate::imt::hashers::default_hash_algorithm;
+use crate::imt::moved::MoveDetector;
+use crate::imt::progress::Progress;
+
+// Saving a file usually produces several events in a row, so we wait for things to settle
+// before acting on them. During a big copy they never do, so a batch is also processed once
+// it is this old, or this big.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+const MAX_BATCH_LEN: usize = 10_000;
+
+/// Keep the catalog up to date as files are created, changed, moved, and deleted.
+/// Runs until interrupted.
+#[derive(StructOpt, Debug)]
+pub struct Watch {
+    #[structopt(flatten)]
+    crawl_options: CrawlOptions,
+
+    /// How often to write the catalog, in seconds.
+    #[structopt(long, default_value = "60")]
+    flush_seconds: u64,
+
+    /// Don't crawl the directories before starting to watch them.
+    #[structopt(long)]
+    no_initial_crawl: bool,
+
+    /// The directories to watch
+    #[structopt(min_values(1), parse(from_os_str))]
+    directories: Vec<PathBuf>,
+}
+
+// The events are reported with the watched path joined onto the current directory, but the
+// catalog uses the paths as they were given on the command line, so we keep both. Symlinks
+// aren't resolved on either side.
+struct Root {
+    path: PathBuf,
+    absolute: PathBuf,
+}
+
+#[derive(Default)]
+struct Batch {
+    changed: BTreeSet<PathBuf>,
+    renames: Vec<(PathBuf, PathBuf)>,
+    // When the first event arrived.
+    started: Option<Instant>,
+}
+
+impl Batch {
+    fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.renames.is_empty()
+    }
+
+    // Whether to process the batch now, even though events are still arriving.
+    fn is_due(&self) -> bool {
+        self.started.is_some_and(|s| s.elapsed() >= SETTLE_TIME)
+            || self.changed.len() + self.renames.len() >= MAX_BATCH_LEN
+    }
+
+    fn add(&mut self, event: Event) {
+        self.started.get_or_insert_with(Instant::now);
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                self.renames
+                    .push((event.paths[0].clone(), event.paths[1].clone()));
+            }
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                self.changed.extend(event.paths);
+            }
+            EventKind::Access(_) => {}
+            _ => self.changed.extend(event.paths),
+        }
+    }
+}
+
+struct WatchState<'a> {
+    watch: &'a Watch,
+    filer: &'a Filer,
+    roots: Vec<Root>,
+    // So that writing the catalog doesn't look like a change, if it's in a watched directory.
+    catalog: PathBuf,
+}
+
+impl<'a> WatchState<'a> {
+    // Returns the index of the watched root and the path to use in the catalog.
+    fn to_catalog_path(&self, path: &Path) -> Option<(usize, PathBuf)> {
+        if path == self.catalog {
+            return None;
+        }
+        self.roots.iter().enumerate().find_map(|(i, root)| {
+            path.strip_prefix(&root.absolute)
+                .ok()
+                .map(|rest| (i, root.path.join(rest)))
+        })
+    }
+
+    fn is_ignored(&self, rules: &mut IgnoreRules, root: &Root, path: &Path) -> Result<bool> {
+        // Like the crawl, skip anything inside of a hidden directory.
+        let rest = path.strip_prefix(&root.path).unwrap_or(path);
+        let mut parents = rest.parent().into_iter().flat_map(|p| p.components());
+        let in_hidden_dir = parents.any(|c| match c {
+            Component::Normal(name) => name.as_encoded_bytes().starts_with(b"."),
+            _ => false,
+        });
+        Ok(in_hidden_dir || rules.is_path_ignored(&root.path, path)?)
+    }
+
+    // Errors with single files are recorded in `errors`, like in a crawl.
+    fn process_batch(&self, batch: Batch, errors: &CrawlErrors) -> Result<()> {
+        let mut rules = IgnoreRules::new(&self.watch.crawl_options)?;
+        let mut changed: BTreeSet<(usize, PathBuf)> = BTreeSet::default();
+
+        for (from, to) in &batch.renames {
+            if let (Some((_, from)), Some((index, to))) =
+                (self.to_catalog_path(from), self.to_catalog_path(to))
+            {
+                move_tree(self.filer, &from, &to);
+                changed.insert((index, to));
+            }
+        }
+        for path in &batch.changed {
+            if let Some(changed_path) = self.to_catalog_path(path) {
+                changed.insert(changed_path);
+            }
+        }
+
+        let moves = MoveDetector::new(self.filer);
+        let progress = Progress::new();
+        for (index, path) in changed {
+            if is_missing(&path) {
+                let removed = prune_missing(self.filer, &[&path], false);
+                debug!("Removed {} entries for {}", removed, path.display());
+            } else if self.is_ignored(&mut rules, &self.roots[index], &path)? {
+                debug!("Ignoring {}", path.display());
+            } else {
+                let options = &self.watch.crawl_options;
+                catalog_files(
+                    &path,
+                    options,
+                    default_hash_algorithm(),
+                    self.filer,
+                    &moves,
+                    &progress,
+                    errors,
+                )?;
+            }
+            if errors.should_stop() {
+                break;
+            }
+        }
+        progress.finish();
+        Ok(())
+    }
+}
+
+// Moves the entries for `from`, and everything under it, if it is a directory, to `to`.
+fn move_tree(filer: &Filer, from: &Path, to: &Path) {
+    let mut moving: Vec<PathBuf> = Vec::default();
+    filer.with_files(|p| {
+        if p.starts_with(from) {
+            moving.push(p.clone());
+        }
+    });
+    for old in moving {
+        if let Ok(rest) = old.strip_prefix(from) {
+            // Joining an empty path would add a trailing slash.
+            let new = if rest.as_os_str().is_empty() {
+                to.to_path_buf()
+            } else {
+                to.join(rest)
+            };
+            info!("{} was moved to {}.", old.display(), new.display());
+            filer.move_file(&old, new);
+        }
+    }
+}
+
+pub fn process_watch(w: &Watch, filer: &Filer) -> Result<()> {
+    if !w.no_initial_crawl {
+        let moves = MoveDetector::new(filer);
+        let progress = Progress::new();
+        let errors = CrawlErrors::new(w.crawl_options.fail_fast);
+        for dir in &w.directories {
+            catalog_files(
+                dir,
+                &w.crawl_options,
+                default_hash_algorithm(),
+                filer,
+                &moves,
+                &progress,
+                &errors,
+            )?;
+        }
+        progress.finish();
+        prune_missing(filer, &w.directories, false);
+        // We can still keep up with changes to the files that did work.
+        if let Err(err) = errors.finish() {
+            error!("{}", err);
+        }
+        filer.write_to_path(CATALOG_PATH)?;
+    }
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mut roots = Vec::default();
+    for dir in &w.directories {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+        roots.push(Root {
+            path: dir.clone(),
+            absolute: std::env::current_dir()?.join(dir),
+        });
+    }
+    let state = WatchState {
+        watch: w,
+        filer,
+        roots,
+        catalog: std::env::current_dir()?.join(CATALOG_PATH),
+    };
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))?;
+
+    eprintln!("Watching for changes. Press Ctrl-C to stop.");
+    let flush_interval = Duration::from_secs(w.flush_seconds);
+    let mut last_flush = Instant::now();
+    let mut dirty = false;
+    let mut batch = Batch::default();
+    let errors = CrawlErrors::new(w.crawl_options.fail_fast);
+    while !interrupted.load(Ordering::SeqCst) && !errors.should_stop() {
+        match rx.recv_timeout(SETTLE_TIME) {
+            Ok(Ok(event)) => {
+                debug!("{:?}", event);
+                batch.add(event);
+                if !batch.is_due() {
+                    continue;
+                }
+            }
+            Ok(Err(err)) => error!("Watch error: {}", err),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if !batch.is_empty() {
+            // One bad file shouldn't stop the watch.
+            if let Err(err) = state.process_batch(std::mem::take(&mut batch), &errors) {
+                error!("{:#}", err);
+                eprintln!("Error: {:#}", err);
+                errors.record(&err);
+            }
+            dirty = true;
+        }
+        if dirty && last_flush.elapsed() >= flush_interval {
+            info!("Writing {}", CATALOG_PATH);
+            filer.write_to_path(CATALOG_PATH)?;
+            last_flush = Instant::now();
+            dirty = false;
+        }
+    }
+
+    if !batch.is_empty() && !errors.should_stop() {
+        state.process_batch(batch, &errors)?;
+    }
+    // The caller writes the catalog one last time.
+    errors.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use notify::event::{CreateKind, RemoveKind};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::imt::finddups::HASH_NAME;
+
+    fn event(kind: EventKind, paths: &[&Path]) -> Event {
+        paths
+            .iter()
+            .fold(Event::new(kind), |e, p| e.add_path(p.to_path_buf()))
+    }
+
+    fn rename() -> EventKind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+    }
+
+    #[test]
+    fn batch_coalesces_events() {
+        let (a, b) = (Path::new("/w/a"), Path::new("/w/b"));
+        let mut batch = Batch::default();
+        assert!(batch.is_empty() && !batch.is_due());
+        batch.add(event(EventKind::Create(CreateKind::File), &[a]));
+        batch.add(event(EventKind::Modify(ModifyKind::Any), &[a]));
+        batch.add(event(
+            EventKind::Access(AccessKind::Close(AccessMode::Write)),
+            &[a],
+        ));
+        // Reading a file doesn't change it.
+        batch.add(event(
+            EventKind::Access(AccessKind::Open(AccessMode::Any)),
+            &[b],
+        ));
+        batch.add(event(rename(), &[a, b]));
+        assert_eq!(batch.changed.iter().collect::<Vec<_>>(), [a]);
+        assert_eq!(batch.renames, [(a.to_path_buf(), b.to_path_buf())]);
+        assert!(!batch.is_due());
+    }
+
+    #[test]
+    fn busy_batch_is_due() {
+        let mut batch = Batch::default();
+        batch.add(event(EventKind::Any, &[Path::new("/w/a")]));
+        batch.started = Some(Instant::now() - SETTLE_TIME);
+        assert!(batch.is_due());
+
+        let mut batch = Batch::default();
+        for i in 0..MAX_BATCH_LEN {
+            batch.add(event(
+                EventKind::Any,
+                &[&PathBuf::from(format!("/w/{}", i))],
+            ));
+        }
+        assert!(batch.is_due());
+    }
+
+    #[test]
+    fn move_tree_moves_everything_below() {
+        let filer = Filer::new().unwrap();
+        for path in ["a/x", "a/sub/y", "ab/z", "a"] {
+            filer.add_file(path);
+        }
+        move_tree(&filer, Path::new("a"), Path::new("b"));
+        let mut paths = Vec::default();
+        filer.with_files(|p| paths.push(p.clone()));
+        paths.sort();
+        assert_eq!(paths, ["ab/z", "b", "b/sub/y", "b/x"].map(PathBuf::from));
+    }
+
+    // A watched directory with one cataloged file, "old.txt".
+    fn watched() -> (TempDir, Watch, Filer) {
+        // Not the default ".tmp" name, since hidden directories aren't crawled.
+        let dir = tempfile::Builder::new().prefix("imt").tempdir().unwrap();
+        let old = dir.path().join("old.txt");
+        fs::write(&old, "old").unwrap();
+        let watch = Watch::from_iter_safe(["watch".as_ref(), dir.path().as_os_str()]).unwrap();
+        let filer = Filer::new().unwrap();
+        catalog_files(
+            dir.path(),
+            &watch.crawl_options,
+            default_hash_algorithm(),
+            &filer,
+            &MoveDetector::new(&filer),
+            &Progress::new(),
+            &CrawlErrors::new(true),
+        )
+        .unwrap();
+        (dir, watch, filer)
+    }
+
+    fn state<'a>(dir: &TempDir, watch: &'a Watch, filer: &'a Filer) -> WatchState<'a> {
+        WatchState {
+            watch,
+            filer,
+            roots: vec![Root {
+                path: dir.path().to_path_buf(),
+                absolute: dir.path().to_path_buf(),
+            }],
+            catalog: dir.path().join(CATALOG_PATH),
+        }
+    }
+
+    #[test]
+    fn batch_updates_the_catalog() {
+        let (dir, watch, filer) = watched();
+        let state = state(&dir, &watch, &filer);
+        let (old, new, moved) = (
+            dir.path().join("old.txt"),
+            dir.path().join("new.txt"),
+            dir.path().join("moved.txt"),
+        );
+        let old_hash = filer.hash_value(&old, HASH_NAME);
+        assert!(old_hash.is_some());
+
+        fs::rename(&old, &moved).unwrap();
+        fs::write(&new, "new").unwrap();
+        // Catalog writes are not changes.
+        fs::write(dir.path().join(CATALOG_PATH), "").unwrap();
+        let mut batch = Batch::default();
+        batch.add(event(rename(), &[&old, &moved]));
+        batch.add(event(EventKind::Create(CreateKind::File), &[&new]));
+        batch.add(event(
+            EventKind::Create(CreateKind::File),
+            &[&dir.path().join(CATALOG_PATH)],
+        ));
+        let errors = CrawlErrors::new(true);
+        state.process_batch(batch, &errors).unwrap();
+        assert_eq!(errors.count(), 0);
+        assert!(!filer.contains_file(&old));
+        assert_eq!(filer.hash_value(&moved, HASH_NAME), old_hash);
+        assert!(filer.contains_hash(&new, HASH_NAME));
+        assert!(!filer.contains_file(dir.path().join(CATALOG_PATH)));
+
+        fs::remove_file(&new).unwrap();
+        let mut batch = Batch::default();
+        batch.add(event(EventKind::Remove(RemoveKind::File), &[&new]));
+        state.process_batch(batch, &errors).unwrap();
+        assert!(!filer.contains_file(&new));
+    }
+
+    #[test]
+    fn paths_outside_of_the_roots_are_left_alone() {
+        let (dir, watch, filer) = watched();
+        let state = state(&dir, &watch, &filer);
+        let elsewhere = TempDir::new().unwrap();
+        let path = elsewhere.path().join("a.txt");
+        fs::write(&path, "a").unwrap();
+        let mut batch = Batch::default();
+        batch.add(event(EventKind::Create(CreateKind::File), &[&path]));
+        state.process_batch(batch, &CrawlErrors::new(true)).unwrap();
+        assert!(!filer.contains_file(&path));
+    }
+}
@@ -1,8 +1,9 @@
 use anyhow::Result;
-use imt::{process_command, Command, Filer};
+use imt::{process_command, Command, CrawlFailed, Filer, CATALOG_PATH};
 use log::LevelFilter;
 use simplelog::{CombinedLogger, Config, SharedLogger, TermLogger, TerminalMode, WriteLogger};
 use std::fs::File;
+use std::path::Path;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -42,8 +43,15 @@ fn set_up_logs(opts: &Opts) -> Result<()> {
     Ok(())
 }
 
+// Exit status when the command ran, but some files could not be processed.
+const EXIT_CRAWL_FAILED: i32 = 2;
+
 fn start_filer() -> Result<Filer> {
-    Filer::new()
+    if Path::new(CATALOG_PATH).exists() {
+        Filer::read_from_path(CATALOG_PATH)
+    } else {
+        Filer::new()
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -52,8 +60,23 @@ fn main() -> anyhow::Result<()> {
 
     let filer = start_filer()?;
 
-    process_command(opts.command, &filer)?;
-    filer.write_to_path("files.toml")?;
+    // Commands that only read the catalog leave the file alone, and don't create one.
+    let changes_catalog = opts.command.changes_catalog();
+    let result = process_command(opts.command, &filer);
+    if let Err(err) = &result {
+        if err.is::<CrawlFailed>() {
+            // Everything that did work is still worth keeping.
+            if changes_catalog {
+                filer.write_to_path(CATALOG_PATH)?;
+            }
+            eprintln!("Error: {}", err);
+            std::process::exit(EXIT_CRAWL_FAILED);
+        }
+    }
+    result?;
+    if changes_catalog {
+        filer.write_to_path(CATALOG_PATH)?;
+    }
 
     Ok(())
 }